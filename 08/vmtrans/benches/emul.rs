@@ -0,0 +1,82 @@
+// Emulator throughput benchmark.  Run with `cargo bench`.
+//
+use std::time::Instant;
+
+use vmtrans::emul::Emul;
+use vmtrans::parser::Parser;
+use vmtrans::translator::Translator;
+
+const COUNT_LOOP: &str = "
+(OUTER)
+@30000
+D=A
+@R1
+M=D
+(LOOP)
+@R1
+M=M-1
+D=M
+@LOOP
+D;JGT
+@R2
+M=M+1
+D=M
+@100
+D=D-A
+@OUTER
+D;JLT
+";
+
+const FIB_VM: &str = "
+push constant 23
+call Main.fib 1
+label DONE
+goto DONE
+function Main.fib 0
+push argument 0
+push constant 2
+lt
+if-goto BASE
+push argument 0
+push constant 1
+sub
+call Main.fib 1
+push argument 0
+push constant 2
+sub
+call Main.fib 1
+add
+return
+label BASE
+push argument 0
+return
+";
+
+fn translate(vm: &str) -> String {
+    let mut parser = Parser::new("Main");
+    let mut tr = Translator::new("Main");
+    let mut code = String::new();
+    for line in vm.lines() {
+        if let Some(cmd) = parser.parse_str(line).unwrap() {
+            code += &tr.trans_cmd(&cmd);
+        }
+    }
+    code
+}
+
+fn bench(name: &str, code: &str, setup: &[(usize,i16)]) -> Emul {
+    let mut em = Emul::new();
+    em.set_ram(setup);
+    let start = Instant::now();
+    em.run_code(code, i32::MAX).unwrap();
+    let secs = start.elapsed().as_secs_f64();
+    println!("{:<12} {:>12} instructions in {:>7.3}s = {:>7.1} M instructions/s",
+             name, em.ticks(), secs, em.ticks() as f64 / secs / 1e6);
+    em
+}
+
+fn main() {
+    bench("count_loop", COUNT_LOOP, &[]);
+    let em = bench("fib(23)", &translate(FIB_VM), &[(0, 256)]);
+    assert_eq!(em.peek(256), 28657);
+}
@@ -1,10 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet};
 use std::fmt;
 use std::fmt::Write;
 
+use crate::bus::{SCREEN,KBD};
+
 pub struct Asm {
     pc: i16,
     syms: HashMap<String,i16>,
+    labels: Vec<(String,i16)>,
+    next_var: i16,
+    vars: Vec<(String,i16)>,
 }
 
 #[derive(Debug,PartialEq)]
@@ -34,6 +39,34 @@ impl Command {
             Command::Label(label) => format!("({})", label),
         }
     }
+
+    // Hack machine code for this command.  Labels have no encoding, and an
+    // A-instruction can only load 0..32767.
+    pub fn encode(&self) -> Option<u16> {
+        match self {
+            Command::A(n) if *n >= 0 => Some(*n as u16),
+            Command::C(dest, comp, jump) =>
+                Some(0xe000 | comp.bits() << 6 | dest.bits() << 3 | jump.bits()),
+            _ => None,
+        }
+    }
+
+    // None for C-instructions whose comp field isn't one of the standard 28
+    pub fn decode(word: u16) -> Option<Command> {
+        if word & 0x8000 == 0 {
+            Some(Command::A(word as i16))
+        } else {
+            Some(Command::C(Dest::from_bits(word >> 3 & 7), Comp::from_bits(word >> 6 & 0x7f)?, Jump::from_bits(word & 7)))
+        }
+    }
+}
+
+// Assembly text for a machine word, or its binary form if it has none
+pub fn disasm(word: u16) -> String {
+    match Command::decode(word) {
+        Some(cmd) => cmd.as_str(),
+        None => format!("{:016b}", word),
+    }
 }
 
 
@@ -70,6 +103,7 @@ pub enum Comp {
 }
 
 impl Comp {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Comp> {
         match s {
             "0" => Some(Comp::Zero),
@@ -137,6 +171,74 @@ impl Comp {
         }
 
     }
+
+    // a c1..c6 bits of a C-instruction
+    pub fn bits(&self) -> u16 {
+        match self {
+            Comp::Zero => 0b0101010,
+            Comp::One => 0b0111111,
+            Comp::MinusOne => 0b0111010,
+            Comp::D => 0b0001100,
+            Comp::A => 0b0110000,
+            Comp::NotD => 0b0001101,
+            Comp::NotA => 0b0110001,
+            Comp::MinusD => 0b0001111,
+            Comp::MinusA => 0b0110011,
+            Comp::DPlusOne => 0b0011111,
+            Comp::APlusOne => 0b0110111,
+            Comp::DMinusOne => 0b0001110,
+            Comp::AMinusOne => 0b0110010,
+            Comp::DPlusA => 0b0000010,
+            Comp::DMinusA => 0b0010011,
+            Comp::AMinusD => 0b0000111,
+            Comp::DAndA => 0b0000000,
+            Comp::DOrA => 0b0010101,
+            Comp::M => 0b1110000,
+            Comp::NotM => 0b1110001,
+            Comp::MinusM => 0b1110011,
+            Comp::MPlusOne => 0b1110111,
+            Comp::MMinusOne => 0b1110010,
+            Comp::DPlusM => 0b1000010,
+            Comp::DMinusM => 0b1010011,
+            Comp::MMinusD => 0b1000111,
+            Comp::DAndM => 0b1000000,
+            Comp::DOrM => 0b1010101,
+        }
+    }
+
+    pub fn from_bits(bits: u16) -> Option<Comp> {
+        match bits {
+            0b0101010 => Some(Comp::Zero),
+            0b0111111 => Some(Comp::One),
+            0b0111010 => Some(Comp::MinusOne),
+            0b0001100 => Some(Comp::D),
+            0b0110000 => Some(Comp::A),
+            0b0001101 => Some(Comp::NotD),
+            0b0110001 => Some(Comp::NotA),
+            0b0001111 => Some(Comp::MinusD),
+            0b0110011 => Some(Comp::MinusA),
+            0b0011111 => Some(Comp::DPlusOne),
+            0b0110111 => Some(Comp::APlusOne),
+            0b0001110 => Some(Comp::DMinusOne),
+            0b0110010 => Some(Comp::AMinusOne),
+            0b0000010 => Some(Comp::DPlusA),
+            0b0010011 => Some(Comp::DMinusA),
+            0b0000111 => Some(Comp::AMinusD),
+            0b0000000 => Some(Comp::DAndA),
+            0b0010101 => Some(Comp::DOrA),
+            0b1110000 => Some(Comp::M),
+            0b1110001 => Some(Comp::NotM),
+            0b1110011 => Some(Comp::MinusM),
+            0b1110111 => Some(Comp::MPlusOne),
+            0b1110010 => Some(Comp::MMinusOne),
+            0b1000010 => Some(Comp::DPlusM),
+            0b1010011 => Some(Comp::DMinusM),
+            0b1000111 => Some(Comp::MMinusD),
+            0b1000000 => Some(Comp::DAndM),
+            0b1010101 => Some(Comp::DOrM),
+            _ => None,
+        }
+    }
 }
 
 
@@ -153,6 +255,7 @@ pub enum Dest {
 }
 
 impl Dest {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Dest> {
         match s {
             "" => Some(Dest::Null),
@@ -179,6 +282,33 @@ impl Dest {
             Dest::AMD => "AMD",
         }
     }
+
+    // d1 d2 d3 (A D M) bits of a C-instruction
+    pub fn bits(&self) -> u16 {
+        match self {
+            Dest::Null => 0,
+            Dest::M => 1,
+            Dest::D => 2,
+            Dest::MD => 3,
+            Dest::A => 4,
+            Dest::AM => 5,
+            Dest::AD => 6,
+            Dest::AMD => 7,
+        }
+    }
+
+    pub fn from_bits(bits: u16) -> Dest {
+        match bits & 7 {
+            0 => Dest::Null,
+            1 => Dest::M,
+            2 => Dest::D,
+            3 => Dest::MD,
+            4 => Dest::A,
+            5 => Dest::AM,
+            6 => Dest::AD,
+            _ => Dest::AMD,
+        }
+    }
 }
 
 #[derive(Debug,PartialEq)]
@@ -194,6 +324,7 @@ pub enum Jump {
 }
 
 impl Jump {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Jump> {
         match s {
             "" => Some(Jump::Null),
@@ -220,6 +351,33 @@ impl Jump {
             Jump::JMP => "JMP",
         }
     }
+
+    // j1 j2 j3 (< = >) bits of a C-instruction
+    pub fn bits(&self) -> u16 {
+        match self {
+            Jump::Null => 0,
+            Jump::JGT => 1,
+            Jump::JEQ => 2,
+            Jump::JGE => 3,
+            Jump::JLT => 4,
+            Jump::JNE => 5,
+            Jump::JLE => 6,
+            Jump::JMP => 7,
+        }
+    }
+
+    pub fn from_bits(bits: u16) -> Jump {
+        match bits & 7 {
+            0 => Jump::Null,
+            1 => Jump::JGT,
+            2 => Jump::JEQ,
+            3 => Jump::JGE,
+            4 => Jump::JLT,
+            5 => Jump::JNE,
+            6 => Jump::JLE,
+            _ => Jump::JMP,
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -240,17 +398,25 @@ impl fmt::Debug for ParserError {
 }
 
 
+impl Default for Asm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Asm {
     pub fn new() -> Asm {
-        let mut asm = Asm{pc: 0, syms: HashMap::new()};
+        let mut asm = Asm{pc: 0, syms: HashMap::new(), labels: vec![], next_var: 16, vars: vec![]};
         asm.syms.insert("SP".to_string(), 0);
         asm.syms.insert("LCL".to_string(), 1);
         asm.syms.insert("ARG".to_string(), 2);
         asm.syms.insert("THIS".to_string(), 3);
         asm.syms.insert("THAT".to_string(), 4);
-        asm.syms.insert("R13".to_string(), 13);
-        asm.syms.insert("R14".to_string(), 14);
-        asm.syms.insert("R15".to_string(), 15);
+        for r in 0..16 {
+            asm.syms.insert(format!("R{}", r), r);
+        }
+        asm.syms.insert("SCREEN".to_string(), SCREEN as i16);
+        asm.syms.insert("KBD".to_string(), KBD as i16);
         asm
     }
 
@@ -258,18 +424,27 @@ impl Asm {
         *self.syms.get(s).unwrap()
     }
 
+    // (label) declarations seen so far, with their ROM addresses
+    pub fn labels(&self) -> &[(String,i16)] {
+        &self.labels
+    }
+
+    // Variables allocated so far, with their RAM addresses
+    pub fn variables(&self) -> &[(String,i16)] {
+        &self.vars
+    }
+
     pub fn parse_cmd(&self, st: &str) -> Result<Option<Command>,ParserError> {
         let mut s: &str = &st.replace(" ","");
         let f = s.split("//").collect::<Vec<_>>();
-        s = &f[0];
-        if s.len() == 0 {
+        s = f[0];
+        if s.is_empty() {
             return Ok(None)
         }
         if s.starts_with("(") {
             Ok(Some(Command::Label(s[1..(s.len()-1)].to_string())))
-        } else if s.starts_with("@") {
-            let rest = &s[1..];
-            if rest.len() == 0 {
+        } else if let Some(rest) = s.strip_prefix("@") {
+            if rest.is_empty() {
                 Ok(None)
             } else if let Ok(n) = rest.parse::<i16>() {
                 Ok(Some(Command::A(n)))
@@ -286,7 +461,7 @@ impl Asm {
                     return Err(ParserError{code: st.to_string()});
                 }
             }
-            s = &f[0];
+            s = f[0];
             let mut dest = Dest::Null;
             let f = s.split("=").collect::<Vec<_>>();
             if f.len() == 2 {
@@ -296,7 +471,7 @@ impl Asm {
                     return Err(ParserError{code: st.to_string()});
                 }
 
-                s = &f[1];
+                s = f[1];
             }
             if let Some(c) = Comp::from_str(s) {
                 Ok(Some(Command::C(dest, c, jump)))
@@ -314,6 +489,7 @@ impl Asm {
             match op_c {
                 Some(Command::Label(ref s)) => {
                     self.syms.insert(s.to_string(), self.pc);
+                    self.labels.push((s.to_string(), self.pc));
                 },
                 Some(c) => {
                     r.push(c);
@@ -323,20 +499,34 @@ impl Asm {
             }
         }
 
-        // now convert labels to numbers
-        for i in 0..r.len() {
-            match &r[i] {
-                Command::ALabel(ref label) => {
-                    match self.syms.get(label) {
-                        Some(val) => {
-                            r[i] = Command::A(*val as i16);
-                        },
-                        None => {
-                            panic!("Assembler doesn't yet handle missing labels: {}", label);
-                        }
+        // Symbols loaded just before a jump must be labels; a typo there
+        // would otherwise become a variable and send the jump into RAM
+        let jump_targets = r.windows(2).filter_map(|w| match w {
+            [Command::ALabel(label), Command::C(_, _, jump)] if *jump != Jump::Null => Some(label.clone()),
+            _ => None,
+        }).collect::<HashSet<_>>();
+
+        // now convert labels to numbers; anything else is a variable,
+        // allocated from RAM[16] in order of first use
+        for cmd in r.iter_mut() {
+            if let Command::ALabel(ref label) = cmd {
+                let val = match self.syms.get(label) {
+                    Some(val) => *val,
+                    None if jump_targets.contains(label) => {
+                        return Err(ParserError{code: format!("Undefined label {}", label)});
+                    },
+                    None if self.next_var as usize >= SCREEN => {
+                        return Err(ParserError{code: format!("No RAM left for variable {}", label)});
+                    },
+                    None => {
+                        let v = self.next_var;
+                        self.syms.insert(label.to_string(), v);
+                        self.vars.push((label.to_string(), v));
+                        self.next_var += 1;
+                        v
                     }
-                },
-                _ => {},
+                };
+                *cmd = Command::A(val);
             }
         }
         Ok(r)
@@ -368,6 +558,36 @@ mod tests {
         assert!(asm.parse_code_str("@32\nxyx\nM=1\n").is_err());
         assert_eq!(asm.parse_code_str("@FOO\n0;JMP\n(FOO)\n"), Ok(vec![Command::A(2), Command::C(Dest::Null, Comp::Zero, Jump::JMP)]));
         assert_eq!(asm.parse_code_str("@THIS\nM=1\n"), Ok(vec![Command::A(3), Command::C(Dest::M, Comp::One, Jump::Null)]));
+        let mut asm = Asm::new();
+        assert_eq!(asm.parse_code_str("@i\n@Foo.1\n@i\n@LOOP\n(LOOP)\n"),
+            Ok(vec![Command::A(16), Command::A(17), Command::A(16), Command::A(4)]));
+        let mut asm = Asm::new();
+        assert_eq!(asm.parse_code_str("@DONE\n0;JMP\n").unwrap_err().to_string(), "ParserError: Undefined label DONE");
+        let mut asm = Asm::new();
+        let vars = (0..SCREEN - 16).map(|i| format!("@v{}\n", i)).collect::<String>();
+        assert!(asm.parse_code_str(&vars).is_ok());
+        assert_eq!(asm.parse_code_str("@one_more\n").unwrap_err().to_string(), "ParserError: No RAM left for variable one_more");
+    }
+
+    #[test]
+    fn test_encode() {
+        let asm = Asm::new();
+        let table = &[
+            ("@21", 0b0000000000010101),
+            ("D=A", 0b1110110000010000),
+            ("AM=M-1", 0b1111110010101000),
+            ("D;JGT", 0b1110001100000001),
+            ("0;JMP", 0b1110101010000111),
+            ("AMD=D|M;JLE", 0b1111010101111110),
+        ];
+        for (code, word) in table {
+            let cmd = asm.parse_cmd(code).unwrap().unwrap();
+            assert_eq!(cmd.encode(), Some(*word), "encoding {}", code);
+            assert_eq!(Command::decode(*word), Some(cmd), "decoding {}", code);
+        }
+        assert_eq!(Command::A(-1).encode(), None);
+        assert_eq!(Command::Label("X".to_string()).encode(), None);
+        assert_eq!(disasm(0b1110101011000000), "1110101011000000");
     }
 }
 
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::fs::{read,read_to_string};
+use std::panic::{AssertUnwindSafe,catch_unwind};
+use std::path::{Path,PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+
+use crate::emul::{Emul,Stop};
+use crate::loader::parse_image;
+use crate::sourcemap::load_program;
+use crate::tst::{Limit,TestScript};
+
+// Instructions to run between checks of the clock
+const CHUNK: u64 = 100_000;
+
+#[derive(Debug,PartialEq,Clone)]
+pub enum Source {
+    // Assembly text
+    Code(String),
+    // A .asm, .hack, .bin, .vm or .tst file, or a directory of .vm files
+    Path(PathBuf),
+}
+
+// One independent run.  A program passes if it ends or halts and the RAM
+// expectations hold; a .tst script passes if its comparison succeeds.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Job {
+    pub name: String,
+    pub source: Source,
+    pub ram: Vec<(usize,i16)>,
+    pub expect: Vec<(usize,i16)>,
+}
+
+impl Job {
+    pub fn file(path: &Path) -> Job {
+        Job{name: path.display().to_string(), source: Source::Path(path.to_path_buf()), ram: vec![], expect: vec![]}
+    }
+
+    pub fn code(name: &str, code: &str) -> Job {
+        Job{name: name.to_string(), source: Source::Code(code.to_string()), ram: vec![], expect: vec![]}
+    }
+
+    // RAM to set before the run
+    pub fn with_ram(mut self, pairs: &[(usize,i16)]) -> Job {
+        self.ram.extend_from_slice(pairs);
+        self
+    }
+
+    // RAM to check after the run
+    pub fn with_expect(mut self, pairs: &[(usize,i16)]) -> Job {
+        self.expect.extend_from_slice(pairs);
+        self
+    }
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    // The job could not be loaded
+    Error(String),
+    TickLimit,
+    Timeout,
+    // The emulator itself panicked; a fault in the program is a Fail
+    Panic(String),
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Pass => "PASS",
+            Outcome::Fail(_) => "FAIL",
+            Outcome::Error(_) => "ERROR",
+            Outcome::TickLimit => "TICKS",
+            Outcome::Timeout => "TIMEOUT",
+            Outcome::Panic(_) => "PANIC",
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct JobResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub ticks: u64,
+    pub elapsed: Duration,
+}
+
+// Results in the order the jobs were given
+pub struct Report {
+    pub results: Vec<JobResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.outcome == Outcome::Pass).count()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.passed() == self.results.len()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.results {
+            write!(f, "{:<7} {} ({} ticks, {:.3}s)", r.outcome.as_str(), r.name, r.ticks, r.elapsed.as_secs_f64())?;
+            match r.outcome {
+                Outcome::Fail(ref m) | Outcome::Error(ref m) | Outcome::Panic(ref m) => {
+                    for line in m.lines() {
+                        write!(f, "\n        {}", line)?;
+                    }
+                },
+                _ => (),
+            }
+            writeln!(f)?;
+        }
+        write!(f, "{} passed, {} failed", self.passed(), self.results.len() - self.passed())
+    }
+}
+
+// Runs jobs on a pool of threads, each with its own Emul
+pub struct Batch {
+    threads: usize,
+    max_ticks: u64,
+    timeout: Option<Duration>,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn load(em: &mut Emul, path: &Path) -> Result<(), String> {
+    let name = path.to_string_lossy();
+    if name.ends_with(".hack") {
+        em.load_hack(&read_to_string(path).map_err(|e| e.to_string())?).map_err(|e| e.to_string())
+    } else if name.ends_with(".bin") {
+        em.load_rom(parse_image(&read(path).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?);
+        Ok(())
+    } else {
+        let (code, map, bootstrap) = load_program(&name)?;
+        em.load_code(&code)?;
+        em.set_functions(&map.functions());
+        if !bootstrap {
+            em.poke(0, 256);
+        }
+        Ok(())
+    }
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Batch{threads, max_ticks: 10_000_000, timeout: None}
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Batch {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn with_max_ticks(mut self, max_ticks: u64) -> Batch {
+        self.max_ticks = max_ticks;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Batch {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn run_script(&self, path: &Path, deadline: Option<Instant>) -> (Outcome, u64) {
+        let src = match read_to_string(path) {
+            Ok(src) => src,
+            Err(e) => return (Outcome::Error(format!("{}: {}", path.display(), e)), 0),
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let run = match TestScript::parse(&src).and_then(|s| s.run_limited(dir, self.max_ticks, deadline)) {
+            Ok(run) => run,
+            Err(e) => return (Outcome::Error(e.to_string()), 0),
+        };
+        let outcome = match (run.limit, run.failure) {
+            (Some(Limit::Ticks), _) => Outcome::TickLimit,
+            (Some(Limit::Time), _) => Outcome::Timeout,
+            (None, Some(m)) => Outcome::Fail(m.to_string()),
+            (None, None) => Outcome::Pass,
+        };
+        (outcome, run.ticks)
+    }
+
+    fn run_program(&self, job: &Job, deadline: Option<Instant>) -> (Outcome, u64) {
+        let mut em = Emul::new();
+        let loaded = match job.source {
+            Source::Code(ref code) => em.load_code(code),
+            Source::Path(ref path) => load(&mut em, path),
+        };
+        if let Err(e) = loaded {
+            return (Outcome::Error(e), 0);
+        }
+        em.set_ram(&job.ram);
+        let stop = loop {
+            let stop = em.resume(CHUNK.min(self.max_ticks - em.ticks()));
+            if stop != Stop::TickLimit || em.ticks() >= self.max_ticks {
+                break stop;
+            }
+            if deadline.is_some_and(|d| Instant::now() > d) {
+                return (Outcome::Timeout, em.ticks());
+            }
+        };
+        let outcome = match stop {
+            Stop::TickLimit => Outcome::TickLimit,
+            Stop::Trapped => Outcome::Fail(em.trap().map_or(String::new(), |t| t.to_string())),
+            Stop::Fault => Outcome::Fail(em.fault_report().unwrap_or_default()),
+            Stop::Breakpoint => Outcome::Fail("Stopped at a breakpoint".to_string()),
+            Stop::End | Stop::Halted => {
+                let wrong = job.expect.iter()
+                    .filter(|(addr, v)| em.peek(*addr) != *v)
+                    .map(|(addr, v)| format!("RAM[{}] = {}, expected {}", addr, em.peek(*addr), v))
+                    .collect::<Vec<_>>();
+                if wrong.is_empty() { Outcome::Pass } else { Outcome::Fail(wrong.join("\n")) }
+            },
+        };
+        (outcome, em.ticks())
+    }
+
+    fn run_job(&self, job: &Job) -> JobResult {
+        let start = Instant::now();
+        let deadline = self.timeout.map(|t| start + t);
+        let is_script = matches!(job.source, Source::Path(ref p) if p.extension().is_some_and(|x| x == "tst"));
+        let res = catch_unwind(AssertUnwindSafe(|| match job.source {
+            Source::Path(ref p) if is_script => self.run_script(p, deadline),
+            _ => self.run_program(job, deadline),
+        }));
+        let (outcome, ticks) = res.unwrap_or_else(|e| {
+            let msg = e.downcast_ref::<String>().cloned()
+                .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            (Outcome::Panic(msg), 0)
+        });
+        JobResult{name: job.name.clone(), outcome, ticks, elapsed: start.elapsed()}
+    }
+
+    // Run every job, each thread taking the next job not yet started
+    pub fn run(&self, jobs: &[Job]) -> Report {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![None; jobs.len()]);
+        std::thread::scope(|s| {
+            for _ in 0..self.threads.min(jobs.len()) {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= jobs.len() {
+                        break;
+                    }
+                    let r = self.run_job(&jobs[i]);
+                    results.lock().unwrap()[i] = Some(r);
+                });
+            }
+        });
+        Report{results: results.into_inner().unwrap().into_iter().map(|r| r.unwrap()).collect()}
+    }
+}
+
+// Jobs for paths given on a command line.  A directory with .tst scripts
+// stands for those scripts, one with .vm files for that program, and any
+// other directory for the jobs found in its subdirectories.
+pub fn find_jobs(path: &Path) -> Vec<Job> {
+    if !path.is_dir() {
+        return vec![Job::file(path)];
+    }
+    let mut entries = match std::fs::read_dir(path) {
+        Ok(rd) => rd.filter_map(|e| e.ok().map(|e| e.path())).collect::<Vec<_>>(),
+        Err(_) => return vec![Job::file(path)],
+    };
+    entries.sort();
+    let has = |ext: &str| entries.iter().any(|p| p.extension().is_some_and(|x| x == ext));
+    if has("tst") {
+        entries.iter().filter(|p| p.extension().is_some_and(|x| x == "tst")).map(|p| Job::file(p)).collect()
+    } else if has("vm") {
+        vec![Job::file(path)]
+    } else {
+        entries.iter().filter(|p| p.is_dir()).flat_map(|p| find_jobs(p)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let dir = std::env::temp_dir().join(format!("vmtrans-batch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("prog")).unwrap();
+        std::fs::write(dir.join("prog/Sys.vm"), "function Sys.init 0\npush constant 7\npop static 0\nlabel L\ngoto L\n").unwrap();
+        std::fs::write(dir.join("Add.asm"), "@2\nD=A\n@3\nD=D+A\n@0\nM=D\n").unwrap();
+        std::fs::write(dir.join("Add.cmp"), "|  RAM[0]  |\n|       5  |\n").unwrap();
+        std::fs::write(dir.join("Add.tst"), "load Add.asm, compare-to Add.cmp, output-list RAM[0]%D2.6.2;\nrepeat 6 { ticktock; } output;\n").unwrap();
+        std::fs::write(dir.join("Slow.tst"), "load Add.asm; repeat 1000 { ticktock; }\n").unwrap();
+
+        let mut jobs = find_jobs(&dir);
+        assert_eq!(jobs.iter().map(|j| j.name.rsplit('/').next().unwrap()).collect::<Vec<_>>(), vec!["Add.tst", "Slow.tst"]);
+        jobs.push(Job::file(&dir.join("prog")).with_expect(&[(16, 7)]));
+        jobs.push(Job::code("wrong", "@5\nD=A\n@100\nM=D\n").with_expect(&[(100, 6)]));
+        jobs.push(Job::code("spin", "(L)\n@L\nD;JEQ\n"));
+        jobs.push(Job::code("bus", "@30000\nM=1\n"));
+        jobs.push(Job::code("ram", "@100\nD=M\n@101\nM=D\n").with_ram(&[(100, 9)]).with_expect(&[(101, 9)]));
+        let report = Batch::new().with_threads(3).with_max_ticks(500).run(&jobs);
+        let outcomes = report.results.iter().map(|r| r.outcome.as_str()).collect::<Vec<_>>();
+        assert_eq!(outcomes, vec!["PASS", "TICKS", "PASS", "FAIL", "TICKS", "FAIL", "PASS"]);
+        assert_eq!(report.results[3].outcome, Outcome::Fail("RAM[100] = 5, expected 6".to_string()));
+        assert!(matches!(report.results[5].outcome, Outcome::Fail(ref m) if m.starts_with("Invalid memory access at address 30000")));
+        assert!(report.to_string().ends_with("3 passed, 4 failed"));
+
+        let report = Batch::new().with_max_ticks(u64::MAX).with_timeout(Duration::from_millis(20))
+            .run(&[Job::code("spin", "(L)\n@L\nD;JEQ\n")]);
+        assert_eq!(report.results[0].outcome, Outcome::Timeout);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,11 @@
+// hackdap.rs
+//
+// Debug Adapter Protocol server for Hack programs, speaking over stdin and
+// stdout.  The program to debug comes from the client's launch request.
+use vmtrans::dap::{DebugAdapter,serve};
+
+fn main() -> Result<(), std::io::Error> {
+    let stdout = std::io::stdout();
+    serve(DebugAdapter::new(), std::io::stdin(), &mut stdout.lock())?;
+    Ok(())
+}
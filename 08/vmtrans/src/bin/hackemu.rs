@@ -0,0 +1,324 @@
+// hackemu.rs
+//
+use std::cell::RefCell;
+use std::fs::{File,read,read_to_string};
+use std::io::{BufWriter,Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use vmtrans::coverage::Coverage;
+use vmtrans::cpu::{pin_header,pin_row};
+use vmtrans::emul::{Emul,Stop};
+use vmtrans::gdb;
+use vmtrans::debugger::Debugger;
+use vmtrans::loader::{parse_image,parse_sym};
+use vmtrans::ocr::{Font,read_text};
+use vmtrans::rpc::{self,RpcServer};
+use vmtrans::snapshot::Snapshot;
+use vmtrans::sourcemap::{SourceMap,load_program};
+use vmtrans::throttle::Throttle;
+use vmtrans::trace::{Tracer,TraceFormat};
+use vmtrans::tst::run_test_file;
+
+const USAGE: &str = "usage: hackemu --rpc
+       hackemu <prog.asm|prog.hack|prog.bin|prog.vm|dir|script.tst> [--sym FILE] [--ticks N]
+               [--debug-port ADDR] [--debug-log FILE]
+               [--trace FILE] [--trace-format text|jsonl] [--trace-label FUNCTION|LABEL]
+               [--trace-pc LO..HI] [--pins FILE] [--profile FILE]
+               [--load-snapshot FILE] [--save-snapshot FILE]
+               [--halt ADDR|LABEL] [--debug] [--gdb PORT] [--check-uninit] [--guard]
+               [--coverage FILE] [--tui] [--rate N] [--fps N]
+               [--screen-text Output.jack|Output.vm]";
+
+fn parse_range(s: &str) -> Option<std::ops::Range<usize>> {
+    let f = s.split("..").collect::<Vec<_>>();
+    if f.len() != 2 {
+        return None;
+    }
+    Some(f[0].parse().ok()?..f[1].parse().ok()?)
+}
+
+fn fail(e: impl std::fmt::Display) -> ! {
+    eprintln!("ERROR: {}", e);
+    std::process::exit(1);
+}
+
+fn debug_session(em: Emul, max_ticks: u64) -> Result<Emul, std::io::Error> {
+    let mut dbg = Debugger::new(em, 1_000_000, max_ticks);
+    println!("{}", dbg.location());
+    let stdin = std::io::stdin();
+    loop {
+        print!("(hackdb) ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 || line.trim() == "q" {
+            break;
+        }
+        println!("{}", dbg.command(&line));
+    }
+    Ok(dbg.em)
+}
+
+// Run, writing the CPU pins for each clock cycle in CPU.cmp format
+fn run_pins(em: &mut Emul, max_ticks: u64, path: &str) -> Result<Stop, std::io::Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{}", pin_header())?;
+    for _ in 0..max_ticks {
+        if em.pc() >= em.rom().len() {
+            return Ok(Stop::End);
+        }
+        if em.is_halted() {
+            return Ok(Stop::Halted);
+        }
+        let t = em.ticks();
+        let (plus, after) = em.step_pins();
+        if em.fault().is_some() {
+            return Ok(Stop::Fault);
+        }
+        writeln!(out, "{}\n{}", pin_row(&format!("{}+", t), &plus), pin_row(&(t + 1).to_string(), &after))?;
+    }
+    Ok(Stop::TickLimit)
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let mut args = std::env::args().skip(1);
+    let mut prog_path = None;
+    let mut max_ticks: u64 = 10_000_000;
+    let mut debug_port = None;
+    let mut debug_log = None;
+    let mut trace_path = None;
+    let mut trace_format = TraceFormat::Text;
+    let mut trace_label = None;
+    let mut trace_pc = None;
+    let mut profile_path = None;
+    let mut sym_path = None;
+    let mut load_snapshot = None;
+    let mut save_snapshot = None;
+    let mut debug = false;
+    let mut check_uninit = false;
+    let mut guard = false;
+    let mut halts = vec![];
+    let mut pins_path = None;
+    let mut gdb_port: Option<u16> = None;
+    let mut rpc = false;
+    let mut coverage_path = None;
+    let mut tui = false;
+    let mut rate = None;
+    let mut fps = None;
+    let mut screen_font = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ticks" => max_ticks = args.next().and_then(|s| s.parse().ok()).expect(USAGE),
+            "--debug-port" => debug_port = Some(args.next().and_then(|s| s.parse().ok()).expect(USAGE)),
+            "--debug-log" => debug_log = Some(args.next().expect(USAGE)),
+            "--trace" => trace_path = Some(args.next().expect(USAGE)),
+            "--trace-format" => trace_format = args.next().and_then(|s| TraceFormat::from_str(&s)).expect(USAGE),
+            "--trace-label" => trace_label = Some(args.next().expect(USAGE)),
+            "--trace-pc" => trace_pc = Some(args.next().and_then(|s| parse_range(&s)).expect(USAGE)),
+            "--load-snapshot" => load_snapshot = Some(args.next().expect(USAGE)),
+            "--save-snapshot" => save_snapshot = Some(args.next().expect(USAGE)),
+            "--debug" => debug = true,
+            "--gdb" => gdb_port = Some(args.next().and_then(|s| s.parse().ok()).expect(USAGE)),
+            "--check-uninit" => check_uninit = true,
+            "--guard" => guard = true,
+            "--rpc" => rpc = true,
+            "--tui" => tui = true,
+            "--rate" => rate = Some(args.next().and_then(|s| s.parse().ok()).expect(USAGE)),
+            "--screen-text" => screen_font = Some(args.next().expect(USAGE)),
+            "--fps" => fps = Some(args.next().and_then(|s| s.parse().ok()).expect(USAGE)),
+            "--coverage" => coverage_path = Some(args.next().expect(USAGE)),
+            "--halt" => halts.push(args.next().expect(USAGE)),
+            "--sym" => sym_path = Some(args.next().expect(USAGE)),
+            "--pins" => pins_path = Some(args.next().expect(USAGE)),
+            "--profile" => profile_path = Some(args.next().expect(USAGE)),
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            },
+            _ => prog_path = Some(arg),
+        }
+    }
+    if rpc {
+        // JSON-RPC requests on stdin; the client loads the program
+        let stdin = std::io::stdin();
+        rpc::serve(RpcServer::new(), stdin.lock(), &mut std::io::stdout())?;
+        return Ok(());
+    }
+    let prog_path = prog_path.expect(USAGE);
+    if prog_path.ends_with(".tst") {
+        match run_test_file(std::path::Path::new(&prog_path)) {
+            Ok(run) => match run.failure {
+                Some(m) => fail(m),
+                None if run.compared => println!("End of script - Comparison ended successfully"),
+                None => println!("End of script"),
+            },
+            Err(e) => fail(e),
+        }
+        return Ok(());
+    }
+
+    let mut em = Emul::new();
+    if let Some(addr) = debug_port {
+        let out: Box<dyn Write> = match debug_log {
+            Some(path) => Box::new(File::create(path)?),
+            None => Box::new(std::io::stdout()),
+        };
+        em.enable_debug_port(addr, out).unwrap_or_else(|e| fail(e));
+    }
+    if let Some(path) = trace_path {
+        let mut tracer = Tracer::new(Box::new(BufWriter::new(File::create(path)?)), trace_format);
+        if let Some(label) = trace_label {
+            tracer = tracer.with_label(&label);
+        }
+        if let Some(range) = trace_pc {
+            tracer = tracer.with_pc_range(range);
+        }
+        em.set_tracer(tracer);
+    }
+    if profile_path.is_some() {
+        em.enable_profiler();
+    }
+    if check_uninit {
+        em.enable_uninit_check();
+    }
+    if guard {
+        em.enable_guard();
+    }
+
+    if let Some(path) = sym_path {
+        match parse_sym(&read_to_string(path)?) {
+            Ok(syms) => em.set_labels(&syms),
+            Err(e) => fail(e),
+        }
+    }
+    let map = if prog_path.ends_with(".hack") {
+        if let Err(e) = em.load_hack(&read_to_string(&prog_path)?) {
+            fail(e);
+        }
+        em.find_functions();
+        SourceMap::for_rom(&prog_path, em.rom().len())
+    } else if prog_path.ends_with(".bin") {
+        match parse_image(&read(&prog_path)?) {
+            Ok(rom) => em.load_rom(rom),
+            Err(e) => fail(e),
+        }
+        em.find_functions();
+        SourceMap::for_rom(&prog_path, em.rom().len())
+    } else {
+        // .asm, or .vm translated on the fly
+        let (code, map, bootstrap) = load_program(&prog_path).unwrap_or_else(|e| fail(e));
+        if let Err(e) = em.load_code(&code) {
+            fail(e);
+        }
+        // Assembly carries no function table, so look for prologues
+        if map.funcs().is_empty() {
+            em.find_functions();
+        } else {
+            em.set_functions(&map.functions());
+        }
+        if !bootstrap {
+            em.poke(0, 256);
+        }
+        map
+    };
+    let coverage = coverage_path.as_ref().map(|_| {
+        let cov = Rc::new(RefCell::new(Coverage::new(em.rom())));
+        em.add_observer(Box::new(cov.clone()));
+        cov
+    });
+    for h in halts {
+        match h.parse() {
+            Ok(addr) => em.set_halt(addr),
+            Err(_) => if !em.set_halt_label(&h) {
+                fail(format!("Unknown halt label {}", h));
+            },
+        }
+    }
+    if let Some(path) = load_snapshot {
+        match Snapshot::parse(&read_to_string(path)?) {
+            Ok(snap) => em.restore(&snap).unwrap_or_else(|e| fail(e)),
+            Err(e) => fail(e),
+        }
+    }
+
+    let screen_font = screen_font.map(|path| Font::load(&path).unwrap_or_else(|e| fail(e)));
+    // The TUI always runs at a fixed rate; a plain run only if asked
+    let throttle = if tui || rate.is_some() || fps.is_some() {
+        let mut t = Throttle::new();
+        if let Some(rate) = rate {
+            t = t.with_rate(rate);
+        }
+        if let Some(fps) = fps {
+            t = t.with_fps(fps);
+        }
+        Some(t)
+    } else {
+        None
+    };
+
+    let mut trapped = false;
+    if let Some(port) = gdb_port {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+        em = gdb::serve(em, listener)?;
+    } else if debug {
+        em = debug_session(em, max_ticks)?;
+    } else {
+        let stop = match pins_path {
+            Some(path) => run_pins(&mut em, max_ticks, &path)?,
+            None if tui => vmtrans::tui::run(&mut em, max_ticks, throttle.unwrap())?,
+            None => match throttle {
+                Some(mut t) => t.run(&mut em, max_ticks, |_| true),
+                None => em.resume(max_ticks),
+            },
+        };
+        match stop {
+            Stop::TickLimit => eprintln!("Tick limit reached at pc {} after {} ticks", em.pc(), em.ticks()),
+            Stop::Trapped => {
+                eprintln!("{}", em.trap().unwrap());
+                eprint!("{}", em.backtrace());
+                trapped = true;
+            },
+            Stop::Fault => {
+                eprintln!("{}", em.fault_report().unwrap());
+                trapped = true;
+            },
+            stop => eprintln!("{:?} at pc {} after {} ticks", stop, em.pc(), em.ticks()),
+        }
+    }
+
+    if let Some(font) = screen_font {
+        for line in read_text(&em, &font) {
+            println!("{}", line);
+        }
+    }
+    for r in em.uninit_reads() {
+        eprintln!("{}", r);
+    }
+    if let Some(path) = save_snapshot {
+        write!(File::create(path)?, "{}", em.snapshot())?;
+    }
+    if let (Some(path), Some(p)) = (profile_path, em.profiler()) {
+        let mut f = File::create(path)?;
+        write!(f, "{}\n{}", p.flat_report(), p.call_tree_report())?;
+    }
+    if let (Some(path), Some(cov)) = (coverage_path, coverage) {
+        let cov = cov.borrow();
+        let mut f = File::create(path)?;
+        write!(f, "{}", cov.lcov(&map))?;
+        // Subroutine records for the .jack files the .vm files came from
+        for vm in map.files.iter().filter(|p| p.ends_with(".vm")) {
+            let jack = Path::new(vm).with_extension("jack");
+            if let Ok(src) = read_to_string(&jack) {
+                write!(f, "{}", cov.jack_lcov(&map, &jack.to_string_lossy(), &src))?;
+            }
+        }
+        if !map.funcs().is_empty() {
+            eprint!("{}", cov.summary(&map));
+        }
+    }
+    if trapped {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+// hackrun.rs
+//
+// Run many programs and test scripts in parallel and report the results.
+// A directory with .tst scripts runs those scripts, one with .vm files runs
+// as a single program, and any other directory is searched for both.
+use std::path::Path;
+use std::time::Duration;
+
+use vmtrans::batch::{Batch,find_jobs};
+
+const USAGE: &str = "usage: hackrun [--jobs N] [--ticks N] [--timeout SECS] <prog|dir|script.tst>...";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut batch = Batch::new();
+    let mut jobs = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--jobs" => batch = batch.with_threads(args.next().and_then(|s| s.parse().ok()).expect(USAGE)),
+            "--ticks" => batch = batch.with_max_ticks(args.next().and_then(|s| s.parse().ok()).expect(USAGE)),
+            "--timeout" => {
+                let secs: f64 = args.next().and_then(|s| s.parse().ok())
+                    .filter(|s: &f64| s.is_finite() && *s >= 0.0).expect(USAGE);
+                batch = batch.with_timeout(Duration::from_secs_f64(secs));
+            },
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            },
+            _ => jobs.extend(find_jobs(Path::new(&arg))),
+        }
+    }
+    if jobs.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let report = batch.run(&jobs);
+    println!("{}", report);
+    if !report.all_passed() {
+        std::process::exit(1);
+    }
+}
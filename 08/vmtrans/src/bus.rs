@@ -0,0 +1,369 @@
+use std::fmt;
+use std::io::Write;
+
+// Hack memory map
+pub const RAM_SIZE: usize = 16384;
+pub const SCREEN: usize = 16384;
+pub const SCREEN_SIZE: usize = 8192;
+pub const KBD: usize = 24576;
+
+// Anything that can sit on the bus.  Offsets are relative to the start
+// of the address range the device is mapped at.
+pub trait Device {
+    fn read(&self, offset: usize) -> i16;
+    fn write(&mut self, offset: usize, val: i16);
+
+    // Internal state to include in a snapshot, if any
+    fn save(&self) -> Vec<i16> {
+        vec![]
+    }
+
+    fn restore(&mut self, _state: &[i16]) {}
+}
+
+pub struct Ram {
+    words: Vec<i16>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Ram {
+        Ram{words: vec![0; size]}
+    }
+}
+
+impl Device for Ram {
+    fn read(&self, offset: usize) -> i16 {
+        self.words[offset]
+    }
+
+    fn write(&mut self, offset: usize, val: i16) {
+        self.words[offset] = val;
+    }
+
+    fn save(&self) -> Vec<i16> {
+        self.words.clone()
+    }
+
+    fn restore(&mut self, state: &[i16]) {
+        self.words.copy_from_slice(state);
+    }
+}
+
+pub struct Screen {
+    words: Vec<i16>,
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen{words: vec![0; SCREEN_SIZE]}
+    }
+
+    // true if the pixel at (row, col) is black
+    pub fn pixel(&self, row: usize, col: usize) -> bool {
+        (self.words[row * 32 + col / 16] >> (col % 16)) & 1 == 1
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Screen {
+    fn read(&self, offset: usize) -> i16 {
+        self.words[offset]
+    }
+
+    fn write(&mut self, offset: usize, val: i16) {
+        self.words[offset] = val;
+    }
+
+    fn save(&self) -> Vec<i16> {
+        self.words.clone()
+    }
+
+    fn restore(&mut self, state: &[i16]) {
+        self.words.copy_from_slice(state);
+    }
+}
+
+// The keyboard register is set by the host; the CPU can only read it.
+#[derive(Default)]
+pub struct Keyboard {
+    pub key: i16,
+}
+
+impl Device for Keyboard {
+    fn read(&self, _offset: usize) -> i16 {
+        self.key
+    }
+
+    fn write(&mut self, _offset: usize, _val: i16) {}
+}
+
+// Debug console: a character written to offset 0 or a number written to
+// offset 1 is sent to the host.  Hack newline (128) is mapped to '\n'.
+// The program is not stopped if the host side fails, e.g. a closed pipe;
+// the port just drops everything from then on.
+pub struct DebugPort {
+    out: Box<dyn Write>,
+    failed: bool,
+}
+
+impl DebugPort {
+    pub const LEN: usize = 2;
+
+    pub fn new(out: Box<dyn Write>) -> DebugPort {
+        DebugPort{out, failed: false}
+    }
+
+    // Whether a write to the host has failed
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+}
+
+impl Device for DebugPort {
+    fn read(&self, _offset: usize) -> i16 {
+        0
+    }
+
+    fn write(&mut self, offset: usize, val: i16) {
+        if self.failed {
+            return;
+        }
+        let r = if offset == 0 {
+            match val {
+                128 => writeln!(self.out),
+                0..=127 => write!(self.out, "{}", val as u8 as char),
+                _ => Ok(()),
+            }
+        } else {
+            write!(self.out, "{}", val)
+        };
+        self.failed = r.and_then(|_| self.out.flush()).is_err();
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub struct BusError {
+    pub addr: usize,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid memory access at address {}", self.addr)
+    }
+}
+
+struct Mapping {
+    base: usize,
+    len: usize,
+    dev: Box<dyn Device>,
+}
+
+// Routes addresses to RAM, screen, keyboard and any user devices.  User
+// devices are checked first, so they may shadow part of the standard map
+// or live above KBD.  Any other address above KBD is an error.
+pub struct Bus {
+    pub ram: Ram,
+    pub screen: Screen,
+    pub keyboard: Keyboard,
+    devices: Vec<Mapping>,
+    ram_shadowed: bool,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus{ram: Ram::new(RAM_SIZE), screen: Screen::new(), keyboard: Keyboard::default(), devices: vec![], ram_shadowed: false}
+    }
+
+    pub fn map(&mut self, base: usize, len: usize, dev: Box<dyn Device>) {
+        for m in &self.devices {
+            if base < m.base + m.len && m.base < base + len {
+                panic!("Device at {} overlaps device at {}", base, m.base);
+            }
+        }
+        self.ram_shadowed |= base < RAM_SIZE;
+        self.devices.push(Mapping{base, len, dev});
+    }
+
+    // True for plain RAM, i.e. not shadowed by a user device
+    pub fn is_ram(&self, addr: usize) -> bool {
+        addr < RAM_SIZE && !self.devices.iter().any(|m| addr >= m.base && addr < m.base + m.len)
+    }
+
+    // True for RAM and screen words not shadowed by a user device, which
+    // can be written without side effects
+    pub fn is_memory(&self, addr: usize) -> bool {
+        addr < KBD && !self.devices.iter().any(|m| addr >= m.base && addr < m.base + m.len)
+    }
+
+    // Write a RAM or screen word directly; see is_memory
+    pub(crate) fn write_memory(&mut self, addr: usize, val: i16) {
+        if addr < RAM_SIZE {
+            self.ram.words[addr] = val;
+        } else {
+            self.screen.words[addr - SCREEN] = val;
+        }
+    }
+
+    // Saved state of each user device, in the order they were mapped
+    pub fn save_devices(&self) -> Vec<Vec<i16>> {
+        self.devices.iter().map(|m| m.dev.save()).collect()
+    }
+
+    // Put back states from save_devices.  Nothing is restored unless each
+    // device has a state of the size it saves.
+    pub fn restore_devices(&mut self, states: &[Vec<i16>]) -> Result<(), String> {
+        if states.len() != self.devices.len() {
+            let n = self.devices.len();
+            return Err(format!("Snapshot has {} device states, but {} device{} mapped",
+                               states.len(), n, if n == 1 { " is" } else { "s are" }));
+        }
+        for (i, (m, state)) in self.devices.iter().zip(states).enumerate() {
+            let n = m.dev.save().len();
+            if state.len() != n {
+                return Err(format!("Device {} state has {} words, expected {}", i, state.len(), n));
+            }
+        }
+        for (m, state) in self.devices.iter_mut().zip(states) {
+            m.dev.restore(state);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn read(&self, addr: usize) -> Result<i16, BusError> {
+        if addr < RAM_SIZE && !self.ram_shadowed {
+            return Ok(self.ram.words[addr]);
+        }
+        if let Some(m) = self.devices.iter().find(|m| addr >= m.base && addr < m.base + m.len) {
+            return Ok(m.dev.read(addr - m.base));
+        }
+        if addr < RAM_SIZE {
+            Ok(self.ram.read(addr))
+        } else if addr < KBD {
+            Ok(self.screen.read(addr - SCREEN))
+        } else if addr == KBD {
+            Ok(self.keyboard.read(0))
+        } else {
+            Err(BusError{addr})
+        }
+    }
+
+    #[inline]
+    pub fn write(&mut self, addr: usize, val: i16) -> Result<(), BusError> {
+        if addr < RAM_SIZE && !self.ram_shadowed {
+            self.ram.words[addr] = val;
+            return Ok(());
+        }
+        if let Some(m) = self.devices.iter_mut().find(|m| addr >= m.base && addr < m.base + m.len) {
+            m.dev.write(addr - m.base, val);
+            return Ok(());
+        }
+        if addr < RAM_SIZE {
+            self.ram.write(addr, val);
+        } else if addr < KBD {
+            self.screen.write(addr - SCREEN, val);
+        } else if addr == KBD {
+            self.keyboard.write(0, val);
+        } else {
+            return Err(BusError{addr});
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::SharedBuf;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Mailbox {
+        last: Rc<RefCell<Vec<(usize, i16)>>>,
+    }
+
+    impl Device for Mailbox {
+        fn read(&self, offset: usize) -> i16 {
+            offset as i16
+        }
+
+        fn write(&mut self, offset: usize, val: i16) {
+            self.last.borrow_mut().push((offset, val));
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut bus = Bus::new();
+        bus.write(100, 7).unwrap();
+        bus.write(SCREEN + 1, -1).unwrap();
+        bus.keyboard.key = 65;
+        assert_eq!(bus.read(100), Ok(7));
+        assert_eq!(bus.read(SCREEN + 1), Ok(-1));
+        assert!(bus.screen.pixel(0, 16));
+        assert!(!bus.screen.pixel(0, 15));
+        assert_eq!(bus.read(KBD), Ok(65));
+        assert_eq!(bus.read(KBD + 1), Err(BusError{addr: KBD + 1}));
+        assert_eq!(bus.write(32767, 1), Err(BusError{addr: 32767}));
+    }
+
+    #[test]
+    fn test_user_device() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut bus = Bus::new();
+        bus.map(KBD + 1, 4, Box::new(Mailbox{last: log.clone()}));
+        bus.write(KBD + 3, 42).unwrap();
+        assert_eq!(bus.read(KBD + 4), Ok(3));
+        assert_eq!(*log.borrow(), vec![(2, 42)]);
+        assert!(bus.read(KBD + 5).is_err());
+    }
+
+    #[test]
+    fn test_debug_port() {
+        let buf = SharedBuf::default();
+        let mut bus = Bus::new();
+        bus.map(KBD + 1, DebugPort::LEN, Box::new(DebugPort::new(Box::new(buf.clone()))));
+        for c in "x=".chars() {
+            bus.write(KBD + 1, c as i16).unwrap();
+        }
+        bus.write(KBD + 2, -12).unwrap();
+        bus.write(KBD + 1, 128).unwrap();
+        assert_eq!(buf.text(), "x=-12\n");
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug_port_closed() {
+        let mut port = DebugPort::new(Box::new(Closed));
+        port.write(0, 65);
+        assert!(port.failed());
+        port.write(1, 7);
+        assert!(port.failed());
+
+        let mut bus = Bus::new();
+        bus.map(KBD + 1, DebugPort::LEN, Box::new(DebugPort::new(Box::new(Closed))));
+        assert_eq!(bus.write(KBD + 1, 65), Ok(()));
+    }
+}
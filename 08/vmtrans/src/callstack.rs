@@ -0,0 +1,228 @@
+use std::fmt;
+
+use crate::asm::Asm;
+use crate::emul::Emul;
+use crate::guard::{STACK_START,STACK_END};
+
+// Frames deeper than this are taken to be a corrupt LCL chain
+const MAX_DEPTH: usize = 10_000;
+// Arguments and locals kept per frame
+const MAX_VALUES: usize = 16;
+
+// One VM function activation.  pc is where the frame is executing: the
+// current pc for the innermost frame, the return address for the others.
+// args and locals hold at most MAX_VALUES of the n_args and n_locals.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub pc: usize,
+    pub return_addr: Option<usize>,
+    pub lcl: usize,
+    pub arg: usize,
+    pub args: Vec<i16>,
+    pub n_args: usize,
+    pub locals: Vec<i16>,
+    pub n_locals: usize,
+}
+
+// [1, 2, ...] when only some of n values are kept
+fn write_values(f: &mut fmt::Formatter, vals: &[i16], n: usize) -> fmt::Result {
+    write!(f, "[")?;
+    for (i, v) in vals.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", v)?;
+    }
+    if n > vals.len() {
+        write!(f, ", ...")?;
+    }
+    write!(f, "]")
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at pc {}", self.function.as_deref().unwrap_or("(top)"), self.pc)?;
+        if self.function.is_some() {
+            write!(f, " args=")?;
+            write_values(f, &self.args, self.n_args)?;
+            write!(f, " locals=")?;
+            write_values(f, &self.locals, self.n_locals)?;
+        }
+        if let Some(ra) = self.return_addr {
+            write!(f, " returns to {}", ra)?;
+        }
+        Ok(())
+    }
+}
+
+// Words of the prologue the translator emits for "function F n": @SP A=M,
+// then M=0 A=A+1 for each local
+struct Prologue {
+    sp: u16,
+    a_eq_m: u16,
+    zero: u16,
+    inc: u16,
+}
+
+impl Prologue {
+    fn new() -> Prologue {
+        let asm = Asm::new();
+        let word = |s: &str| asm.parse_cmd(s).unwrap().unwrap().encode().unwrap();
+        Prologue{sp: word("@0"), a_eq_m: word("A=M"), zero: word("M=0"), inc: word("A=A+1")}
+    }
+
+    fn starts(&self, rom: &[u16], addr: usize) -> bool {
+        rom.get(addr) == Some(&self.sp) && rom.get(addr + 1) == Some(&self.a_eq_m)
+    }
+
+    fn n_locals(&self, rom: &[u16], addr: usize) -> usize {
+        let mut n = 0;
+        while rom.get(addr + 2 + 2*n) == Some(&self.zero) && rom.get(addr + 3 + 2*n) == Some(&self.inc) {
+            n += 1;
+        }
+        n
+    }
+}
+
+// The labels a function prologue starts at, for a program known only by
+// its ROM and symbol table
+pub fn prologue_functions(labels: &[(usize,String)], rom: &[u16]) -> Vec<(usize,String)> {
+    let pro = Prologue::new();
+    labels.iter().filter(|(addr, _)| pro.starts(rom, *addr)).cloned().collect()
+}
+
+// Walk the saved LCL chain.  The frame a call pushes holds the return
+// address, LCL, ARG, THIS and THAT just below the callee's LCL.  This is
+// exact between VM commands; inside a call or return sequence the
+// registers are half updated.  The walk ends at code outside any function
+// or at a frame that isn't on the stack, as at the top level.
+pub fn unwind(em: &Emul) -> Vec<Frame> {
+    let ram = |addr: usize| em.bus.read(addr).unwrap_or(0);
+    let pro = Prologue::new();
+    let mut frames = vec![];
+    let mut pc = em.pc();
+    let mut lcl = ram(1) as u16 as usize;
+    let mut arg = ram(2) as u16 as usize;
+    while frames.len() < MAX_DEPTH {
+        let on_stack = (STACK_START..=STACK_END).contains(&arg) && arg + 5 <= lcl && lcl <= STACK_END + 1;
+        let (faddr, name) = match em.function_at(pc) {
+            Some((faddr, name)) if on_stack => (faddr, name.to_string()),
+            _ => {
+                frames.push(Frame{function: None, pc, return_addr: None, lcl, arg,
+                                  args: vec![], n_args: 0, locals: vec![], n_locals: 0});
+                break;
+            },
+        };
+        let n_args = lcl - 5 - arg;
+        let n_locals = pro.n_locals(em.rom(), faddr);
+        let ra = ram(lcl - 5) as u16 as usize;
+        frames.push(Frame{
+            function: Some(name),
+            pc,
+            return_addr: Some(ra),
+            lcl,
+            arg,
+            args: (arg..arg + n_args.min(MAX_VALUES)).map(ram).collect(),
+            n_args,
+            locals: (lcl..lcl + n_locals.min(MAX_VALUES)).map(ram).collect(),
+            n_locals,
+        });
+        pc = ra;
+        arg = ram(lcl - 3) as u16 as usize;
+        lcl = ram(lcl - 4) as u16 as usize;
+    }
+    frames
+}
+
+// One line per frame, innermost first.  Runs of identical frames, as from
+// runaway recursion, are shown once with a count.
+pub fn format_backtrace(frames: &[Frame]) -> String {
+    let mut r = String::new();
+    let mut i = 0;
+    while i < frames.len() {
+        let line = frames[i].to_string();
+        let n = frames[i + 1..].iter().take_while(|f| f.to_string() == line).count();
+        r += &format!("#{} {}\n", i, line);
+        if n > 0 {
+            r += &format!("... {} more identical frames\n", n);
+        }
+        i += n + 1;
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::load_vm;
+    use crate::types::*;
+
+    #[test]
+    fn test_unwind() {
+        let table = vec![
+            VMCommand::Call("Main.main".to_string(), 0),
+            VMCommand::Label("END".to_string()),
+            VMCommand::Goto("END".to_string()),
+            VMCommand::Function("Main.main".to_string(), 2),
+            VMCommand::Push(VMSeg::CONSTANT, 7),
+            VMCommand::Pop(VMSeg::LOCAL, 1),
+            VMCommand::Push(VMSeg::CONSTANT, 3),
+            VMCommand::Push(VMSeg::CONSTANT, 4),
+            VMCommand::Call("Main.add".to_string(), 2),
+            VMCommand::Return,
+            VMCommand::Function("Main.add".to_string(), 0),
+            VMCommand::Push(VMSeg::ARGUMENT, 0),
+            VMCommand::Label("BRK".to_string()),
+            VMCommand::Push(VMSeg::ARGUMENT, 1),
+            VMCommand::Arithmetic(VMOp::ADD),
+            VMCommand::Return,
+        ];
+        let mut em = Emul::new();
+        load_vm(&mut em, &table, &[(0, 256), (1, 0), (2, 0)]);
+        em.add_breakpoint(em.label_addr("Main.add$BRK").unwrap());
+        em.resume(10_000);
+
+        let frames = em.call_stack();
+        let summary = frames.iter().map(|f| (f.function.clone(), f.args.clone(), f.locals.clone())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (Some("Main.add".to_string()), vec![3, 4], vec![]),
+            (Some("Main.main".to_string()), vec![], vec![0, 7]),
+            (None, vec![], vec![]),
+        ]);
+        assert_eq!(frames[0].return_addr, Some(frames[1].pc));
+        assert_eq!(em.label_at(frames[2].pc), Some("RETURN.Main.0"));
+        assert!(em.backtrace().starts_with("#0 Main.add at pc "));
+        assert!(em.backtrace().contains("args=[3, 4] locals=[]"));
+
+        let mut deep = vec![frames[1].clone(); 3];
+        deep.push(frames[2].clone());
+        deep[0].pc = 99;
+        let bt = format_backtrace(&deep);
+        assert_eq!(bt.lines().map(|l| l.split(' ').next().unwrap()).collect::<Vec<_>>(), vec!["#0", "#1", "...", "#3"]);
+        assert!(bt.contains("... 1 more identical frames\n#3 (top)"));
+    }
+
+    #[test]
+    fn test_unwind_limits() {
+        // 20 locals, of which the first 16 are shown
+        let table = vec![
+            VMCommand::Call("Main.big".to_string(), 0),
+            VMCommand::Function("Main.big".to_string(), 20),
+            VMCommand::Label("BRK".to_string()),
+            VMCommand::Return,
+        ];
+        let mut em = Emul::new();
+        load_vm(&mut em, &table, &[(0, 256), (1, 0), (2, 0)]);
+        em.add_breakpoint(em.label_addr("Main.big$BRK").unwrap());
+        em.resume(10_000);
+        let frames = em.call_stack();
+        assert_eq!((frames[0].locals.len(), frames[0].n_locals), (16, 20));
+        assert!(frames[0].to_string().contains(&format!("locals=[{}, ...]", vec!["0"; 16].join(", "))));
+        assert_eq!(frames.len(), 2);
+
+        // An ARG outside the stack ends the walk
+        em.poke(2, 100);
+        assert_eq!(em.call_stack()[0].function, None);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt::Write;
+
+use crate::emul::Stop;
+use crate::observer::Observer;
+use crate::sourcemap::SourceMap;
+
+// True for a C-instruction with a jump that depends on the ALU result
+fn is_cond_jump(instr: u16) -> bool {
+    instr & 0x8000 != 0 && !matches!(instr & 7, 0 | 7)
+}
+
+// Subroutine declarations in Jack source, as (line, Class.name)
+fn jack_subroutines(src: &str) -> Vec<(usize,String)> {
+    let mut class = None;
+    let mut r = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let ws = line.split("//").next().unwrap().split_whitespace().collect::<Vec<_>>();
+        match ws.as_slice() {
+            ["class", name, ..] => class = Some(name.trim_end_matches('{').to_string()),
+            [kind, _, name, ..] if matches!(*kind, "constructor" | "function" | "method") => {
+                if let Some(ref c) = class {
+                    r.push((i + 1, format!("{}.{}", c, name.split('(').next().unwrap())));
+                }
+            },
+            _ => (),
+        }
+    }
+    r
+}
+
+// Execution counts per ROM address, plus how often each conditional jump
+// was taken and not taken.  Attach with Emul::add_observer; results are
+// mapped back to source lines through a SourceMap.
+pub struct Coverage {
+    rom: Vec<u16>,
+    hits: Vec<u64>,
+    taken: Vec<u64>,
+    not_taken: Vec<u64>,
+    prev: Option<usize>,
+}
+
+#[derive(Debug,PartialEq,Default,Clone,Copy)]
+pub struct Counts {
+    pub hit: usize,
+    pub total: usize,
+}
+
+impl Counts {
+    fn add(&mut self, hit: bool) {
+        self.total += 1;
+        if hit {
+            self.hit += 1;
+        }
+    }
+
+    fn cell(&self) -> String {
+        match self.total {
+            0 => "-".to_string(),
+            t => format!("{}/{} {:3}%", self.hit, t, self.hit * 100 / t),
+        }
+    }
+}
+
+// Per-function totals for the summary table
+#[derive(Debug,PartialEq,Clone)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub calls: u64,
+    pub lines: Counts,
+    pub instrs: Counts,
+    pub branches: Counts,
+}
+
+impl Observer for Coverage {
+    fn on_step(&mut self, pc: usize, _instr: u16) -> Option<Stop> {
+        if let Some(prev) = self.prev.take() {
+            if pc == prev + 1 {
+                self.not_taken[prev] += 1;
+            } else {
+                self.taken[prev] += 1;
+            }
+        }
+        if pc < self.hits.len() {
+            self.hits[pc] += 1;
+            if is_cond_jump(self.rom[pc]) {
+                self.prev = Some(pc);
+            }
+        }
+        None
+    }
+}
+
+impl Coverage {
+    pub fn new(rom: &[u16]) -> Coverage {
+        let n = rom.len();
+        Coverage{rom: rom.to_vec(), hits: vec![0; n], taken: vec![0; n], not_taken: vec![0; n], prev: None}
+    }
+
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(addr).copied().unwrap_or(0)
+    }
+
+    fn jumps(&self, range: std::ops::Range<usize>) -> impl Iterator<Item=usize> + '_ {
+        range.filter(move |a| is_cond_jump(self.rom[*a]))
+    }
+
+    // Lines with code, as (line index, ROM range)
+    fn code_lines<'a>(&'a self, map: &'a SourceMap) -> impl Iterator<Item=(usize, std::ops::Range<usize>)> + 'a {
+        (0..map.lines().len())
+            .map(move |i| (i, map.line_range(i, self.rom.len())))
+            .filter(|(_, r)| !r.is_empty())
+    }
+
+    pub fn functions(&self, map: &SourceMap) -> Vec<FunctionCoverage> {
+        let funcs = map.funcs();
+        let mut r = funcs.iter().map(|f| FunctionCoverage{
+            name: f.name.clone(), calls: self.hits(f.addr),
+            lines: Counts::default(), instrs: Counts::default(), branches: Counts::default(),
+        }).collect::<Vec<_>>();
+        for (i, range) in self.code_lines(map) {
+            let fi = match map.func_at(map.lines()[i].addr) {
+                Some(f) => funcs.iter().position(|g| g.addr == f.addr).unwrap(),
+                None => continue,
+            };
+            let fc = &mut r[fi];
+            fc.lines.add(self.hits(range.start) > 0);
+            for a in range.clone() {
+                fc.instrs.add(self.hits(a) > 0);
+            }
+            for a in self.jumps(range) {
+                fc.branches.add(self.taken[a] > 0);
+                fc.branches.add(self.not_taken[a] > 0);
+            }
+        }
+        r
+    }
+
+    // One row per function
+    pub fn summary(&self, map: &SourceMap) -> String {
+        let mut r = format!("{:<30} {:>8} {:>14} {:>14} {:>14}\n", "Function", "Calls", "Lines", "Instructions", "Branches");
+        for f in self.functions(map) {
+            writeln!(&mut r, "{:<30} {:>8} {:>14} {:>14} {:>14}", f.name, f.calls,
+                     f.lines.cell(), f.instrs.cell(), f.branches.cell()).unwrap();
+        }
+        r
+    }
+
+    // lcov tracefile, one record per source file.  A line's count is the
+    // count of its first instruction; each conditional jump in it is a
+    // pair of branches, taken and not taken.
+    pub fn lcov(&self, map: &SourceMap) -> String {
+        let mut r = String::new();
+        for (fi, path) in map.files.iter().enumerate() {
+            writeln!(&mut r, "TN:\nSF:{}", path).unwrap();
+            let mut fns = Counts::default();
+            for f in map.funcs().iter().filter(|f| f.file == fi) {
+                let line = map.loc(f.addr).map_or(0, |l| l.line);
+                writeln!(&mut r, "FN:{},{}\nFNDA:{},{}", line, f.name, self.hits(f.addr), f.name).unwrap();
+                fns.add(self.hits(f.addr) > 0);
+            }
+            writeln!(&mut r, "FNF:{}\nFNH:{}", fns.total, fns.hit).unwrap();
+            let (mut lines, mut branches) = (Counts::default(), Counts::default());
+            let mut da = String::new();
+            for (i, range) in self.code_lines(map).filter(|(i, _)| map.lines()[*i].file == fi) {
+                let line = map.lines()[i].line;
+                let hits = self.hits(range.start);
+                for (n, a) in self.jumps(range).enumerate() {
+                    for (b, count) in [self.taken[a], self.not_taken[a]].iter().enumerate() {
+                        let taken = if hits == 0 { "-".to_string() } else { count.to_string() };
+                        writeln!(&mut r, "BRDA:{},{},{},{}", line, n, b, taken).unwrap();
+                        branches.add(*count > 0);
+                    }
+                }
+                writeln!(&mut da, "DA:{},{}", line, hits).unwrap();
+                lines.add(hits > 0);
+            }
+            writeln!(&mut r, "BRF:{}\nBRH:{}", branches.total, branches.hit).unwrap();
+            r += &da;
+            writeln!(&mut r, "LF:{}\nLH:{}\nend_of_record", lines.total, lines.hit).unwrap();
+        }
+        r
+    }
+
+    // lcov record for the .jack source of a class.  VM code from the Jack
+    // compiler carries no .jack line numbers, so only subroutines map back:
+    // each is a function whose declaration line ran if it was called.
+    // Statements inside them are covered per .vm line only.
+    pub fn jack_lcov(&self, map: &SourceMap, path: &str, src: &str) -> String {
+        let subs = jack_subroutines(src).into_iter()
+            .filter_map(|(line, name)| {
+                let f = map.funcs().iter().find(|f| f.name == name)?;
+                Some((line, name, self.hits(f.addr)))
+            })
+            .collect::<Vec<_>>();
+        let mut r = format!("TN:\nSF:{}\n", path);
+        let mut fns = Counts::default();
+        for (line, name, hits) in &subs {
+            writeln!(&mut r, "FN:{},{}\nFNDA:{},{}", line, name, hits, name).unwrap();
+            fns.add(*hits > 0);
+        }
+        writeln!(&mut r, "FNF:{}\nFNH:{}", fns.total, fns.hit).unwrap();
+        for (line, _, hits) in &subs {
+            writeln!(&mut r, "DA:{},{}", line, hits).unwrap();
+        }
+        writeln!(&mut r, "LF:{}\nLH:{}\nend_of_record", fns.total, fns.hit).unwrap();
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::Emul;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const MAIN_VM: &str = "\
+function Main.main 0
+push constant 3
+call Main.abs 1
+push constant 0
+call Main.abs 1
+return
+function Main.abs 0
+push argument 0
+push constant 0
+lt
+if-goto NEG
+push argument 0
+return
+label NEG
+push argument 0
+neg
+return
+";
+
+    #[test]
+    fn test_coverage() {
+        let files = [("Main.vm".to_string(), MAIN_VM.to_string())];
+        let (code, map) = SourceMap::translate(&files, false).unwrap();
+        let mut em = Emul::new();
+        em.load_code(&code).unwrap();
+        em.set_ram(&[(0, 261), (1, 261), (2, 256)]);
+        em.set_halt(map.addr_of(0, 6).unwrap().0);
+        let cov = Rc::new(RefCell::new(Coverage::new(em.rom())));
+        em.add_observer(Box::new(cov.clone()));
+        em.resume(10_000);
+        let cov = cov.borrow();
+
+        let abs = &cov.functions(&map)[1];
+        assert_eq!((abs.name.as_str(), abs.calls), ("Main.abs", 2));
+        // The negative case, lines 15-17, never ran, so neither lt's jump
+        // nor the if-goto was ever taken
+        assert_eq!((abs.lines.hit, abs.lines.total), (7, 10));
+        assert_eq!((abs.branches.hit, abs.branches.total), (2, 4));
+        assert!(cov.summary(&map).contains("Main.abs                              2      7/10  70%   103/168  61%       2/4  50%"));
+
+        let lcov = cov.lcov(&map);
+        assert!(lcov.starts_with("TN:\nSF:Main.vm\nFN:1,Main.main\nFNDA:1,Main.main\nFN:7,Main.abs\nFNDA:2,Main.abs\nFNF:2\nFNH:2\n"));
+        assert!(lcov.contains("BRDA:11,0,0,0\nBRDA:11,0,1,2\nBRF:4\nBRH:2\nDA:1,1\n"));
+        assert!(lcov.contains("DA:13,2\nDA:15,0\n"));
+        assert!(lcov.ends_with("DA:17,0\nLF:16\nLH:12\nend_of_record\n"));
+
+        let jack = "/** Absolute values */\nclass Main {\n    function void main() {\n        do Main.abs(3);\n    }\n\n    \
+                    // not in MAIN_VM\n    function int twice(int x) { return x + x; }\n\n    function int abs(int x) {\n    }\n}\n";
+        assert_eq!(cov.jack_lcov(&map, "Main.jack", jack),
+                   "TN:\nSF:Main.jack\nFN:3,Main.main\nFNDA:1,Main.main\nFN:10,Main.abs\nFNDA:2,Main.abs\nFNF:2\nFNH:2\n\
+                    DA:3,1\nDA:10,2\nLF:2\nLH:2\nend_of_record\n");
+    }
+}
@@ -0,0 +1,152 @@
+use crate::emul::alu;
+use crate::tst::{Column,output_line};
+
+// The CPU chip's interface at one moment, as CPU.tst shows it
+#[derive(Debug,PartialEq,Copy,Clone)]
+pub struct Pins {
+    pub in_m: i16,
+    pub instruction: u16,
+    pub reset: bool,
+    pub out_m: i16,
+    pub write_m: bool,
+    pub address_m: i16,
+    pub pc: usize,
+    pub d: i16,
+}
+
+// The Hack CPU on its own, with instruction and inM supplied from outside,
+// as in CPU.hdl.  Used as a golden model for hardware implementations.
+//
+// On the clock tick the D register takes its new value; A and pc change on
+// the tock.  So the "t+" row shows the new D with the old A and pc, and the
+// "t+1" row shows everything updated, with outM recomputed from the new
+// registers.
+#[derive(Debug,PartialEq,Copy,Clone,Default)]
+pub struct Cpu {
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+}
+
+impl Cpu {
+    fn eval(&self, instruction: u16, in_m: i16) -> i16 {
+        let y = if instruction & 0x1000 != 0 { in_m } else { self.a };
+        alu(instruction >> 6 & 0x3f, self.d, y)
+    }
+
+    // Combinational outputs for the current registers
+    pub fn pins(&self, instruction: u16, in_m: i16, reset: bool) -> Pins {
+        let c = instruction & 0x8000 != 0;
+        Pins{in_m, instruction, reset, out_m: if c { self.eval(instruction, in_m) } else { 0 },
+             write_m: c && instruction & 0x0008 != 0, address_m: self.a & 0x7fff, pc: self.pc, d: self.d}
+    }
+
+    // One clock cycle; returns the pins after the tick and after the tock
+    pub fn cycle(&mut self, instruction: u16, in_m: i16, reset: bool) -> (Pins, Pins) {
+        let mut plus = self.pins(instruction, in_m, reset);
+        let old_a = self.a;
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+        } else {
+            let res = plus.out_m;
+            if instruction & 0x0010 != 0 {
+                self.d = res;
+            }
+            if instruction & 0x0020 != 0 {
+                self.a = res;
+            }
+            let jmp = (instruction & 4 != 0 && res < 0) || (instruction & 2 != 0 && res == 0)
+                || (instruction & 1 != 0 && res > 0);
+            if jmp {
+                self.pc = old_a as u16 as usize;
+            } else {
+                self.pc += 1;
+            }
+        }
+        if reset {
+            self.pc = 0;
+        }
+        plus.d = self.d;
+        (plus, self.pins(instruction, in_m, reset))
+    }
+}
+
+pub const PIN_COLUMNS: &str =
+    "time%S0.4.0 inM%D0.6.0 instruction%B0.16.0 reset%B2.1.2 outM%D1.6.0 writeM%B3.1.3 addressM%D0.5.0 pc%D0.5.0 DRegister[]%D1.6.1";
+
+fn columns() -> Vec<Column> {
+    PIN_COLUMNS.split_whitespace().map(|s| Column::parse(s).unwrap()).collect()
+}
+
+pub fn pin_header() -> String {
+    output_line(&columns().iter().map(|c| c.header()).collect::<Vec<_>>())
+}
+
+// One row of CPU.cmp; time is e.g. "3+" or "4"
+pub fn pin_row(time: &str, p: &Pins) -> String {
+    let cs = columns();
+    let out_m = if p.write_m { cs[4].num(p.out_m) } else { cs[4].stars() };
+    output_line(&[cs[0].cell(time), cs[1].num(p.in_m), cs[2].num(p.instruction as i16), cs[3].num(p.reset as i16),
+                  out_m, cs[5].num(p.write_m as i16), cs[6].num(p.address_m), cs[7].num(p.pc as i16), cs[8].num(p.d)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::Emul;
+
+    #[test]
+    fn test_cpu_rows() {
+        let prog: &[(u16, i16)] = &[
+            (0b0011000000111001, 0),  // @12345
+            (0b1110110000010000, 0),  // D=A
+            (0b0101101110100000, 0),  // @23456
+            (0b1110000111010000, 0),  // D=A-D
+            (0b0000001111101000, 0),  // @1000
+            (0b1110001100001000, 0),  // M=D
+            (0b1111110010011000, 11111),  // MD=M-1
+        ];
+        let mut cpu = Cpu::default();
+        let mut rows = vec![pin_header()];
+        for (t, (instr, in_m)) in prog.iter().enumerate() {
+            let (plus, after) = cpu.cycle(*instr, *in_m, false);
+            rows.push(pin_row(&format!("{}+", t), &plus));
+            rows.push(pin_row(&(t + 1).to_string(), &after));
+        }
+        let expected = "\
+|time| inM  |  instruction   |reset| outM  |writeM |addre| pc  |DRegiste|
+|0+  |     0|0011000000111001|  0  |*******|   0   |    0|    0|      0 |
+|1   |     0|0011000000111001|  0  |*******|   0   |12345|    1|      0 |
+|1+  |     0|1110110000010000|  0  |*******|   0   |12345|    1|  12345 |
+|2   |     0|1110110000010000|  0  |*******|   0   |12345|    2|  12345 |
+|2+  |     0|0101101110100000|  0  |*******|   0   |12345|    2|  12345 |
+|3   |     0|0101101110100000|  0  |*******|   0   |23456|    3|  12345 |
+|3+  |     0|1110000111010000|  0  |*******|   0   |23456|    3|  11111 |
+|4   |     0|1110000111010000|  0  |*******|   0   |23456|    4|  11111 |
+|4+  |     0|0000001111101000|  0  |*******|   0   |23456|    4|  11111 |
+|5   |     0|0000001111101000|  0  |*******|   0   | 1000|    5|  11111 |
+|5+  |     0|1110001100001000|  0  |  11111|   1   | 1000|    5|  11111 |
+|6   |     0|1110001100001000|  0  |  11111|   1   | 1000|    6|  11111 |
+|6+  | 11111|1111110010011000|  0  |  11110|   1   | 1000|    6|  11110 |
+|7   | 11111|1111110010011000|  0  |  11110|   1   | 1000|    7|  11110 |";
+        assert_eq!(rows.join("\n"), expected);
+
+        let (_, after) = cpu.cycle(0b1110101010000111, 0, true);  // 0;JMP, with reset
+        assert_eq!(after.pc, 0);
+    }
+
+    #[test]
+    fn test_emul_pins() {
+        let mut em = Emul::new();
+        em.set_ram(&[(100, 5)]);
+        em.load_code("@100\nD=M\n@LOOP\n(LOOP)\nD=D-1;JGT\n@101\nM=D\n").unwrap();
+        while em.pc() < em.rom().len() {
+            let (plus, after) = em.step_pins();
+            assert_eq!(after.pc, em.pc());
+            assert_eq!((after.address_m, after.d), (em.a, em.d));
+            assert_eq!(plus.write_m, plus.instruction == 0b1110001100001000);
+        }
+        assert_eq!(em.peek(101), 0);
+    }
+}
@@ -0,0 +1,589 @@
+use std::collections::{HashMap,VecDeque};
+use std::io::{BufRead,BufReader,Read,Write};
+use std::path::Path;
+use std::sync::mpsc::channel;
+
+use crate::asm::Asm;
+use crate::emul::{Emul,Stop};
+use crate::json::{Json,obj};
+use crate::sourcemap::{SourceMap,file_stem,load_program};
+
+// Instructions to run between checks for a pause request
+const CHUNK: u64 = 100_000;
+// Longest message accepted from the client
+const MAX_MESSAGE: usize = 1 << 20;
+
+const SEGMENTS: [&str; 8] = ["local", "argument", "this", "that", "static", "temp", "pointer", "stack"];
+const SEGMENTS_REF: i64 = 1;
+const REGISTERS_REF: i64 = 2;
+const SEGMENT_BASE_REF: i64 = 10;
+
+// A Debug Adapter Protocol server around Emul.  Programs are a .vm file, a
+// directory of .vm files (translated with the bootstrap) or a .asm file;
+// breakpoints and steps work on the lines of those sources.
+pub struct DebugAdapter {
+    pub em: Emul,
+    map: SourceMap,
+    statics: Vec<(String,i16)>,
+    seq: i64,
+    breakpoints: HashMap<usize,Vec<usize>>,
+    stop_on_entry: bool,
+    max_ticks: u64,
+    pub done: bool,
+}
+
+impl Default for DebugAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn ram(em: &Emul, addr: usize) -> i16 {
+    em.bus.read(addr).unwrap_or(0)
+}
+
+fn ptr(em: &Emul, addr: usize) -> usize {
+    ram(em, addr) as u16 as usize
+}
+
+impl DebugAdapter {
+    pub fn new() -> DebugAdapter {
+        DebugAdapter{em: Emul::new(), map: SourceMap::default(), statics: vec![], seq: 0,
+                     breakpoints: HashMap::new(), stop_on_entry: false, max_ticks: 10_000_000, done: false}
+    }
+
+    fn message(&mut self, kind: &str, mut kvs: Vec<(String,Json)>) -> Json {
+        self.seq += 1;
+        kvs.insert(0, ("seq".to_string(), self.seq.into()));
+        kvs.insert(1, ("type".to_string(), kind.into()));
+        Json::Obj(kvs)
+    }
+
+    fn response(&mut self, req: &Json, body: Json) -> Json {
+        let kvs = vec![
+            ("request_seq".to_string(), req.get("seq").clone()),
+            ("success".to_string(), true.into()),
+            ("command".to_string(), req.get("command").clone()),
+            ("body".to_string(), body),
+        ];
+        self.message("response", kvs)
+    }
+
+    fn error(&mut self, req: &Json, msg: &str) -> Json {
+        let kvs = vec![
+            ("request_seq".to_string(), req.get("seq").clone()),
+            ("success".to_string(), false.into()),
+            ("command".to_string(), req.get("command").clone()),
+            ("message".to_string(), msg.into()),
+        ];
+        self.message("response", kvs)
+    }
+
+    fn event(&mut self, name: &str, body: Json) -> Json {
+        self.message("event", vec![("event".to_string(), name.into()), ("body".to_string(), body)])
+    }
+
+    fn stopped(&mut self, reason: &str) -> Vec<Json> {
+        let body = obj(&[("reason", reason.into()), ("threadId", 1.into()), ("allThreadsStopped", true.into())]);
+        vec![self.event("stopped", body)]
+    }
+
+    // Stopped by the memory guard, with the violation as the text
+    fn trapped(&mut self) -> Vec<Json> {
+        let text = self.em.trap().map_or(String::new(), |t| t.to_string());
+        let body = obj(&[("reason", "exception".into()), ("description", "Memory guard".into()), ("text", text.into()),
+                         ("threadId", 1.into()), ("allThreadsStopped", true.into())]);
+        vec![self.event("stopped", body)]
+    }
+
+    // Stopped by a bad memory access or a jump out of the ROM
+    fn faulted(&mut self) -> Vec<Json> {
+        let text = self.em.fault().unwrap_or_default().to_string();
+        let body = obj(&[("reason", "exception".into()), ("description", "Fault".into()), ("text", text.into()),
+                         ("threadId", 1.into()), ("allThreadsStopped", true.into())]);
+        vec![self.event("stopped", body)]
+    }
+
+    fn finished(&mut self) -> Vec<Json> {
+        vec![self.event("exited", obj(&[("exitCode", 0.into())])), self.event("terminated", obj(&[]))]
+    }
+
+    fn at_end(&self) -> bool {
+        self.em.pc() >= self.em.rom().len() || self.em.is_halted()
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+        let program = args.get("program").as_str().ok_or("No program given")?;
+        let (code, map, bootstrap) = load_program(program)?;
+        let mut asm = Asm::new();
+        let cmds = asm.parse_code_str(&code).map_err(|e| e.to_string())?;
+        self.em = Emul::new();
+        self.em.set_labels(asm.labels());
+        self.em.set_functions(&map.functions());
+        self.em.load(&cmds)?;
+        self.statics = asm.variables().to_vec();
+        self.map = map;
+        self.breakpoints.clear();
+        if !bootstrap {
+            self.em.poke(0, 256);
+        }
+        for pair in args.get("ram").as_array() {
+            let addr = pair.as_array().first().and_then(|a| a.as_i64()).filter(|a| *a >= 0).map(|a| a as usize);
+            let v = pair.as_array().get(1).and_then(|v| v.as_i64()).filter(|v| (-32768..=32767).contains(v)).map(|v| v as i16);
+            match (addr, v) {
+                (Some(addr), Some(v)) if self.em.bus.is_memory(addr) => self.em.poke(addr, v),
+                _ => return Err("ram entries must be [address, value] with a RAM or screen address and a 16 bit value".to_string()),
+            }
+        }
+        if args.get("guard").as_bool() == Some(true) {
+            self.em.enable_guard();
+        }
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        let file = args.get("source").get("path").as_str().and_then(|p| self.map.file_index(p));
+        if let Some(f) = file {
+            for addr in self.breakpoints.remove(&f).unwrap_or_default() {
+                self.em.remove_breakpoint(addr);
+            }
+        }
+        let mut r = vec![];
+        let mut addrs = vec![];
+        for bp in args.get("breakpoints").as_array() {
+            let line = bp.get("line").as_i64().unwrap_or(0) as usize;
+            match file.and_then(|f| self.map.addr_of(f, line)) {
+                Some((addr, actual)) => {
+                    self.em.add_breakpoint(addr);
+                    addrs.push(addr);
+                    r.push(obj(&[("verified", true.into()), ("line", (actual as i64).into())]));
+                },
+                None => r.push(obj(&[("verified", false.into()), ("line", (line as i64).into())])),
+            }
+        }
+        if let Some(f) = file {
+            self.breakpoints.insert(f, addrs);
+        }
+        obj(&[("breakpoints", r.into())])
+    }
+
+    fn cont(&mut self, interrupted: &mut dyn FnMut() -> bool) -> Vec<Json> {
+        loop {
+            match self.em.resume(CHUNK) {
+                Stop::Breakpoint => return self.stopped("breakpoint"),
+                Stop::Trapped => return self.trapped(),
+                Stop::Fault => return self.faulted(),
+                Stop::End | Stop::Halted => return self.finished(),
+                Stop::TickLimit => if interrupted() {
+                    return self.stopped("pause");
+                },
+            }
+        }
+    }
+
+    // Step instructions until the start of a source line where done holds.
+    // Breakpoints stop the step early, unless it only goes one line.
+    fn step_until(&mut self, done: &dyn Fn(&Emul) -> bool, use_breakpoints: bool) -> Vec<Json> {
+        for _ in 0..self.max_ticks {
+            if self.at_end() {
+                return self.finished();
+            }
+            self.em.step();
+            if self.em.fault().is_some() {
+                return self.faulted();
+            }
+            if self.em.trap().is_some() {
+                return self.trapped();
+            }
+            let pc = self.em.pc();
+            if self.map.is_start(pc) && done(&self.em) {
+                return self.stopped("step");
+            }
+            if use_breakpoints && self.em.is_breakpoint(pc) {
+                return self.stopped("breakpoint");
+            }
+        }
+        self.stopped("step")
+    }
+
+    fn step_in(&mut self) -> Vec<Json> {
+        self.step_until(&|_| true, false)
+    }
+
+    // Step over calls: stop at the next line in the same frame
+    fn next(&mut self) -> Vec<Json> {
+        if self.map.loc(self.em.pc()).is_some_and(|l| l.ret) {
+            return self.step_in();
+        }
+        let lcl = ram(&self.em, 1);
+        self.step_until(&move |em| ram(em, 1) == lcl, true)
+    }
+
+    // Run until back in the caller, whose LCL the frame saved at LCL-4
+    fn step_out(&mut self) -> Vec<Json> {
+        if self.map.func_at(self.em.pc()).is_none() {
+            return self.step_until(&|_| false, true);
+        }
+        let caller = ram(&self.em, ptr(&self.em, 1).wrapping_sub(4));
+        self.step_until(&move |em| ram(em, 1) == caller, true)
+    }
+
+    fn frame(&self, id: i64, pc: usize) -> Json {
+        match self.map.loc(pc) {
+            Some(l) => {
+                let path = &self.map.files[l.file];
+                let name = self.map.func_at(pc).map_or_else(|| file_stem(path), |f| f.name.clone());
+                let file_name = Path::new(path).file_name().map_or(path.clone(), |n| n.to_string_lossy().into_owned());
+                obj(&[("id", id.into()), ("name", name.into()),
+                      ("source", obj(&[("name", file_name.into()), ("path", path.as_str().into())])),
+                      ("line", (l.line as i64).into()), ("column", 1.into()),
+                      ("instructionPointerReference", pc.to_string().into())])
+            },
+            None => obj(&[("id", id.into()), ("name", "bootstrap".into()), ("line", 0.into()), ("column", 0.into()),
+                          ("instructionPointerReference", pc.to_string().into())]),
+        }
+    }
+
+    // (name, address) of each word in a segment, for the current function
+    fn segment(&self, seg: usize) -> Vec<(String,usize)> {
+        let em = &self.em;
+        let pc = em.pc();
+        let func = if self.map.loc(pc).is_some() { self.map.func_at(pc) } else { None };
+        let (sp, lcl, arg) = (ptr(em, 0), ptr(em, 1), ptr(em, 2));
+        let words = |base: usize, n: usize| (0..n).map(|k| (format!("{}[{}]", SEGMENTS[seg], k), base + k)).collect();
+        match seg {
+            0 => words(lcl, func.map_or(0, |f| f.n_locals)),
+            1 => words(arg, if func.is_some() && lcl >= arg + 5 { lcl - 5 - arg } else { 0 }),
+            2 | 3 => {
+                let base = ptr(em, seg + 1);
+                words(base, if base == 0 { 0 } else { 8 })
+            },
+            4 => {
+                let prefix = match self.map.loc(pc) {
+                    Some(l) => format!("{}.", file_stem(&self.map.files[l.file])),
+                    None => return vec![],
+                };
+                let mut r = self.statics.iter()
+                    .filter_map(|(name, addr)| name.strip_prefix(&prefix)
+                                .and_then(|k| k.parse::<usize>().ok())
+                                .map(|k| (k, *addr as usize)))
+                    .collect::<Vec<_>>();
+                r.sort_unstable();
+                r.into_iter().map(|(k, addr)| (format!("static[{}]", k), addr)).collect()
+            },
+            5 => words(5, 8),
+            6 => words(3, 2),
+            _ => {
+                let base = func.map_or(256, |f| lcl + f.n_locals);
+                words(base, sp.saturating_sub(base))
+            },
+        }
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Json> {
+        let var = |name: &str, value: String, r: i64| {
+            obj(&[("name", name.into()), ("value", value.into()), ("variablesReference", r.into())])
+        };
+        match reference {
+            SEGMENTS_REF => (0..SEGMENTS.len())
+                .map(|i| var(SEGMENTS[i], format!("{} words", self.segment(i).len()), SEGMENT_BASE_REF + i as i64))
+                .collect(),
+            REGISTERS_REF => {
+                let mut r = vec![var("A", self.em.a.to_string(), 0), var("D", self.em.d.to_string(), 0),
+                                 var("PC", self.em.pc().to_string(), 0)];
+                for (i, name) in ["SP", "LCL", "ARG", "THIS", "THAT"].iter().enumerate() {
+                    r.push(var(name, ram(&self.em, i).to_string(), 0));
+                }
+                r
+            },
+            r if r >= SEGMENT_BASE_REF && r < SEGMENT_BASE_REF + SEGMENTS.len() as i64 => {
+                self.segment((r - SEGMENT_BASE_REF) as usize).iter()
+                    .map(|(name, addr)| var(name, ram(&self.em, *addr).to_string(), 0))
+                    .collect()
+            },
+            _ => vec![],
+        }
+    }
+
+    // Reply to one request.  interrupted is polled while running, and
+    // returns true if the client has asked to pause.
+    pub fn handle(&mut self, req: &Json, interrupted: &mut dyn FnMut() -> bool) -> Vec<Json> {
+        let args = req.get("arguments");
+        let cmd = req.get("command").as_str().unwrap_or("").to_string();
+        match cmd.as_str() {
+            "initialize" => {
+                let body = obj(&[("supportsConfigurationDoneRequest", true.into())]);
+                vec![self.response(req, body)]
+            },
+            "launch" => match self.launch(args) {
+                Ok(()) => {
+                    let r = self.response(req, obj(&[]));
+                    vec![r, self.event("initialized", obj(&[]))]
+                },
+                Err(e) => vec![self.error(req, &e)],
+            },
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(args);
+                vec![self.response(req, body)]
+            },
+            "configurationDone" => {
+                let mut r = vec![self.response(req, obj(&[]))];
+                if self.stop_on_entry {
+                    r.extend(self.stopped("entry"));
+                } else {
+                    r.extend(self.cont(interrupted));
+                }
+                r
+            },
+            "threads" => {
+                let body = obj(&[("threads", vec![obj(&[("id", 1.into()), ("name", "hack".into())])].into())]);
+                vec![self.response(req, body)]
+            },
+            "stackTrace" => {
+                // Callers are shown at their call, the instruction before the
+                // return address
+                let frames = self.em.call_stack().iter().enumerate()
+                    .map(|(i, f)| self.frame(i as i64, if i == 0 { f.pc } else { f.pc.saturating_sub(1) }))
+                    .collect::<Vec<_>>();
+                let n = frames.len() as i64;
+                let body = obj(&[("stackFrames", frames.into()), ("totalFrames", n.into())]);
+                vec![self.response(req, body)]
+            },
+            "scopes" => {
+                let scope = |name: &str, r: i64| obj(&[("name", name.into()), ("variablesReference", r.into()),
+                                                       ("expensive", false.into())]);
+                let body = obj(&[("scopes", vec![scope("Segments", SEGMENTS_REF), scope("Registers", REGISTERS_REF)].into())]);
+                vec![self.response(req, body)]
+            },
+            "variables" => {
+                let vars = self.variables(args.get("variablesReference").as_i64().unwrap_or(0));
+                vec![self.response(req, obj(&[("variables", vars.into())]))]
+            },
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let body = obj(&[("allThreadsContinued", true.into())]);
+                let mut r = vec![self.response(req, body)];
+                r.extend(match cmd.as_str() {
+                    "continue" => self.cont(interrupted),
+                    "next" => self.next(),
+                    "stepIn" => self.step_in(),
+                    _ => self.step_out(),
+                });
+                r
+            },
+            "pause" => vec![self.response(req, obj(&[]))],
+            "disconnect" => {
+                self.done = true;
+                vec![self.response(req, obj(&[]))]
+            },
+            _ => vec![self.error(req, &format!("Unsupported request: {}", cmd))],
+        }
+    }
+}
+
+// One Content-Length framed message
+pub fn read_message(r: &mut dyn BufRead) -> std::io::Result<Option<Json>> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let mut len = None;
+    let mut headers = false;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            // Blank lines before the headers are skipped
+            if headers {
+                break;
+            }
+            continue;
+        }
+        headers = true;
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+    let len = len.ok_or_else(|| invalid("Missing or invalid Content-Length"))?;
+    if len > MAX_MESSAGE {
+        return Err(invalid("Message too long"));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf);
+    Json::parse(&text).map(Some).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(w: &mut dyn Write, msg: &Json) -> std::io::Result<()> {
+    let text = msg.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+    w.flush()
+}
+
+// Serve requests from input until the client disconnects.  Requests are
+// read on another thread so that a pause can interrupt a continue.
+pub fn serve(mut da: DebugAdapter, input: impl Read + Send + 'static, out: &mut dyn Write) -> std::io::Result<DebugAdapter> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let mut rdr = BufReader::new(input);
+        while let Ok(Some(msg)) = read_message(&mut rdr) {
+            if tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    let mut pending = VecDeque::new();
+    while !da.done {
+        let req = match pending.pop_front() {
+            Some(req) => req,
+            None => match rx.recv() {
+                Ok(req) => req,
+                Err(_) => break,
+            },
+        };
+        let msgs = da.handle(&req, &mut || {
+            let mut pause = false;
+            while let Ok(m) = rx.try_recv() {
+                pause |= m.get("command").as_str() == Some("pause");
+                pending.push_back(m);
+            }
+            pause
+        });
+        for m in msgs {
+            write_message(out, &m)?;
+        }
+    }
+    Ok(da)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MAIN_VM: &str = "\
+function Main.main 1
+push constant 3
+pop local 0
+push local 0
+call Main.dbl 1
+pop static 0
+label END
+goto END
+function Main.dbl 0
+push argument 0
+push argument 0
+add
+return
+";
+
+    fn request(seq: i64, command: &str, args: Json) -> Json {
+        obj(&[("seq", seq.into()), ("type", "request".into()), ("command", command.into()), ("arguments", args)])
+    }
+
+    fn events(msgs: &[Json]) -> Vec<String> {
+        msgs.iter().filter_map(|m| m.get("event").as_str().map(|e| format!("{}:{}", e, m.get("body").get("reason").as_str().unwrap_or("")))).collect()
+    }
+
+    fn line(da: &mut DebugAdapter) -> i64 {
+        let r = da.handle(&request(0, "stackTrace", obj(&[])), &mut || false);
+        r[0].get("body").get("stackFrames").as_array()[0].get("line").as_i64().unwrap()
+    }
+
+    fn vars(da: &mut DebugAdapter, r: i64) -> Vec<(String,String)> {
+        let msgs = da.handle(&request(0, "variables", obj(&[("variablesReference", r.into())])), &mut || false);
+        msgs[0].get("body").get("variables").as_array().iter()
+            .map(|v| (v.get("name").as_str().unwrap().to_string(), v.get("value").as_str().unwrap().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_session() {
+        let dir = std::env::temp_dir().join(format!("vmtrans-dap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Main.vm");
+        std::fs::write(&path, MAIN_VM).unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut da = DebugAdapter::new();
+        let mut never = || false;
+        let r = da.handle(&request(1, "initialize", obj(&[])), &mut never);
+        assert_eq!(r[0].get("success"), &Json::Bool(true));
+        let r = da.handle(&request(2, "launch", obj(&[("program", path.as_str().into()), ("stopOnEntry", true.into()),
+                                                          ("ram", Json::parse("[[0, 261], [1, 261], [2, 256]]").unwrap())])), &mut never);
+        assert_eq!(events(&r), vec!["initialized:"]);
+        let bps = obj(&[("source", obj(&[("path", path.as_str().into())])),
+                        ("breakpoints", vec![obj(&[("line", 10.into())]), obj(&[("line", 99.into())])].into())]);
+        let r = da.handle(&request(3, "setBreakpoints", bps), &mut never);
+        let verified = r[0].get("body").get("breakpoints").as_array().iter()
+            .map(|b| (b.get("verified").as_bool().unwrap(), b.get("line").as_i64().unwrap())).collect::<Vec<_>>();
+        assert_eq!(verified, vec![(true, 10), (false, 99)]);
+        let r = da.handle(&request(4, "configurationDone", obj(&[])), &mut never);
+        assert_eq!(events(&r), vec!["stopped:entry"]);
+        assert_eq!(line(&mut da), 1);
+
+        let r = da.handle(&request(5, "next", obj(&[])), &mut never);
+        assert_eq!(events(&r), vec!["stopped:step"]);
+        assert_eq!(line(&mut da), 2);
+        da.handle(&request(6, "next", obj(&[])), &mut never);
+        da.handle(&request(7, "next", obj(&[])), &mut never);
+        assert_eq!(line(&mut da), 4);
+        assert_eq!(vars(&mut da, SEGMENT_BASE_REF), vec![("local[0]".to_string(), "3".to_string())]);
+        da.handle(&request(7, "next", obj(&[])), &mut never);
+        assert_eq!(line(&mut da), 5);
+
+        // Stepping over the call stops at the breakpoint inside it
+        let r = da.handle(&request(8, "next", obj(&[])), &mut never);
+        assert_eq!(events(&r), vec!["stopped:breakpoint"]);
+        assert_eq!(line(&mut da), 10);
+        let r = da.handle(&request(0, "stackTrace", obj(&[])), &mut never);
+        let frames = r[0].get("body").get("stackFrames").as_array().iter()
+            .map(|f| (f.get("name").as_str().unwrap().to_string(), f.get("line").as_i64().unwrap())).collect::<Vec<_>>();
+        assert_eq!(frames[..2], [("Main.dbl".to_string(), 10), ("Main.main".to_string(), 5)]);
+        assert_eq!(vars(&mut da, SEGMENT_BASE_REF + 1), vec![("argument[0]".to_string(), "3".to_string())]);
+        let r = da.handle(&request(9, "stepOut", obj(&[])), &mut never);
+        assert_eq!(events(&r), vec!["stopped:step"]);
+        assert_eq!(line(&mut da), 6);
+        da.handle(&request(10, "stepIn", obj(&[])), &mut never);
+        assert_eq!(vars(&mut da, SEGMENT_BASE_REF + 4), vec![("static[0]".to_string(), "6".to_string())]);
+        let regs = vars(&mut da, REGISTERS_REF);
+        assert_eq!(regs[3], ("SP".to_string(), "262".to_string()));
+
+        let r = da.handle(&request(11, "continue", obj(&[])), &mut never);
+        assert_eq!(events(&r), vec!["exited:", "terminated:"]);
+        let r = da.handle(&request(12, "evaluate", obj(&[])), &mut never);
+        assert_eq!(r[0].get("success"), &Json::Bool(false));
+
+        for ram in &["[[-1, 0]]", "[[24576, 0]]", "[[100, 40000]]", "[[100]]"] {
+            let args = obj(&[("program", path.as_str().into()), ("ram", Json::parse(ram).unwrap())]);
+            let r = da.handle(&request(13, "launch", args), &mut never);
+            assert_eq!(r[0].get("success"), &Json::Bool(false), "{}", ram);
+        }
+
+        let bad = dir.join("Bad.asm");
+        std::fs::write(&bad, "@-1\nD=A\n").unwrap();
+        let r = da.handle(&request(13, "launch", obj(&[("program", bad.to_string_lossy().as_ref().into())])), &mut never);
+        assert_eq!(r[0].get("success"), &Json::Bool(false));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut out = vec![];
+        let msg = obj(&[("seq", 1.into()), ("command", "threads".into())]);
+        write_message(&mut out, &msg).unwrap();
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.starts_with("Content-Length: 29\r\n\r\n{"));
+
+        let input = [out.clone(), out].concat();
+        let mut rdr = Cursor::new(input);
+        assert_eq!(read_message(&mut rdr).unwrap(), Some(msg.clone()));
+        assert_eq!(read_message(&mut rdr).unwrap(), Some(msg));
+        assert_eq!(read_message(&mut rdr).unwrap(), None);
+
+        let mut rdr = Cursor::new(b"Content-Type: x\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut rdr).is_err());
+        let mut rdr = Cursor::new(b"Content-Length: 99999999999\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut rdr).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+
+use crate::asm::disasm;
+use crate::emul::{Emul,Stop};
+
+const HELP: &str = "\
+s [N]          step N instructions (default 1)
+c              continue to a breakpoint, a halt or the end
+b LOC          set a breakpoint at a ROM address or label
+d LOC          delete a breakpoint
+bl             list breakpoints
+back [N]       step back N instructions (default 1)
+rw ADDR        rewind to just before the last write to RAM[ADDR]
+r              show registers
+bt             show the VM call stack
+p ADDR [N]     print N words of RAM starting at ADDR
+set ADDR VAL   write VAL to RAM[ADDR]
+q              quit
+";
+
+// A line-oriented debugger around Emul.  Each command returns the text to
+// show the user.
+pub struct Debugger {
+    pub em: Emul,
+    max_ticks: u64,
+}
+
+impl Debugger {
+    pub fn new(mut em: Emul, history: usize, max_ticks: u64) -> Debugger {
+        em.enable_history(history);
+        Debugger{em, max_ticks}
+    }
+
+    // ROM address of a number or label
+    fn parse_loc(&self, s: &str) -> Option<usize> {
+        s.parse().ok().or_else(|| self.em.label_addr(s))
+    }
+
+    fn parse_count(ws: &[&str], i: usize) -> Option<usize> {
+        match ws.get(i) {
+            Some(s) => s.parse().ok(),
+            None => Some(1),
+        }
+    }
+
+    pub fn location(&self) -> String {
+        let pc = self.em.pc();
+        let mut r = format!("pc={}", pc);
+        // The label's own address, as another label may share its name
+        if let Some((addr, label)) = self.em.symbols().label_start(pc) {
+            if let Some(off) = pc.checked_sub(addr) {
+                write!(&mut r, " ({}+{})", label, off).unwrap();
+            }
+        }
+        match self.em.rom().get(pc) {
+            Some(w) => write!(&mut r, ": {}", disasm(*w)).unwrap(),
+            None => r.push_str(": (end)"),
+        }
+        r
+    }
+
+    fn registers(&self) -> String {
+        format!("A={} D={} PC={} ticks={}", self.em.a, self.em.d, self.em.pc(), self.em.ticks())
+    }
+
+    pub fn command(&mut self, line: &str) -> String {
+        let ws: Vec<&str> = line.split_whitespace().collect();
+        if ws.is_empty() {
+            return String::new();
+        }
+        let r = match (ws[0], ws.len()) {
+            ("h", _) | ("help", _) => Some(HELP.to_string()),
+            ("s", 1..=2) | ("step", 1..=2) => Self::parse_count(&ws, 1).map(|n| {
+                for _ in 0..n {
+                    if self.em.pc() >= self.em.rom().len() {
+                        break;
+                    }
+                    self.em.step();
+                    if let Some(f) = self.em.fault() {
+                        return format!("{}\n{}", f, self.location());
+                    }
+                }
+                self.location()
+            }),
+            ("c", 1) | ("continue", 1) => {
+                let stop = self.em.resume(self.max_ticks);
+                match self.em.trap() {
+                    Some(t) if stop == Stop::Trapped => Some(format!("{}\n{}", t, self.location())),
+                    _ if stop == Stop::Fault => Some(format!("{}\n{}", self.em.fault().unwrap(), self.location())),
+                    _ => Some(format!("{:?} at {}", stop, self.location())),
+                }
+            },
+            ("b", 2) => self.parse_loc(ws[1]).map(|pc| {
+                self.em.add_breakpoint(pc);
+                format!("Breakpoint at {}", pc)
+            }),
+            ("d", 2) => self.parse_loc(ws[1]).map(|pc| {
+                self.em.remove_breakpoint(pc);
+                format!("Deleted breakpoint at {}", pc)
+            }),
+            ("bl", 1) => Some(format!("{:?}", self.em.breakpoints())),
+            ("back", 1..=2) => Self::parse_count(&ws, 1).map(|n| {
+                let mut done = 0;
+                while done < n && self.em.step_back() {
+                    done += 1;
+                }
+                if done < n {
+                    format!("History exhausted after {} steps\n{}", done, self.location())
+                } else {
+                    self.location()
+                }
+            }),
+            ("rw", 2) => ws[1].parse().ok().map(|addr| {
+                if self.em.rewind_to_write(addr) {
+                    self.location()
+                } else {
+                    format!("No write to RAM[{}] in history", addr)
+                }
+            }),
+            ("r", 1) | ("regs", 1) => Some(self.registers()),
+            ("bt", 1) | ("where", 1) => Some(self.em.backtrace().trim_end().to_string()),
+            ("p", 2..=3) => match (ws[1].parse::<usize>(), Self::parse_count(&ws, 2)) {
+                (Ok(addr), Some(n)) => {
+                    let mut r = String::new();
+                    // Up to the first address off the bus
+                    for a in addr..addr.saturating_add(n) {
+                        match self.em.bus.read(a) {
+                            Ok(v) => writeln!(&mut r, "RAM[{}] = {}", a, v).unwrap(),
+                            Err(e) => {
+                                writeln!(&mut r, "{}", e).unwrap();
+                                break;
+                            },
+                        }
+                    }
+                    Some(r.trim_end().to_string())
+                },
+                _ => None,
+            },
+            ("set", 3) => match (ws[1].parse::<usize>(), ws[2].parse::<i16>()) {
+                (Ok(addr), Ok(val)) => Some(match self.em.edit(addr, val) {
+                    Ok(()) => format!("RAM[{}] = {}", addr, val),
+                    Err(e) => e.to_string(),
+                }),
+                _ => None,
+            },
+            _ => None,
+        };
+        r.unwrap_or_else(|| format!("Bad command: {} (h for help)", line.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debugger_session() {
+        let mut em = Emul::new();
+        em.load_code("@3\nD=A\n(LOOP)\n@100\nM=D\nD=D-1\n@LOOP\nD;JGT\n").unwrap();
+        let mut dbg = Debugger::new(em, 1000, 1000);
+        assert_eq!(dbg.command("s 2"), "pc=2 (LOOP+0): @100");
+        assert_eq!(dbg.command("b LOOP"), "Breakpoint at 2");
+        assert_eq!(dbg.command("c"), "Breakpoint at pc=2 (LOOP+0): @100");
+        assert_eq!(dbg.command("p 100"), "RAM[100] = 3");
+        assert_eq!(dbg.command("d 2"), "Deleted breakpoint at 2");
+        assert_eq!(dbg.command("c"), "End at pc=7 (LOOP+5): (end)");
+        assert_eq!(dbg.command("p 100"), "RAM[100] = 1");
+        assert_eq!(dbg.command("rw 100"), "pc=3 (LOOP+1): M=D");
+        assert_eq!(dbg.command("r"), "A=100 D=1 PC=3 ticks=13");
+        assert_eq!(dbg.command("p 100"), "RAM[100] = 2");
+        assert_eq!(dbg.command("back 20"), "History exhausted after 13 steps\npc=0: @3");
+        assert_eq!(dbg.command("rw 100"), "No write to RAM[100] in history");
+        assert_eq!(dbg.command("bt"), "#0 (top) at pc 0");
+        assert_eq!(dbg.command("bogus"), "Bad command: bogus (h for help)");
+
+        // An edit can't be stepped back over, and initializes the word
+        dbg.em.enable_uninit_check();
+        assert_eq!(dbg.command("s 4"), "pc=4 (LOOP+2): D=D-1");
+        assert_eq!(dbg.command("set 101 9"), "RAM[101] = 9");
+        assert_eq!(dbg.command("back"), "History exhausted after 0 steps\npc=4 (LOOP+2): D=D-1");
+        assert_eq!(dbg.command("p 100 2"), "RAM[100] = 3\nRAM[101] = 9");
+        dbg.em.load_code("@101\nD=M\n").unwrap();
+        dbg.em.set_pc(0);
+        dbg.command("s 2");
+        assert!(dbg.em.uninit_reads().is_empty());
+        assert!(dbg.command("set 24577 1").starts_with("Invalid memory access"));
+
+        assert_eq!(dbg.command(&format!("p 24576 {}", usize::MAX)).lines().count(), 2);
+        dbg.em.set_labels(&[("X".to_string(), 1), ("X".to_string(), 0)]);
+        assert_eq!(dbg.location(), "pc=2 (X+1): (end)");
+    }
+}
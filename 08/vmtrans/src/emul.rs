@@ -1,146 +1,974 @@
-use crate::asm::{Comp,Dest,Jump,Command,Asm,ParserError};
+use crate::asm::{Command,Asm};
+use crate::bus::{Bus,BusError,Device,DebugPort,KBD,RAM_SIZE,SCREEN};
+use crate::callstack::{Frame,unwind,format_backtrace,prologue_functions};
+use crate::cpu::{Cpu,Pins};
+use crate::trace::Tracer;
+use crate::profile::Profiler;
+use crate::loader::{LoadError,parse_hack};
+use crate::snapshot::Snapshot;
+use crate::shadow::{Shadow,UninitRead};
+use crate::guard::{Guard,Trap};
+use crate::observer::{Observer,Step};
+use std::cell::{Ref,RefCell};
+use std::collections::{HashSet,VecDeque};
+use std::io::Write;
+use std::rc::Rc;
 
 pub struct Emul {
     pub a: i16,
     pub d: i16,
     pc: usize,
-    pub ram: [i16; 32768],
+    pub bus: Bus,
+    rom: Vec<u16>,
+    ticks: u64,
+    syms: Symbols,
+    last_write: Option<(usize,i16)>,
+    history: Option<History>,
+    breakpoints: HashSet<usize>,
+    fault: Option<String>,
+    // Handles on the built-in observers, which are also in observers
+    tracer: Option<Rc<RefCell<Tracer>>>,
+    profiler: Option<Rc<RefCell<Profiler>>>,
+    shadow: Option<Rc<RefCell<Shadow>>>,
+    guard: Option<Rc<RefCell<Guard>>>,
+    observers: Vec<Box<dyn Observer>>,
+    // The stop an observer asked for during the last step
+    requested: Option<Stop>,
+    halt_addrs: Vec<usize>,
+    halts: Vec<bool>,
+}
+
+#[derive(Debug,PartialEq,Copy,Clone)]
+pub enum Stop {
+    End,
+    Halted,
+    Breakpoint,
+    Trapped,
+    // A bad memory access or a jump out of the ROM; see Emul::fault
+    Fault,
+    TickLimit,
+}
+
+// What an instruction changed, so that it can be undone: the old value of
+// the word it wrote, and whether the uninit check had seen it written
+struct Undo {
+    pc: u16,
+    a: i16,
+    d: i16,
+    write: Option<(u16,i16,bool)>,
+}
+
+struct History {
+    entries: VecDeque<Undo>,
+    limit: usize,
+}
+
+impl History {
+    fn push(&mut self, u: Undo) {
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(u);
+    }
+}
+
+// The Hack ALU.  The common cases are dispatched directly; any other
+// combination of the zx nx zy ny f no bits is computed bit by bit.
+#[inline]
+fn comp(c: u16, x: i16, y: i16) -> i16 {
+    match c {
+        0b101010 => 0,
+        0b111111 => 1,
+        0b111010 => -1,
+        0b001100 => x,
+        0b110000 => y,
+        0b001101 => !x,
+        0b110001 => !y,
+        0b001111 => x.wrapping_neg(),
+        0b110011 => y.wrapping_neg(),
+        0b011111 => x.wrapping_add(1),
+        0b110111 => y.wrapping_add(1),
+        0b001110 => x.wrapping_sub(1),
+        0b110010 => y.wrapping_sub(1),
+        0b000010 => x.wrapping_add(y),
+        0b010011 => x.wrapping_sub(y),
+        0b000111 => y.wrapping_sub(x),
+        0b000000 => x & y,
+        0b010101 => x | y,
+        _ => alu(c, x, y),
+    }
+}
+
+pub fn alu(c: u16, x: i16, y: i16) -> i16 {
+    let mut x = if c & 0b100000 != 0 { 0 } else { x };
+    if c & 0b010000 != 0 {
+        x = !x;
+    }
+    let mut y = if c & 0b001000 != 0 { 0 } else { y };
+    if c & 0b000100 != 0 {
+        y = !y;
+    }
+    let out = if c & 0b000010 != 0 { x.wrapping_add(y) } else { x & y };
+    if c & 0b000001 != 0 { !out } else { out }
+}
+
+// The last entry at or before pc
+fn enclosing(table: &[(usize,String)], pc: usize) -> Option<(usize,&str)> {
+    let i = table.partition_point(|(addr, _)| *addr <= pc);
+    if i == 0 {
+        None
+    } else {
+        Some((table[i-1].0, &table[i-1].1))
+    }
+}
+
+// The ROM addresses of the program's labels and of its VM functions,
+// each sorted by address
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct Symbols {
+    pub labels: Vec<(usize,String)>,
+    pub functions: Vec<(usize,String)>,
+}
+
+impl Symbols {
+    // The last label at or before pc, i.e. the code block pc is in
+    pub fn label_at(&self, pc: usize) -> Option<&str> {
+        self.label_start(pc).map(|(_, l)| l)
+    }
+
+    // The address and name of that label
+    pub fn label_start(&self, pc: usize) -> Option<(usize,&str)> {
+        enclosing(&self.labels, pc)
+    }
+
+    // The start address and name of the function pc is in
+    pub fn function_at(&self, pc: usize) -> Option<(usize,&str)> {
+        enclosing(&self.functions, pc)
+    }
+}
+
+// Attach a built-in observer, or replace its state if it is attached
+fn attach<T: Observer + 'static>(handle: &mut Option<Rc<RefCell<T>>>, observers: &mut Vec<Box<dyn Observer>>,
+                                 syms: &Symbols, mut obs: T) {
+    obs.set_symbols(syms);
+    match handle {
+        Some(h) => *h.borrow_mut() = obs,
+        None => {
+            let h = Rc::new(RefCell::new(obs));
+            observers.push(Box::new(h.clone()));
+            *handle = Some(h);
+        },
+    }
+}
+
+impl Default for Emul {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Emul {
     pub fn new() -> Emul {
-        Emul{a: 0, d: 0,pc: 0, ram: [0; 32768]}
+        Emul{a: 0, d: 0,pc: 0, bus: Bus::new(), rom: vec![], ticks: 0, syms: Symbols::default(), last_write: None, history: None,
+             breakpoints: HashSet::new(), fault: None, tracer: None, profiler: None, shadow: None, guard: None,
+             observers: vec![], requested: None, halt_addrs: vec![], halts: vec![]}
+    }
+
+    pub fn set_labels(&mut self, labels: &[(String,i16)]) {
+        self.syms.labels = labels.iter().map(|(name, addr)| (*addr as usize, name.clone())).collect();
+        self.syms.labels.sort();
+        self.symbols_changed();
+    }
+
+    // The (address, name) of each VM function, as recorded by the
+    // translator in SourceMap::functions
+    pub fn set_functions(&mut self, functions: &[(usize,String)]) {
+        self.syms.functions = functions.to_vec();
+        self.syms.functions.sort();
+        self.symbols_changed();
+    }
+
+    // For a ROM with only a symbol table: take the labels where the
+    // translator's function prologue starts to be the functions
+    pub fn find_functions(&mut self) {
+        let functions = prologue_functions(&self.syms.labels, &self.rom);
+        self.set_functions(&functions);
+    }
+
+    fn symbols_changed(&mut self) {
+        for o in self.observers.iter_mut() {
+            o.set_symbols(&self.syms);
+        }
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.syms
+    }
+
+    pub fn label_at(&self, pc: usize) -> Option<&str> {
+        self.syms.label_at(pc)
+    }
+
+    pub fn function_at(&self, pc: usize) -> Option<(usize,&str)> {
+        self.syms.function_at(pc)
+    }
+
+    pub fn label_addr(&self, name: &str) -> Option<usize> {
+        self.syms.labels.iter().find(|(_, l)| l == name).map(|(addr, _)| *addr)
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        attach(&mut self.tracer, &mut self.observers, &self.syms, tracer);
+    }
+
+    // Observers are called in the order they were added
+    pub fn add_observer(&mut self, mut obs: Box<dyn Observer>) {
+        obs.set_symbols(&self.syms);
+        self.observers.push(obs);
+    }
+
+    // Detach every observer, the tracer, profiler and checks included
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+        self.tracer = None;
+        self.profiler = None;
+        self.shadow = None;
+        self.guard = None;
+    }
+
+    pub fn enable_profiler(&mut self) {
+        attach(&mut self.profiler, &mut self.observers, &self.syms, Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<Ref<'_, Profiler>> {
+        self.profiler.as_ref().map(|p| p.borrow())
+    }
+
+    // Report reads of RAM words that were never written by the program or
+    // preset with set_ram/poke.  Device regions are not checked.
+    pub fn enable_uninit_check(&mut self) {
+        attach(&mut self.shadow, &mut self.observers, &self.syms, Shadow::new());
+        self.mark_devices();
+    }
+
+    // Device regions count as initialized
+    fn mark_devices(&self) {
+        if let Some(ref sh) = self.shadow {
+            let mut sh = sh.borrow_mut();
+            for addr in (0..RAM_SIZE).filter(|a| !self.bus.is_ram(*a)) {
+                sh.mark(addr);
+            }
+        }
     }
 
-    fn m(&self) -> i16 {
-        if self.a < 0 {
-            panic!("Invalid address in A register: {}", self.a);
+    pub fn uninit_reads(&self) -> Vec<UninitRead> {
+        match self.shadow {
+            Some(ref sh) => sh.borrow().reads.iter()
+                .map(|r| UninitRead{label: self.label_at(r.pc).map(|l| l.to_string()), ..r.clone()})
+                .collect(),
+            None => vec![],
         }
-        self.ram[self.a as usize]
+    }
+
+    // Stop with Stop::Trapped when the program breaks the VM memory
+    // layout: SP outside the stack, THIS/THAT writes outside the heap and
+    // screen, or statics past RAM[255]
+    pub fn enable_guard(&mut self) {
+        attach(&mut self.guard, &mut self.observers, &self.syms, Guard::new());
+    }
+
+    // The violation made by the last instruction executed, if any
+    pub fn trap(&self) -> Option<Trap> {
+        let trap = self.guard.as_ref().and_then(|g| g.borrow().trap.clone());
+        trap.map(|t| Trap{label: self.label_at(t.pc).map(|l| l.to_string()), ..t})
+    }
+
+    // Why the program stopped with Stop::Fault.  The pc is left at the
+    // instruction that faulted.
+    pub fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+
+    // The fault with the VM call stack, as a report for the user
+    pub fn fault_report(&self) -> Option<String> {
+        self.fault.as_ref().map(|f| format!("{}\nCall stack:\n{}", f, self.backtrace().trim_end()))
+    }
+
+    #[cold]
+    fn set_fault(&mut self, msg: String) -> bool {
+        self.fault = Some(msg);
+        false
+    }
+
+    // Read and write memory from the host side, e.g. to set up a test
+    pub fn peek(&self, addr: usize) -> i16 {
+        self.bus.read(addr).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn poke(&mut self, addr: usize, val: i16) {
+        self.bus.write(addr, val).unwrap_or_else(|e| panic!("{}", e));
+        if let Some(ref sh) = self.shadow {
+            sh.borrow_mut().mark(addr);
+        }
+    }
+
+    // A write made by the user while the program is stopped.  It counts as
+    // initializing the word, and the undo history before it is dropped, as
+    // stepping back over it would restore stale values.
+    pub fn edit(&mut self, addr: usize, val: i16) -> Result<(), BusError> {
+        self.bus.write(addr, val)?;
+        if let Some(ref sh) = self.shadow {
+            sh.borrow_mut().mark(addr);
+        }
+        self.clear_history();
+        Ok(())
     }
 
     pub fn set_ram(&mut self, pairs: &[(usize,i16)]) {
         for (k,v) in pairs {
-            self.ram[*k] = *v;
-        }
-    }
-
-    fn do_comp(&self, comp: &Comp) -> i16 {
-        match comp {
-            Comp::Zero => 0,
-            Comp::One => 1,
-            Comp::MinusOne => -1,
-            Comp::D => self.d,
-            Comp::A => self.a,
-            Comp::NotD => !self.d,
-            Comp::NotA => !self.a,
-            Comp::MinusD => -self.d,
-            Comp::MinusA => -self.a,
-            Comp::DPlusOne => self.d + 1,
-            Comp::APlusOne => self.a + 1,
-            Comp::DMinusOne => self.d - 1,
-            Comp::AMinusOne => self.a - 1,
-            Comp::DPlusA => self.d + self.a,
-            Comp::DMinusA => self.d - self.a,
-            Comp::AMinusD => self.a - self.d,
-            Comp::DAndA => self.d & self.a,
-            Comp::DOrA => self.d | self.a,
-            Comp::M => self.m(),
-            Comp::NotM => !self.m(),
-            Comp::MinusM => -self.m(),
-            Comp::MPlusOne => self.m() + 1,
-            Comp::MMinusOne => self.m() - 1,
-            Comp::DPlusM => self.d + self.m(),
-            Comp::DMinusM => self.d - self.m(),
-            Comp::MMinusD => self.m() - self.d,
-            Comp::DAndM => self.d & self.m(),
-            Comp::DOrM => self.d | self.m(),
-        }
-    }
-
-    pub fn do_dest(&mut self, dest: &Dest, res: i16) {
-        if *dest == Dest::M || *dest == Dest::MD || *dest == Dest::AM || *dest == Dest::AMD {
-            if self.a < 0 {
-                panic!("Invalid address in A register: {}", self.a);
+            self.poke(*k, *v);
+        }
+    }
+
+    pub fn set_key(&mut self, key: i16) {
+        self.bus.keyboard.key = key;
+    }
+
+    pub fn map_device(&mut self, base: usize, len: usize, dev: Box<dyn Device>) {
+        self.bus.map(base, len, dev);
+        self.mark_devices();
+    }
+
+    // Map a DebugPort at addr (chars) and addr+1 (numbers).  It must lie
+    // above KBD, where it cannot hide RAM, the screen or the keyboard, and
+    // within the 15 bit address range.
+    pub fn enable_debug_port(&mut self, addr: usize, out: Box<dyn Write>) -> Result<(), String> {
+        if addr <= KBD || addr + DebugPort::LEN > 1 << 15 {
+            return Err(format!("Debug port address {} is not between {} and {}", addr, KBD + 1, (1 << 15) - DebugPort::LEN));
+        }
+        self.map_device(addr, DebugPort::LEN, Box::new(DebugPort::new(out)));
+        Ok(())
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    // Instructions executed since the emulator was created
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    // Encode and load a program; an A-instruction outside 0..32767 has no
+    // encoding and is an error
+    pub fn load(&mut self, prog: &[Command]) -> Result<(), String> {
+        let rom = prog.iter().enumerate()
+            .map(|(i, c)| c.encode().ok_or_else(|| format!("Instruction {}: can't encode {}", i, c.as_str())))
+            .collect::<Result<Vec<_>,_>>()?;
+        self.load_rom(rom);
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, rom: Vec<u16>) {
+        self.rom = rom;
+        self.find_halts();
+        self.clear_history();
+    }
+
+    // Mark the ROM addresses where the program is finished: the @X of a
+    // "(X) @X 0;JMP" loop, plus any set with set_halt.  The 0;JMP alone is
+    // not a halt, as reaching it with some other A jumps away.
+    fn find_halts(&mut self) {
+        self.halts = vec![false; self.rom.len()];
+        for p in 0..self.rom.len().saturating_sub(1) {
+            let jmp = self.rom[p + 1];
+            if self.rom[p] as usize == p && jmp & 0xe03f == 0xe007 {
+                self.halts[p] = true;
             }
-            //println!("Setting M({}) to {}", self.a, res);
-            self.ram[self.a as usize] = res;
         }
-        if *dest == Dest::D || *dest == Dest::MD || *dest == Dest::AD || *dest == Dest::AMD {
-            //println!("Setting D to {}", res);
-            self.d = res;
+        for &addr in &self.halt_addrs {
+            if addr < self.halts.len() {
+                self.halts[addr] = true;
+            }
         }
-        if *dest == Dest::A || *dest == Dest::AM || *dest == Dest::AD || *dest == Dest::AMD {
-            //println!("Setting A to {}", res);
-            self.a = res;
+    }
+
+    // Treat reaching addr as a clean halt
+    pub fn set_halt(&mut self, addr: usize) {
+        self.halt_addrs.push(addr);
+        self.find_halts();
+    }
+
+    pub fn set_halt_label(&mut self, name: &str) -> bool {
+        match self.label_addr(name) {
+            Some(addr) => {
+                self.set_halt(addr);
+                true
+            },
+            None => false,
         }
     }
 
-    pub fn do_jump(&mut self, jump: &Jump, res: i16) {
-        let jmp: bool = match jump {
-            Jump::Null => false,
-            Jump::JGT => res > 0,
-            Jump::JEQ => res == 0,
-            Jump::JGE => res >= 0,
-            Jump::JLT => res < 0,
-            Jump::JNE => res != 0,
-            Jump::JLE => res <= 0,
-            Jump::JMP => true,
-        };
+    pub fn is_halted(&self) -> bool {
+        self.halts.get(self.pc) == Some(&true)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut mem = self.bus.ram.save();
+        mem.extend(self.bus.screen.save());
+        Snapshot{a: self.a, d: self.d, pc: self.pc, ticks: self.ticks, key: self.bus.keyboard.key,
+                 mem, devices: self.bus.save_devices()}
+    }
 
+    // Restore machine state; the ROM is left as it is.  Fails, changing
+    // nothing, if the devices don't match those mapped.
+    pub fn restore(&mut self, snap: &Snapshot) -> Result<(), String> {
+        self.bus.restore_devices(&snap.devices)?;
+        self.a = snap.a;
+        self.d = snap.d;
+        self.pc = snap.pc;
+        self.ticks = snap.ticks;
+        self.bus.keyboard.key = snap.key;
+        self.bus.ram.restore(&snap.mem[..SCREEN]);
+        self.bus.screen.restore(&snap.mem[SCREEN..]);
+        if let Some(ref sh) = self.shadow {
+            sh.borrow_mut().mark_all();
+        }
+        self.clear_history();
+        Ok(())
+    }
+
+    // Execute the instruction at pc, with no tracing or other bookkeeping.
+    // False, with nothing changed, if it faulted.
+    #[inline]
+    fn exec(&mut self) -> bool {
+        let instr = self.rom[self.pc];
+        if instr & 0x8000 == 0 {
+            self.a = instr as i16;
+            self.pc += 1;
+            return true;
+        }
+        let a = self.a;
+        let addr = a as u16 as usize;
+        let y = if instr & 0x1000 != 0 {
+            match self.bus.read(addr) {
+                Ok(v) => v,
+                Err(e) => return self.set_fault(format!("{} (A register: {})", e, a)),
+            }
+        } else {
+            a
+        };
+        let res = comp(instr >> 6 & 0x3f, self.d, y);
+        if instr & 0x0008 != 0 {
+            if let Err(e) = self.bus.write(addr, res) {
+                return self.set_fault(format!("{} (A register: {})", e, a));
+            }
+            self.last_write = Some((addr, res));
+        }
+        if instr & 0x0010 != 0 {
+            self.d = res;
+        }
+        if instr & 0x0020 != 0 {
+            self.a = res;
+        }
+        let jmp = (instr & 4 != 0 && res < 0) || (instr & 2 != 0 && res == 0) || (instr & 1 != 0 && res > 0);
         if jmp {
-            self.pc = self.a as usize;
+            self.pc = a as u16 as usize;
         } else {
             self.pc += 1;
         }
+        true
+    }
+
+    // Execute the instruction at pc, returning the CPU pins after the
+    // clock's tick and tock
+    pub fn step_pins(&mut self) -> (Pins, Pins) {
+        let instr = self.rom[self.pc];
+        let in_m = self.bus.read(self.a as u16 as usize).unwrap_or(0);
+        let mut cpu = Cpu{a: self.a, d: self.d, pc: self.pc};
+        let rows = cpu.cycle(instr, in_m, false);
+        self.step();
+        rows
     }
 
-    pub fn run(&mut self, prog: Vec<Command>, maxticks: i32) {
-        let mut n_ticks = 0i32;
-        while self.pc < prog.len() && n_ticks < maxticks {
-            //println!("Doing command: {:?}", prog[self.pc]);
-            match &prog[self.pc] {
-                Command::A(n) => {
-                    self.a = *n;
-                    self.pc += 1;
-                },
-                Command::C(ref dest, ref comp, ref jump) => {
-                    let res = self.do_comp(comp);
-                    self.do_dest(dest, res);
-                    self.do_jump(jump, res);
-                },
-                _ => {panic!("WTF?  Can't emulate command: {:?}", prog[self.pc]);}
+    // Execute the instruction at pc.  On a bad memory access nothing is
+    // changed and fault() says why.
+    pub fn step(&mut self) {
+        let pc = self.pc;
+        let instr = self.rom[pc];
+        let a = self.a;
+        self.last_write = None;
+        self.fault = None;
+        self.requested = None;
+        // None for a device write, which can't be taken back
+        let undo = self.history.as_ref().map(|_| {
+            let addr = a as u16 as usize;
+            if instr & 0x8008 != 0x8008 {
+                Some(Undo{pc: pc as u16, a, d: self.d, write: None})
+            } else if self.bus.is_memory(addr) {
+                let init = self.shadow.as_ref().is_none_or(|sh| sh.borrow().is_init(addr));
+                let old = self.bus.read(addr).unwrap();
+                Some(Undo{pc: pc as u16, a, d: self.d, write: Some((addr as u16, old, init))})
+            } else {
+                None
+            }
+        });
+        // The first stop an observer asks for wins, but every observer
+        // sees every event
+        let mut stop = None;
+        if !self.observers.is_empty() {
+            let addr = a as u16 as usize;
+            let read = if instr & 0x9000 == 0x9000 { self.bus.read(addr).ok() } else { None };
+            for o in self.observers.iter_mut() {
+                stop = stop.or(o.on_step(pc, instr));
+                if let Some(val) = read {
+                    stop = stop.or(o.on_mem_read(pc, addr, val));
+                }
+            }
+        }
+        if !self.exec() {
+            return;
+        }
+        match (self.history.as_mut(), undo) {
+            (Some(h), Some(Some(u))) => h.push(u),
+            // History stops at a device write
+            (Some(h), Some(None)) => h.entries.clear(),
+            _ => (),
+        }
+        if !self.observers.is_empty() {
+            let step = Step{tick: self.ticks, pc, instr, a_before: a, a: self.a, d: self.d, write: self.last_write, next_pc: self.pc};
+            for o in self.observers.iter_mut() {
+                if let Some((addr, val)) = step.write {
+                    stop = stop.or(o.on_mem_write(pc, addr, val));
+                }
+                stop = stop.or(o.on_exec(&step));
             }
-            n_ticks += 1;
         }
+        self.requested = stop;
+        self.ticks += 1;
+    }
+
+    // The VM call stack, innermost frame first, found by following the
+    // saved LCL chain
+    pub fn call_stack(&self) -> Vec<Frame> {
+        unwind(self)
+    }
 
-        //println!("Got to end at {} with prog {:?}", self.pc, prog);
-        if self.pc > prog.len() {
-            panic!("Attempt to access non-existent instruction {}", self.pc);
+    pub fn backtrace(&self) -> String {
+        format_backtrace(&self.call_stack())
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    pub fn is_breakpoint(&self, pc: usize) -> bool {
+        self.breakpoints.contains(&pc)
+    }
+
+    pub fn breakpoints(&self) -> Vec<usize> {
+        let mut r = self.breakpoints.iter().cloned().collect::<Vec<_>>();
+        r.sort_unstable();
+        r
+    }
+
+    // Run from the current pc until the end of the ROM, a breakpoint or
+    // maxticks instructions.
+    pub fn resume(&mut self, maxticks: u64) -> Stop {
+        let mut n_ticks = 0u64;
+        let len = self.rom.len();
+        let mut stop = Stop::TickLimit;
+        self.fault = None;
+        if self.observers.is_empty() && self.history.is_none() && self.breakpoints.is_empty() {
+            while self.pc < len && n_ticks < maxticks {
+                if self.halts[self.pc] {
+                    stop = Stop::Halted;
+                    break;
+                }
+                if !self.exec() {
+                    stop = Stop::Fault;
+                    break;
+                }
+                n_ticks += 1;
+            }
+            self.ticks += n_ticks;
+        } else {
+            while self.pc < len && n_ticks < maxticks {
+                if self.halts[self.pc] {
+                    stop = Stop::Halted;
+                    break;
+                }
+                self.step();
+                if self.fault.is_some() {
+                    stop = Stop::Fault;
+                    break;
+                }
+                n_ticks += 1;
+                if let Some(s) = self.requested {
+                    stop = s;
+                    break;
+                }
+                if self.breakpoints.contains(&self.pc) {
+                    stop = Stop::Breakpoint;
+                    break;
+                }
+            }
+        }
+        for o in self.observers.iter_mut() {
+            o.flush();
         }
 
-        if n_ticks >= maxticks {
-            panic!("Too many ticks!");
+        if self.pc > len {
+            self.set_fault(format!("Attempt to access non-existent instruction {}", self.pc));
+            stop = Stop::Fault;
         }
+        if self.pc == len && !matches!(stop, Stop::Trapped | Stop::Fault) {
+            stop = Stop::End;
+        }
+        stop
     }
 
-    pub fn run_code(&mut self, code: &str, maxticks: i32) -> Result<(), ParserError> {
+    // Run the loaded ROM from the current pc until it stops: TickLimit
+    // means a runaway program, and Fault a broken one (see fault_report).
+    pub fn run_rom(&mut self, maxticks: i32) -> Stop {
+        self.resume(maxticks as u64)
+    }
+
+    // Keep an undo log of the last `limit` instructions, so that they can
+    // be stepped back over.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History{entries: VecDeque::new(), limit});
+    }
+
+    // Undo the last instruction.  False if there is no history left; it
+    // does not reach back past a write to a device, a load or a restore.
+    pub fn step_back(&mut self) -> bool {
+        let ticks = match self.ticks.checked_sub(1) {
+            Some(t) => t,
+            None => return false,
+        };
+        let u = match self.history.as_mut().and_then(|h| h.entries.pop_back()) {
+            Some(u) => u,
+            None => return false,
+        };
+        if let Some((addr, old, init)) = u.write {
+            self.bus.write_memory(addr as usize, old);
+            if let Some(ref sh) = self.shadow {
+                sh.borrow_mut().set_init(addr as usize, init);
+            }
+        }
+        self.pc = u.pc as usize;
+        self.a = u.a;
+        self.d = u.d;
+        self.ticks = ticks;
+        true
+    }
+
+    fn clear_history(&mut self) {
+        if let Some(ref mut h) = self.history {
+            h.entries.clear();
+        }
+    }
+
+    // Step back to just before the most recent write to addr.  False, and
+    // nothing is undone, if the history has no such write.
+    pub fn rewind_to_write(&mut self, addr: usize) -> bool {
+        let n = match self.history {
+            Some(ref h) => h.entries.iter().rev().position(|u| u.write.map(|(a, _, _)| a as usize) == Some(addr)),
+            None => None,
+        };
+        match n {
+            Some(n) => {
+                for _ in 0..=n {
+                    self.step_back();
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn run(&mut self, prog: Vec<Command>, maxticks: i32) -> Result<Stop, String> {
+        self.load(&prog)?;
+        Ok(self.run_rom(maxticks))
+    }
+
+    pub fn load_code(&mut self, code: &str) -> Result<(), String> {
         let mut asm = Asm::new();
-        let cmds = asm.parse_code_str(code)?;
-        self.run(cmds, maxticks);
+        let cmds = asm.parse_code_str(code).map_err(|e| e.to_string())?;
+        self.set_labels(asm.labels());
+        self.set_functions(&[]);
+        self.load(&cmds)
+    }
+
+    pub fn run_code(&mut self, code: &str, maxticks: i32) -> Result<Stop, String> {
+        self.load_code(code)?;
+        Ok(self.run_rom(maxticks))
+    }
+
+    pub fn load_hack(&mut self, code: &str) -> Result<(), LoadError> {
+        self.load_rom(parse_hack(code)?);
         Ok(())
-    } 
+    }
+
+    pub fn run_hack(&mut self, code: &str, maxticks: i32) -> Result<Stop, LoadError> {
+        self.load_hack(code)?;
+        Ok(self.run_rom(maxticks))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::SharedBuf;
+    use crate::trace::TraceFormat;
 
     #[test]
     fn test_simple() {
         let mut em = Emul::new();
-        em.run_code("@33\nD=A\nA=1\nM=D\n", 50).unwrap();
-        assert_eq!(em.ram[1], 33);
+        assert_eq!(em.run_code("@33\nD=A\nA=1\nM=D\n", 50), Ok(Stop::End));
+        assert_eq!(em.peek(1), 33);
+    }
+
+    #[test]
+    fn test_screen_and_keyboard() {
+        let mut em = Emul::new();
+        em.set_key(75);
+        assert_eq!(em.run_code("@KBD\nD=M\n@SCREEN\nM=D\n", 50), Ok(Stop::End));
+        assert_eq!(em.peek(16384), 75);
+    }
+
+    #[test]
+    fn test_bad_address() {
+        let mut em = Emul::new();
+        assert_eq!(em.run_code("@24577\nM=1\n", 50), Ok(Stop::Fault));
+        assert_eq!(em.fault(), Some("Invalid memory access at address 24577 (A register: 24577)"));
+    }
+
+    #[test]
+    fn test_fault_backtrace() {
+        let mut em = Emul::new();
+        // Main.poke's frame as "call Main.poke 1" would leave it, with the
+        // return address 0 and a zeroed caller LCL
+        em.set_ram(&[(0, 263), (1, 262), (2, 256), (256, 24577)]);
+        em.load_code("(Main.poke)\n@SP\nA=M\nM=0\nA=A+1\n@ARG\nA=M\nA=M\nM=1\n").unwrap();
+        em.set_functions(&[(0, "Main.poke".to_string())]);
+        assert_eq!(em.run_rom(50), Stop::Fault);
+        assert!(em.fault_report().unwrap().ends_with(
+            "Call stack:\n#0 Main.poke at pc 7 args=[24577] locals=[0] returns to 0\n#1 (top) at pc 0"));
+    }
+
+    #[test]
+    fn test_load_unencodable() {
+        let mut em = Emul::new();
+        em.load_code("@5\n").unwrap();
+        assert_eq!(em.load_code("@-1\nD=A\n"), Err("Instruction 0: can't encode @-1".to_string()));
+        assert_eq!(em.rom(), &[5]);
+        assert!(em.load_code("@32767\nD=A\n").is_ok());
+        assert!(em.run(vec![Command::A(-32768)], 10).is_err());
+    }
+
+    #[test]
+    fn test_fault_stop() {
+        let code = "@5\nD=A\n@24577\nM=D\n";
+        let mut em = Emul::new();
+        em.load_code(code).unwrap();
+        assert_eq!(em.resume(50), Stop::Fault);
+        assert_eq!(em.fault(), Some("Invalid memory access at address 24577 (A register: 24577)"));
+        assert_eq!((em.pc(), em.ticks(), em.d), (3, 3, 5));
+        // Again through step, with the bookkeeping on
+        let mut em = Emul::new();
+        em.enable_history(10);
+        em.load_code(code).unwrap();
+        assert_eq!(em.resume(50), Stop::Fault);
+        assert_eq!((em.pc(), em.ticks()), (3, 3));
+        assert!(em.step_back());
+        assert_eq!(em.pc(), 2);
+
+        let mut em = Emul::new();
+        em.load_code("@100\n0;JMP\n").unwrap();
+        assert_eq!(em.resume(50), Stop::Fault);
+        assert_eq!(em.fault(), Some("Attempt to access non-existent instruction 100"));
+        assert!(em.fault_report().unwrap().contains("Call stack:\n"));
+    }
+
+    #[test]
+    fn test_trace() {
+        let code = "@2\nD=A\n(STORE)\n@7\nM=D\n";
+        let buf = SharedBuf::default();
+        let mut em = Emul::new();
+        em.set_tracer(Tracer::new(Box::new(buf.clone()), TraceFormat::JsonLines).with_label("STORE"));
+        assert_eq!(em.run_code(code, 50), Ok(Stop::End));
+        assert_eq!(buf.text(),
+            "{\"tick\":2,\"pc\":2,\"instr\":\"@7\",\"a\":7,\"d\":2,\"write\":null,\"label\":\"STORE\"}\n".to_owned() +
+            "{\"tick\":3,\"pc\":3,\"instr\":\"M=D\",\"a\":7,\"d\":2,\"write\":{\"addr\":7,\"value\":2},\"label\":\"STORE\"}\n");
+
+        let buf = SharedBuf::default();
+        let mut em = Emul::new();
+        em.set_tracer(Tracer::new(Box::new(buf.clone()), TraceFormat::Text).with_pc_range(3..4));
+        assert_eq!(em.run_code(code, 50), Ok(Stop::End));
+        assert_eq!(buf.text(),
+            "       3     3 M=D          A=7      D=2 RAM[7]=2\n");
+
+        // A function is traced past the labels inside it
+        let code = "@Main.main\n0;JMP\n(Main.main)\n@2\nD=A\n(Main.main$L)\n@7\nM=D\n";
+        let buf = SharedBuf::default();
+        let mut em = Emul::new();
+        em.set_tracer(Tracer::new(Box::new(buf.clone()), TraceFormat::Text).with_label("Main.main"));
+        em.load_code(code).unwrap();
+        em.set_functions(&[(2, "Main.main".to_string())]);
+        assert_eq!(em.run_rom(50), Stop::End);
+        let pcs = buf.text().lines()
+            .map(|l| l.split_whitespace().nth(1).unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(pcs, vec!["2", "3", "4", "5"]);
+    }
+    #[test]
+    fn test_alu() {
+        // every standard comp must agree with the generic ALU
+        for c in 0..64 {
+            for (x, y) in &[(0, 0), (5, 3), (-7, 12), (32767, 1), (-32768, -1)] {
+                let expected = alu(c, *x, *y);
+                assert_eq!(comp(c, *x, *y), expected, "comp {:06b} x={} y={}", c, x, y);
+            }
+        }
+        assert_eq!(alu(0b000010, 32767, 1), -32768);
+    }
+
+    #[test]
+    fn test_jump_uses_old_a() {
+        let mut em = Emul::new();
+        assert_eq!(em.run_code("@4\nA=-1;JMP\n@99\nD=A\n(X)\n@7\nD=A\n", 50), Ok(Stop::End));
+        assert_eq!(em.d, 7);
+    }
+    #[test]
+    fn test_run_hack() {
+        // @33 D=A @1 M=D
+        let mut em = Emul::new();
+        assert_eq!(em.run_hack("0000000000100001\n1110110000010000\n0000000000000001\n1110001100001000\n", 50), Ok(Stop::End));
+        assert_eq!(em.peek(1), 33);
+    }
+    #[test]
+    fn test_breakpoints() {
+        let mut em = Emul::new();
+        em.load_code("@3\nD=A\n(LOOP)\nD=D-1\n@LOOP\nD;JGT\n").unwrap();
+        em.add_breakpoint(em.label_addr("LOOP").unwrap());
+        assert_eq!(em.resume(100), Stop::Breakpoint);
+        assert_eq!((em.pc(), em.d), (2, 3));
+        assert_eq!(em.resume(100), Stop::Breakpoint);
+        assert_eq!((em.pc(), em.d), (2, 2));
+        em.remove_breakpoint(2);
+        assert_eq!(em.resume(2), Stop::TickLimit);
+        assert_eq!(em.resume(100), Stop::End);
+        assert_eq!(em.d, 0);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut em = Emul::new();
+        assert_eq!(em.run_code("@7\nD=A\n@0\nM=D\n(END)\n@END\n0;JMP\n", 100), Ok(Stop::Halted));
+        assert_eq!((em.pc(), em.ticks(), em.peek(0)), (4, 4, 7));
+        assert!(em.is_halted());
+        assert_eq!(em.resume(100), Stop::Halted);
+        assert_eq!(em.ticks(), 4);
+
+        // Landing on the 0;JMP with another A carries on
+        let mut em = Emul::new();
+        em.load_code("(END)\n@END\n0;JMP\n@1\nM=D\n").unwrap();
+        em.set_pc(1);
+        em.a = 2;
+        em.d = 9;
+        assert!(!em.is_halted());
+        assert_eq!(em.resume(100), Stop::End);
+        assert_eq!((em.ticks(), em.peek(1)), (3, 9));
+
+        // A loop that does work is only a halt if we say so
+        let code = "@Sys.halt\n0;JMP\n(Sys.halt)\n@1\nM=M+1\n@Sys.halt\n0;JMP\n";
+        let mut em = Emul::new();
+        em.load_code(code).unwrap();
+        assert_eq!(em.resume(100), Stop::TickLimit);
+        let mut em = Emul::new();
+        em.load_code(code).unwrap();
+        assert!(em.set_halt_label("Sys.halt"));
+        assert!(!em.set_halt_label("Nowhere"));
+        assert_eq!(em.run_rom(100), Stop::Halted);
+        assert_eq!((em.pc(), em.ticks()), (2, 2));
+    }
+
+    #[test]
+    fn test_runaway() {
+        let mut em = Emul::new();
+        assert_eq!(em.run_code("(LOOP)\n@0\nM=M+1\n@LOOP\n0;JMP\n", 100), Ok(Stop::TickLimit));
+        assert_eq!(em.ticks(), 100);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut em = Emul::new();
+        em.enable_history(100);
+        em.set_ram(&[(0, 256)]);
+        assert_eq!(em.run_code("@5\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@SP\nM=M+1\n", 100), Ok(Stop::End));
+        assert_eq!((em.peek(0), em.peek(256), em.ticks()), (258, 5, 9));
+        assert!(em.step_back());
+        assert_eq!((em.pc(), em.peek(0), em.ticks()), (8, 257, 8));
+        assert!(em.rewind_to_write(256));
+        assert_eq!((em.pc(), em.a, em.d, em.peek(256), em.peek(0)), (4, 256, 5, 0, 256));
+        assert_eq!(em.run_rom(100), Stop::End);
+        assert_eq!((em.peek(0), em.peek(256)), (258, 5));
+        assert!(!em.rewind_to_write(300));
+        assert_eq!(em.pc(), 9);
+        while em.step_back() {}
+        assert_eq!((em.pc(), em.a, em.d, em.ticks()), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_step_back_limits() {
+        let buf = SharedBuf::default();
+        let mut em = Emul::new();
+        em.enable_history(100);
+        em.enable_uninit_check();
+        em.enable_debug_port(30000, Box::new(buf.clone())).unwrap();
+        assert_eq!(em.run_code("@65\nD=A\n@30000\nM=D\n@100\nM=D\nD=M\n", 100), Ok(Stop::End));
+        assert!(em.step_back() && em.step_back());
+        assert_eq!((em.pc(), em.peek(100)), (5, 0));
+        // The write to RAM[100] is undone for the uninit check too
+        assert!(em.uninit_reads().is_empty());
+        em.set_pc(6);
+        em.step();
+        assert_eq!(em.uninit_reads().iter().map(|r| r.addr).collect::<Vec<_>>(), vec![100]);
+        em.set_pc(5);
+        // Nothing before the debug port write can be undone, and undoing
+        // never writes to the port again
+        while em.step_back() {}
+        assert_eq!(em.pc(), 4);
+        assert_eq!(buf.text(), "A");
+
+        assert_eq!(em.run_rom(100), Stop::End);
+        let snap = em.snapshot();
+        em.restore(&snap).unwrap();
+        assert!(!em.step_back());
+    }
+
+    #[test]
+    fn test_debug_port_range() {
+        let mut em = Emul::new();
+        for addr in &[0, 100, SCREEN, KBD, 32767] {
+            assert!(em.enable_debug_port(*addr, Box::new(std::io::sink())).is_err());
+        }
+        assert!(em.bus.is_ram(100) && em.bus.is_memory(SCREEN));
+        assert!(em.enable_debug_port(KBD + 1, Box::new(std::io::sink())).is_ok());
+        assert!(em.enable_debug_port(32766, Box::new(std::io::sink())).is_ok());
     }
     // need way more tests?
 }
@@ -0,0 +1,442 @@
+use std::io::{Read,Write};
+use std::net::{TcpListener,TcpStream};
+
+use crate::emul::{Emul,Stop};
+
+// GDB addresses bytes, so each 16-bit word takes two little-endian bytes.
+// ROM is at 0 and RAM follows it, as on Harvard targets like the AVR.
+pub const RAM_BASE: usize = 0x10000;
+
+// Instructions to run between checks for an interrupt from gdb
+const CHUNK: u64 = 100_000;
+// Largest packet we accept or send, as advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nand2tetris.hack">
+    <reg name="a" bitsize="16" type="int16" regnum="0"/>
+    <reg name="d" bitsize="16" type="int16" regnum="1"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="2"/>
+  </feature>
+</target>
+"#;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |s, b| s.wrapping_add(*b))
+}
+
+// $data#cs, escaping the characters the protocol reserves
+pub fn frame(data: &str) -> Vec<u8> {
+    let mut body = vec![];
+    for b in data.bytes() {
+        if b"#$}*".contains(&b) {
+            body.push(b'}');
+            body.push(b ^ 0x20);
+        } else {
+            body.push(b);
+        }
+    }
+    let mut r = vec![b'$'];
+    r.extend(&body);
+    r.extend(format!("#{:02x}", checksum(&body)).bytes());
+    r
+}
+
+fn hex_word(v: u16) -> String {
+    let b = v.to_le_bytes();
+    format!("{:02x}{:02x}", b[0], b[1])
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+// Hex pairs, taken as bytes so that a stray non-ASCII character is an
+// error rather than a split in the middle of a char
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    let b = s.as_bytes();
+    if !b.len().is_multiple_of(2) {
+        return None;
+    }
+    b.chunks(2).map(|c| Some((hex_digit(c[0])? << 4) | hex_digit(c[1])?)).collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+pub struct GdbStub {
+    pub em: Emul,
+}
+
+impl GdbStub {
+    pub fn new(em: Emul) -> GdbStub {
+        GdbStub{em}
+    }
+
+    fn reg(&self, n: usize) -> Option<u16> {
+        match n {
+            0 => Some(self.em.a as u16),
+            1 => Some(self.em.d as u16),
+            2 => Some((self.em.pc() * 2) as u16),
+            _ => None,
+        }
+    }
+
+    fn set_reg(&mut self, n: usize, v: u16) -> bool {
+        match n {
+            0 => self.em.a = v as i16,
+            1 => self.em.d = v as i16,
+            2 => self.em.set_pc(v as usize / 2),
+            _ => return false,
+        }
+        true
+    }
+
+    // None outside the loaded ROM and the bus
+    fn read_word(&self, word: usize) -> Option<u16> {
+        if word < RAM_BASE / 2 {
+            self.em.rom().get(word).cloned()
+        } else {
+            self.em.bus.read(word - RAM_BASE / 2).ok().map(|v| v as u16)
+        }
+    }
+
+    // At most a packet's worth, which gdb asks again for the rest of
+    fn read_mem(&self, addr: usize, len: usize) -> Option<String> {
+        addr.checked_add(len)?;
+        let end = addr + len.min(PACKET_SIZE / 2);
+        let mut r = String::new();
+        for b in addr..end {
+            let w = self.read_word(b / 2)?.to_le_bytes();
+            r += &format!("{:02x}", w[b % 2]);
+        }
+        Some(r)
+    }
+
+    // Only RAM is writable; breakpoints don't patch the ROM
+    fn write_mem(&mut self, addr: usize, bytes: &[u8]) -> bool {
+        for (i, byte) in bytes.iter().enumerate() {
+            let b = match addr.checked_add(i) {
+                Some(b) if b >= RAM_BASE => b,
+                _ => return false,
+            };
+            let word = b / 2 - RAM_BASE / 2;
+            let mut w = match self.em.bus.read(word) {
+                Ok(v) => (v as u16).to_le_bytes(),
+                Err(_) => return false,
+            };
+            w[b % 2] = *byte;
+            if self.em.bus.write(word, u16::from_le_bytes(w) as i16).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn xfer(&self, args: &str) -> String {
+        let f = args.split(':').collect::<Vec<_>>();
+        if f.len() != 2 || f[0] != "target.xml" {
+            return "E00".to_string();
+        }
+        let ol = f[1].split(',').map(parse_hex).collect::<Option<Vec<_>>>();
+        match ol.as_deref() {
+            Some([off, len]) => {
+                let off = (*off).min(TARGET_XML.len());
+                let end = off.saturating_add(*len).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                format!("{}{}", more, &TARGET_XML[off..end])
+            },
+            _ => "E00".to_string(),
+        }
+    }
+
+    // Continue until a breakpoint or the program finishes.  interrupted is
+    // polled every CHUNK instructions.
+    pub fn cont(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        loop {
+            match self.em.resume(CHUNK) {
+                Stop::Breakpoint => return "T05swbreak:;".to_string(),
+                // SIGSEGV
+                Stop::Trapped | Stop::Fault => return "T0b".to_string(),
+                Stop::End | Stop::Halted => return "W00".to_string(),
+                Stop::TickLimit => if interrupted() {
+                    return "T02".to_string();
+                },
+            }
+        }
+    }
+
+    // Reply to one packet; None means the session is over
+    pub fn handle(&mut self, pkt: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let ok = || "OK".to_string();
+        let err = || "E01".to_string();
+        let r = match pkt.chars().next() {
+            Some('?') => "S05".to_string(),
+            Some('g') => (0..3).map(|n| hex_word(self.reg(n).unwrap())).collect(),
+            Some('G') => match parse_bytes(&pkt[1..]) {
+                Some(bs) if bs.len() == 6 => {
+                    for n in 0..3 {
+                        self.set_reg(n, u16::from_le_bytes([bs[2*n], bs[2*n+1]]));
+                    }
+                    ok()
+                },
+                _ => err(),
+            },
+            Some('p') => match parse_hex(&pkt[1..]).and_then(|n| self.reg(n)) {
+                Some(v) => hex_word(v),
+                None => err(),
+            },
+            Some('P') => {
+                let f = pkt[1..].split('=').collect::<Vec<_>>();
+                match (f.len(), parse_hex(f[0]), f.get(1).and_then(|s| parse_bytes(s))) {
+                    (2, Some(n), Some(bs)) if bs.len() == 2 && self.set_reg(n, u16::from_le_bytes([bs[0], bs[1]])) => ok(),
+                    _ => err(),
+                }
+            },
+            Some('m') => {
+                let al = pkt[1..].split(',').map(parse_hex).collect::<Option<Vec<_>>>();
+                match al.as_deref() {
+                    Some([addr, len]) => self.read_mem(*addr, *len).unwrap_or_else(err),
+                    _ => err(),
+                }
+            },
+            Some('M') => {
+                let f = pkt[1..].split(':').collect::<Vec<_>>();
+                let al = f[0].split(',').map(parse_hex).collect::<Option<Vec<_>>>();
+                match (al.as_deref(), f.get(1).and_then(|s| parse_bytes(s))) {
+                    (Some([addr, len]), Some(bs)) if bs.len() == *len && self.write_mem(*addr, &bs) => ok(),
+                    _ => err(),
+                }
+            },
+            Some('Z') | Some('z') => {
+                let f = pkt[1..].split(',').collect::<Vec<_>>();
+                match (f.len(), f[0], f.get(1).and_then(|s| parse_hex(s))) {
+                    (3, "0", Some(addr)) | (3, "1", Some(addr)) => {
+                        if pkt.starts_with('Z') {
+                            self.em.add_breakpoint(addr / 2);
+                        } else {
+                            self.em.remove_breakpoint(addr / 2);
+                        }
+                        ok()
+                    },
+                    _ => String::new(),
+                }
+            },
+            Some('s') => {
+                if self.em.pc() < self.em.rom().len() {
+                    self.em.step();
+                }
+                if self.em.fault().is_some() { "S0b" } else { "S05" }.to_string()
+            },
+            Some('c') => self.cont(interrupted),
+            Some('H') => ok(),
+            Some('k') | Some('D') => return None,
+            _ => {
+                if pkt.starts_with("qSupported") {
+                    format!("PacketSize={:x};qXfer:features:read+;swbreak+", PACKET_SIZE)
+                } else if let Some(args) = pkt.strip_prefix("qXfer:features:read:") {
+                    self.xfer(args)
+                } else if pkt == "qAttached" {
+                    "1".to_string()
+                } else if pkt == "qfThreadInfo" {
+                    "m1".to_string()
+                } else if pkt == "qsThreadInfo" {
+                    "l".to_string()
+                } else if pkt == "qC" {
+                    "QC1".to_string()
+                } else {
+                    String::new()
+                }
+            },
+        };
+        Some(r)
+    }
+}
+
+// Packets from gdb, with acks sent as they arrive
+struct Conn {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Conn {
+    fn next_byte(&mut self) -> std::io::Result<Option<u8>> {
+        if self.buf.is_empty() {
+            let mut b = [0u8; 4096];
+            let n = self.stream.read(&mut b)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.buf.extend(&b[..n]);
+        }
+        Ok(Some(self.buf.remove(0)))
+    }
+
+    fn packet(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            match self.next_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                Some(_) => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match self.next_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut cs = vec![];
+            for _ in 0..2 {
+                match self.next_byte()? {
+                    None => return Ok(None),
+                    Some(b) => cs.push(b),
+                }
+            }
+            let sum = std::str::from_utf8(&cs).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            if sum == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    // True if gdb has sent ^C, without blocking
+    fn interrupted(&mut self) -> bool {
+        let mut b = [0u8; 64];
+        let _ = self.stream.set_nonblocking(true);
+        if let Ok(n) = self.stream.read(&mut b) {
+            self.buf.extend(&b[..n]);
+        }
+        let _ = self.stream.set_nonblocking(false);
+        match self.buf.iter().position(|b| *b == 3) {
+            Some(i) => {
+                self.buf.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// Serve one gdb connection, returning the emulator when gdb detaches
+pub fn serve(em: Emul, listener: TcpListener) -> std::io::Result<Emul> {
+    let (stream, _) = listener.accept()?;
+    let mut conn = Conn{stream, buf: vec![]};
+    let mut stub = GdbStub::new(em);
+    while let Some(pkt) = conn.packet()? {
+        match stub.handle(&pkt, &mut || conn.interrupted()) {
+            Some(r) => conn.stream.write_all(&frame(&r))?,
+            None => {
+                if pkt.starts_with('D') {
+                    conn.stream.write_all(&frame("OK"))?;
+                }
+                break;
+            },
+        }
+    }
+    Ok(stub.em)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    fn stub() -> GdbStub {
+        let mut em = Emul::new();
+        em.load_code("@3\nD=A\n(LOOP)\n@100\nM=D\nD=D-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP\n").unwrap();
+        GdbStub::new(em)
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut g = stub();
+        let mut never = || false;
+        let mut h = |g: &mut GdbStub, p: &str| g.handle(p, &mut never).unwrap();
+        assert_eq!(h(&mut g, "?"), "S05");
+        assert!(h(&mut g, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(h(&mut g, "qXfer:features:read:target.xml:0,20").starts_with("m<?xml"));
+        assert!(h(&mut g, "qXfer:features:read:target.xml:0,1000").ends_with("</target>\n"));
+        assert_eq!(h(&mut g, "s"), "S05");
+        assert_eq!(h(&mut g, "s"), "S05");
+        assert_eq!(h(&mut g, "g"), "030003000400");
+        assert_eq!(h(&mut g, "p2"), "0400");
+        assert_eq!(h(&mut g, "Z0,8,2"), "OK");
+        assert_eq!(h(&mut g, "c"), "T05swbreak:;");
+        assert_eq!(h(&mut g, "p2"), "0800");
+        assert_eq!(h(&mut g, "p1"), "0300");
+        assert_eq!(h(&mut g, "m100c8,2"), "0300");
+        assert_eq!(h(&mut g, "m0,4"), "030010ec");
+        assert_eq!(h(&mut g, "M100c8,2:2a00"), "OK");
+        assert_eq!(g.em.peek(100), 42);
+        assert_eq!(h(&mut g, "M0,2:0000"), "E01");
+        assert_eq!(h(&mut g, "P1=0100"), "OK");
+        assert_eq!(g.em.d, 1);
+        assert_eq!(h(&mut g, "z0,8,2"), "OK");
+        assert_eq!(h(&mut g, "c"), "W00");
+        assert_eq!(g.em.pc(), 7);
+        assert_eq!(h(&mut g, "vMustReplyEmpty"), "");
+        assert_eq!(g.handle("k", &mut never), None);
+    }
+
+    #[test]
+    fn test_bad_packets() {
+        let mut g = stub();
+        let mut never = || false;
+        let mut h = |g: &mut GdbStub, p: &str| g.handle(p, &mut never).unwrap();
+        assert_eq!(parse_bytes("2a00"), Some(vec![42, 0]));
+        assert_eq!(parse_bytes("0é0"), None);
+        assert_eq!(parse_bytes("+1"), None);
+        assert_eq!(h(&mut g, "M100c8,2:0é0"), "E01");
+        assert_eq!(h(&mut g, "P1=0é0"), "E01");
+        assert_eq!(h(&mut g, "G0é00000000"), "E01");
+        assert_eq!(h(&mut g, "é"), "");
+        assert!(h(&mut g, "qXfer:features:read:target.xml:10,ffffffffffffffff").starts_with('l'));
+        // Past the ROM, past the bus, and a length that would overflow
+        assert_eq!(h(&mut g, "m16,2"), "E01");
+        assert_eq!(h(&mut g, "m1c002,2"), "E01");
+        assert_eq!(h(&mut g, "m10000,ffffffffffffffff"), "E01");
+        assert_eq!(h(&mut g, "Mffffffffffffffff,2:0000"), "E01");
+        // Long reads are cut to a packet
+        assert_eq!(h(&mut g, "m10000,100000").len(), PACKET_SIZE);
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame("OK"), b"$OK#9a".to_vec());
+        assert_eq!(frame("a#b"), b"$a}\x03b#43".to_vec());
+    }
+
+    #[test]
+    fn test_serve() {
+        // Emul isn't Send, so the server stays on this thread
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let mut s = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut rdr = BufReader::new(s.try_clone().unwrap());
+            let mut reply = |pkt: &str| {
+                s.write_all(&frame(pkt)).unwrap();
+                let mut b = vec![0u8; 1];
+                rdr.read_exact(&mut b).unwrap();
+                assert_eq!(b, b"+");
+                let mut r = vec![];
+                while r.len() < 3 || r[r.len() - 3] != b'#' {
+                    rdr.read_exact(&mut b).unwrap();
+                    r.push(b[0]);
+                }
+                String::from_utf8(r).unwrap()
+            };
+            vec![reply("?"), reply("c"), reply("D")]
+        });
+        let em = serve(stub().em, listener).unwrap();
+        assert_eq!(client.join().unwrap(), vec!["$S05#b8", "$W00#b7", "$OK#9a"]);
+        assert_eq!(em.peek(100), 1);
+    }
+}
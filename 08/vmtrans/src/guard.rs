@@ -0,0 +1,209 @@
+use std::fmt;
+
+use crate::bus::{KBD,SCREEN};
+use crate::emul::Stop;
+use crate::observer::{Observer,Step};
+
+pub const STACK_START: usize = 256;
+pub const STACK_END: usize = 2047;
+pub const HEAP_START: usize = 2048;
+pub const STATIC_START: usize = 16;
+pub const STATIC_END: usize = 255;
+
+// What a value was derived from, ordered so that combining two values
+// keeps the more specific source
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Copy,Clone)]
+enum Tag {
+    None,
+    // An address loaded by an A-instruction, as for statics and temps
+    Direct,
+    // SP, LCL or ARG
+    Stack,
+    This,
+    That,
+}
+
+#[derive(Debug,PartialEq,Copy,Clone)]
+pub enum Violation {
+    Stack(i16),
+    Overflow(usize),
+    This(usize),
+    That(usize),
+    Static(usize),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Stack(sp) => write!(f, "Stack pointer out of range: SP={}", sp),
+            Violation::Overflow(addr) => write!(f, "Stack write above the stack: RAM[{}]", addr),
+            Violation::This(addr) => write!(f, "Write through THIS outside heap and screen: RAM[{}]", addr),
+            Violation::That(addr) => write!(f, "Write through THAT outside heap and screen: RAM[{}]", addr),
+            Violation::Static(addr) => write!(f, "Static out of range: RAM[{}]", addr),
+        }
+    }
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Trap {
+    pub violation: Violation,
+    pub pc: usize,
+    // Filled in by Emul::trap
+    pub label: Option<String>,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at pc {}", self.violation, self.pc)?;
+        if let Some(ref l) = self.label {
+            write!(f, " ({})", l)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks the VM memory layout as the program runs.  Whether an access goes
+// through THIS, THAT or a static is not visible in a single instruction,
+// so each of A, D and R13-R15 carries the source its value was derived from.
+pub struct Guard {
+    a: Tag,
+    d: Tag,
+    regs: [Tag; 3],
+    // The violation made by the last instruction
+    pub trap: Option<Trap>,
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Observer for Guard {
+    fn on_step(&mut self, _pc: usize, _instr: u16) -> Option<Stop> {
+        self.trap = None;
+        None
+    }
+
+    fn on_exec(&mut self, s: &Step) -> Option<Stop> {
+        let violation = self.exec(s.instr, s.a_before, s.write)?;
+        self.trap = Some(Trap{violation, pc: s.pc, label: None});
+        Some(Stop::Trapped)
+    }
+}
+
+impl Guard {
+    pub fn new() -> Guard {
+        Guard{a: Tag::None, d: Tag::None, regs: [Tag::None; 3], trap: None}
+    }
+
+    fn mem_tag(&self, addr: usize) -> Tag {
+        match addr {
+            0..=2 => Tag::Stack,
+            3 => Tag::This,
+            4 => Tag::That,
+            13..=15 => self.regs[addr - 13],
+            _ => Tag::None,
+        }
+    }
+
+    // Follow one instruction, given A before it ran and the write it made
+    pub fn exec(&mut self, instr: u16, a: i16, write: Option<(usize,i16)>) -> Option<Violation> {
+        if instr & 0x8000 == 0 {
+            self.a = Tag::Direct;
+            return None;
+        }
+        let addr = a as u16 as usize;
+        let uses_m = instr & 0x1000 != 0;
+        // The zx and zy bits say whether the ALU looks at D and at A/M
+        let x = if instr & 0x0800 == 0 { self.d } else { Tag::None };
+        let y = match (instr & 0x0200 == 0, uses_m) {
+            (false, _) => Tag::None,
+            (true, true) => self.mem_tag(addr),
+            (true, false) => self.a,
+        };
+        let res = x.max(y);
+        let mut violation = None;
+        if (uses_m || write.is_some()) && self.a == Tag::Direct && (STATIC_END + 1..SCREEN).contains(&addr) {
+            violation = Some(Violation::Static(addr));
+        }
+        if let Some((waddr, val)) = write {
+            let in_heap = (HEAP_START..KBD).contains(&waddr);
+            match self.a {
+                Tag::This if !in_heap => violation = Some(Violation::This(waddr)),
+                Tag::That if !in_heap => violation = Some(Violation::That(waddr)),
+                Tag::Stack if waddr >= HEAP_START => violation = Some(Violation::Overflow(waddr)),
+                _ => (),
+            }
+            // SP is one past the top, so a full stack has SP=HEAP_START
+            if waddr == 0 && !(STACK_START..=HEAP_START).contains(&(val as u16 as usize)) {
+                violation = Some(Violation::Stack(val));
+            }
+            if (13..=15).contains(&waddr) {
+                self.regs[waddr - 13] = res;
+            }
+        }
+        if instr & 0x0010 != 0 {
+            self.d = res;
+        }
+        if instr & 0x0020 != 0 {
+            self.a = res;
+        }
+        violation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::{Emul,Stop};
+    use crate::testutil::load_vm;
+    use crate::types::*;
+
+    fn run(cmds: &[VMCommand], ram: &[(usize,i16)]) -> (Stop, Option<Trap>) {
+        let mut em = Emul::new();
+        em.enable_guard();
+        em.set_ram(&[(0, 256), (1, 256), (2, 256), (3, 3000), (4, 3000)]);
+        load_vm(&mut em, cmds, ram);
+        let stop = em.resume(100_000);
+        (stop, em.trap())
+    }
+
+    #[test]
+    fn test_guard_pointers() {
+        let pop_that = [VMCommand::Push(VMSeg::CONSTANT, 7), VMCommand::Pop(VMSeg::THAT, 2)];
+        assert_eq!(run(&pop_that, &[]), (Stop::End, None));
+        assert_eq!(run(&pop_that, &[(4, 16384)]), (Stop::End, None));
+        let (stop, trap) = run(&pop_that, &[(4, 100)]);
+        assert_eq!(stop, Stop::Trapped);
+        assert_eq!(trap.unwrap().violation, Violation::That(102));
+
+        // Only THIS and THAT are confined to the heap
+        let pop_this = [VMCommand::Push(VMSeg::CONSTANT, 7), VMCommand::Pop(VMSeg::THIS, 0)];
+        assert_eq!(run(&pop_this, &[(3, 5)]).1.map(|t| t.violation), Some(Violation::This(5)));
+        let locals = [VMCommand::Push(VMSeg::CONSTANT, 7), VMCommand::Pop(VMSeg::LOCAL, 300)];
+        assert_eq!(run(&locals, &[]), (Stop::End, None));
+    }
+
+    #[test]
+    fn test_guard_stack_and_statics() {
+        // Filling the stack is fine; pushing onto a full one is not
+        let push = [VMCommand::Push(VMSeg::CONSTANT, 1)];
+        assert_eq!(run(&push, &[(0, 2047)]), (Stop::End, None));
+        let (stop, trap) = run(&push, &[(0, 2048)]);
+        assert_eq!(stop, Stop::Trapped);
+        assert_eq!(trap.unwrap().to_string(), "Stack write above the stack: RAM[2048] at pc 4");
+        let (_, trap) = run(&[VMCommand::Arithmetic(VMOp::ADD)], &[(0, 2050)]);
+        assert_eq!(trap.map(|t| t.violation), Some(Violation::Stack(2049)));
+        let locals = [VMCommand::Push(VMSeg::CONSTANT, 7), VMCommand::Pop(VMSeg::LOCAL, 1792)];
+        assert_eq!(run(&locals, &[]).1.map(|t| t.violation), Some(Violation::Overflow(2048)));
+        let (_, trap) = run(&[VMCommand::Arithmetic(VMOp::ADD)], &[]);
+        assert_eq!(trap.map(|t| t.violation), Some(Violation::Stack(255)));
+
+        let mut em = Emul::new();
+        em.enable_guard();
+        em.load_code("@Foo.0\nD=M\n@5\nM=D\n@300\nD=A\n@R15\nM=D\nA=M\nM=0\n").unwrap();
+        assert_eq!(em.resume(100), Stop::Trapped);
+        assert_eq!(em.trap().map(|t| (t.violation, t.pc)), Some((Violation::Static(300), 9)));
+    }
+}
@@ -0,0 +1,308 @@
+use std::fmt;
+
+// Just enough JSON for the debug adapter and RPC protocols.  Objects keep
+// their keys in order.
+#[derive(Debug,PartialEq,Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String,Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Obj(kvs) => kvs.iter().find(|(k, _)| k == key).map_or(&Json::Null, |(_, v)| v),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Num(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Arr(v) => v,
+            _ => &[],
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    pub fn parse(s: &str) -> Result<Json, String> {
+        let cs = s.chars().collect::<Vec<_>>();
+        let mut p = JsonParser{cs, i: 0};
+        let v = p.value()?;
+        p.ws();
+        if p.i != p.cs.len() {
+            return Err(format!("Trailing characters at {}", p.i));
+        }
+        Ok(v)
+    }
+}
+
+// Shorthand for building objects: obj(&[("a", 1.into()), ...])
+pub fn obj(kvs: &[(&str, Json)]) -> Json {
+    Json::Obj(kvs.iter().map(|(k, v)| (k.to_string(), v.clone())).collect())
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::Str(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(v: Vec<Json>) -> Json {
+        Json::Arr(v)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(s) => write_str(f, s),
+            Json::Arr(v) => {
+                write!(f, "[")?;
+                for (i, x) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, "]")
+            },
+            Json::Obj(kvs) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in kvs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+struct JsonParser {
+    cs: Vec<char>,
+    i: usize,
+}
+
+impl JsonParser {
+    fn ws(&mut self) {
+        while self.i < self.cs.len() && self.cs[self.i].is_whitespace() {
+            self.i += 1;
+        }
+    }
+
+    fn err<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{} at {}", what, self.i))
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for c in word.chars() {
+            if self.cs.get(self.i) != Some(&c) {
+                return self.err(&format!("Expected {}", word));
+            }
+            self.i += 1;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.ws();
+        match self.cs.get(self.i) {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::Str),
+            Some('[') => {
+                self.i += 1;
+                let mut v = vec![];
+                self.ws();
+                if self.cs.get(self.i) == Some(&']') {
+                    self.i += 1;
+                    return Ok(Json::Arr(v));
+                }
+                loop {
+                    v.push(self.value()?);
+                    self.ws();
+                    match self.cs.get(self.i) {
+                        Some(',') => self.i += 1,
+                        Some(']') => {
+                            self.i += 1;
+                            return Ok(Json::Arr(v));
+                        },
+                        _ => return self.err("Expected , or ]"),
+                    }
+                }
+            },
+            Some('{') => {
+                self.i += 1;
+                let mut kvs = vec![];
+                self.ws();
+                if self.cs.get(self.i) == Some(&'}') {
+                    self.i += 1;
+                    return Ok(Json::Obj(kvs));
+                }
+                loop {
+                    self.ws();
+                    if self.cs.get(self.i) != Some(&'"') {
+                        return self.err("Expected key");
+                    }
+                    let k = self.string()?;
+                    self.ws();
+                    self.expect(":")?;
+                    kvs.push((k, self.value()?));
+                    self.ws();
+                    match self.cs.get(self.i) {
+                        Some(',') => self.i += 1,
+                        Some('}') => {
+                            self.i += 1;
+                            return Ok(Json::Obj(kvs));
+                        },
+                        _ => return self.err("Expected , or }"),
+                    }
+                }
+            },
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let start = self.i;
+                self.i += 1;
+                while self.i < self.cs.len() && (self.cs[self.i].is_ascii_digit() || "+-.eE".contains(self.cs[self.i])) {
+                    self.i += 1;
+                }
+                let s = self.cs[start..self.i].iter().collect::<String>();
+                match s.parse() {
+                    Ok(n) => Ok(Json::Num(n)),
+                    Err(_) => self.err("Invalid number"),
+                }
+            },
+            _ => self.err("Unexpected character"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.i += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.cs.get(self.i) {
+                Some(c) => *c,
+                None => return self.err("Unterminated string"),
+            };
+            self.i += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = match self.cs.get(self.i) {
+                        Some(e) => *e,
+                        None => return self.err("Unterminated string"),
+                    };
+                    self.i += 1;
+                    match e {
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            let hex = self.cs.get(self.i..self.i + 4).map(|h| h.iter().collect::<String>());
+                            match hex.and_then(|h| u32::from_str_radix(&h, 16).ok()) {
+                                Some(n) => s.push(char::from_u32(n).unwrap_or('\u{fffd}')),
+                                None => return self.err("Invalid \\u escape"),
+                            }
+                            self.i += 4;
+                        },
+                        e => s.push(e),
+                    }
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let src = r#" {"a": [1, -2.5, true, null], "b": {"c": "x\"y\nA"}, "d": []} "#;
+        let v = Json::parse(src).unwrap();
+        assert_eq!(v.get("a").as_array()[0].as_i64(), Some(1));
+        assert_eq!(v.get("a").as_array()[1], Json::Num(-2.5));
+        assert_eq!(v.get("b").get("c").as_str(), Some("x\"y\nA"));
+        assert!(v.get("missing").is_null());
+        assert_eq!(v.to_string(), r#"{"a":[1,-2.5,true,null],"b":{"c":"x\"y\nA"},"d":[]}"#);
+        assert_eq!(Json::parse(&v.to_string()), Ok(v));
+        assert_eq!(obj(&[("n", 3.into()), ("s", "t".into())]).to_string(), r#"{"n":3,"s":"t"}"#);
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("1 2").is_err());
+    }
+}
@@ -2,4 +2,28 @@ pub mod types;
 pub mod translator;
 pub mod parser;
 pub mod asm;
+pub mod batch;
+pub mod bus;
+pub mod dap;
+pub mod callstack;
+pub mod coverage;
+pub mod cpu;
 pub mod emul;
+pub mod gdb;
+pub mod guard;
+pub mod json;
+pub mod loader;
+pub mod observer;
+pub mod ocr;
+pub mod snapshot;
+pub mod debugger;
+pub mod shadow;
+pub mod sourcemap;
+pub mod tst;
+pub mod trace;
+pub mod throttle;
+pub mod tui;
+pub mod profile;
+pub mod rpc;
+#[cfg(test)]
+mod testutil;
@@ -0,0 +1,98 @@
+use std::fmt;
+
+#[derive(PartialEq)]
+pub struct LoadError {
+    line_num: usize,
+    description: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LoadError: Line: {}, Error: {}", self.line_num, self.description)
+    }
+}
+
+impl fmt::Debug for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LoadError: Line: {}, Error: {}", self.line_num, self.description)
+    }
+}
+
+pub(crate) fn load_error(line_num: usize, desc: &str) -> LoadError {
+    LoadError{line_num, description: desc.to_string()}
+}
+
+// .hack text: one 16-digit binary word per line
+pub fn parse_hack(src: &str) -> Result<Vec<u16>, LoadError> {
+    let mut r = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let s = line.trim();
+        if s.is_empty() {
+            continue;
+        }
+        if s.len() != 16 {
+            return Err(load_error(i + 1, "Expected 16 binary digits"));
+        }
+        match u16::from_str_radix(s, 2) {
+            Ok(w) => r.push(w),
+            Err(_) => return Err(load_error(i + 1, "Invalid binary digit")),
+        }
+    }
+    Ok(r)
+}
+
+// Raw image: big-endian 16-bit words
+pub fn parse_image(bytes: &[u8]) -> Result<Vec<u16>, LoadError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(load_error(0, "Image has an odd number of bytes"));
+    }
+    Ok(bytes.chunks(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect())
+}
+
+// .sym: "NAME ADDRESS" per line, giving the ROM address of each label.
+// ROM addresses are 15 bits, so 0 to 32767.
+pub fn parse_sym(src: &str) -> Result<Vec<(String,i16)>, LoadError> {
+    let mut r = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let s = line.split("//").next().unwrap();
+        let ws: Vec<&str> = s.split_whitespace().collect();
+        if ws.is_empty() {
+            continue;
+        }
+        if ws.len() != 2 {
+            return Err(load_error(i + 1, "Expected NAME ADDRESS"));
+        }
+        match ws[1].parse::<u16>() {
+            Ok(addr) if addr < 1 << 15 => r.push((ws[0].to_string(), addr as i16)),
+            Ok(_) => return Err(load_error(i + 1, "Address out of range")),
+            Err(_) => return Err(load_error(i + 1, "Invalid address")),
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hack() {
+        assert_eq!(parse_hack("0000000000000010\n\n1110110000010000\n"), Ok(vec![2, 0xec10]));
+        assert_eq!(parse_hack("0000000000000010\n111011000001000\n"), Err(load_error(2, "Expected 16 binary digits")));
+        assert!(parse_hack("000000000000002\n").is_err());
+        assert_eq!(parse_image(&[0, 2, 0xec, 0x10]), Ok(vec![2, 0xec10]));
+        assert!(parse_image(&[0, 2, 0xec]).is_err());
+    }
+
+    #[test]
+    fn test_parse_sym() {
+        assert_eq!(parse_sym("LOOP 4\n// comment\n\nEND 10 // end\n"),
+            Ok(vec![("LOOP".to_string(), 4), ("END".to_string(), 10)]));
+        assert!(parse_sym("LOOP\n").is_err());
+        assert!(parse_sym("LOOP x\n").is_err());
+        assert_eq!(parse_sym("TOP 32767\n"), Ok(vec![("TOP".to_string(), 32767)]));
+        assert_eq!(parse_sym("LOOP 4\nFAR 32768\n"), Err(load_error(2, "Address out of range")));
+        assert_eq!(parse_sym("NEG -1\n"), Err(load_error(1, "Invalid address")));
+        assert!(parse_sym("BIG 65536\n").is_err());
+    }
+}
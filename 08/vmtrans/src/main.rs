@@ -9,13 +9,13 @@ use std::path::Path;
 use vmtrans::translator::Translator;
 use vmtrans::parser::Parser;
 
-fn process_file(base: &str, inpath: &Path, mut outfile: &File) -> Result<(), std::io::Error> {
+fn process_file(tr: &mut Translator, base: &str, inpath: &Path, mut outfile: &File) -> Result<(), std::io::Error> {
     let infile = File::open(inpath)?;
     let rdr = BufReader::new(&infile);
 
     let mut parser = Parser::new(base);
 
-    let mut tr = Translator::new(base);
+    tr.set_file(base);
     for some_line in rdr.lines() {
         let line = some_line.unwrap();
         match parser.parse_str(&line) {
@@ -44,20 +44,20 @@ fn main() -> Result<(), std::io::Error> {
 
     if inpath.is_file() {
         if inpath.extension().unwrap() == "vm" {
-            process_file(&base, &inpath, &mut outfile)?;
+            process_file(&mut Translator::new(&base), &base, &inpath, &outfile)?;
         } else {
             panic!("Input is a file and does not have a .vm extension");
         }
     } else if inpath.is_dir() {
-        write!(&mut outfile, "{}", Translator::gen_bootstrap()).unwrap();
+        // One translator for the whole program keeps generated labels unique
+        let mut tr = Translator::new("bootstrap");
+        write!(&mut outfile, "{}", tr.gen_bootstrap()).unwrap();
         for entry in read_dir(inpath)? {
             let entry = entry?;
             let ep = entry.path();
-            if ep.is_file() {
-                if ep.extension().unwrap() == "vm" {
-                    let base = ep.file_stem().unwrap().to_string_lossy().into_owned();
-                    process_file(&base, &ep, &mut outfile)?;
-                }
+            if ep.is_file() && ep.extension().unwrap() == "vm" {
+                let base = ep.file_stem().unwrap().to_string_lossy().into_owned();
+                process_file(&mut tr, &base, &ep, &outfile)?;
             }
         }
     } else {
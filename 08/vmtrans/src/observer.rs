@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::emul::{Stop,Symbols};
+
+// One executed instruction.  A and D are the values after it ran.
+pub struct Step {
+    pub tick: u64,
+    pub pc: usize,
+    pub instr: u16,
+    // A before the instruction, i.e. the address M referred to
+    pub a_before: i16,
+    pub a: i16,
+    pub d: i16,
+    pub write: Option<(usize,i16)>,
+    pub next_pc: usize,
+}
+
+// Callbacks from Emul::step for analysis tools.  Every hook has an empty
+// default, so an observer implements only what it needs.  A hook that
+// returns a Stop ends the run with it after the current instruction.
+// Observers run only on the slow path; with none attached, resume runs at
+// full speed.
+pub trait Observer {
+    // The program's labels and functions: when the observer is added and
+    // whenever they change
+    fn set_symbols(&mut self, _syms: &Symbols) {}
+
+    // Before the instruction at pc runs
+    fn on_step(&mut self, _pc: usize, _instr: u16) -> Option<Stop> {
+        None
+    }
+
+    // The instruction at pc read M
+    fn on_mem_read(&mut self, _pc: usize, _addr: usize, _val: i16) -> Option<Stop> {
+        None
+    }
+
+    // The instruction at pc wrote M
+    fn on_mem_write(&mut self, _pc: usize, _addr: usize, _val: i16) -> Option<Stop> {
+        None
+    }
+
+    // After the instruction ran
+    fn on_exec(&mut self, _step: &Step) -> Option<Stop> {
+        None
+    }
+
+    // At the end of each resume
+    fn flush(&mut self) {}
+}
+
+// Lets the caller keep a handle on an observer to read its results
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn set_symbols(&mut self, syms: &Symbols) {
+        self.borrow_mut().set_symbols(syms);
+    }
+
+    fn on_step(&mut self, pc: usize, instr: u16) -> Option<Stop> {
+        self.borrow_mut().on_step(pc, instr)
+    }
+
+    fn on_mem_read(&mut self, pc: usize, addr: usize, val: i16) -> Option<Stop> {
+        self.borrow_mut().on_mem_read(pc, addr, val)
+    }
+
+    fn on_mem_write(&mut self, pc: usize, addr: usize, val: i16) -> Option<Stop> {
+        self.borrow_mut().on_mem_write(pc, addr, val)
+    }
+
+    fn on_exec(&mut self, step: &Step) -> Option<Stop> {
+        self.borrow_mut().on_exec(step)
+    }
+
+    fn flush(&mut self) {
+        self.borrow_mut().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::disasm;
+    use crate::emul::Emul;
+
+    #[derive(Default)]
+    struct Log {
+        events: Vec<String>,
+    }
+
+    impl Observer for Log {
+        fn on_step(&mut self, pc: usize, instr: u16) -> Option<Stop> {
+            self.events.push(format!("{}: {}", pc, disasm(instr)));
+            None
+        }
+
+        fn on_mem_read(&mut self, _pc: usize, addr: usize, val: i16) -> Option<Stop> {
+            self.events.push(format!("read RAM[{}] = {}", addr, val));
+            None
+        }
+
+        fn on_mem_write(&mut self, _pc: usize, addr: usize, val: i16) -> Option<Stop> {
+            self.events.push(format!("write RAM[{}] = {}", addr, val));
+            None
+        }
+    }
+
+    // Counts writes only, relying on the default hooks for the rest, and
+    // stops the run at the given count
+    struct Writes(usize, usize);
+
+    impl Observer for Writes {
+        fn on_mem_write(&mut self, _pc: usize, _addr: usize, _val: i16) -> Option<Stop> {
+            self.0 += 1;
+            if self.0 == self.1 { Some(Stop::Breakpoint) } else { None }
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let log = Rc::new(RefCell::new(Log::default()));
+        let writes = Rc::new(RefCell::new(Writes(0, 0)));
+        let mut em = Emul::new();
+        em.add_observer(Box::new(log.clone()));
+        em.add_observer(Box::new(writes.clone()));
+        em.set_ram(&[(100, 4)]);
+        assert_eq!(em.run_code("@100\nM=M+1\nD=A\n", 10), Ok(Stop::End));
+        assert_eq!(log.borrow().events, vec![
+            "0: @100", "1: M=M+1", "read RAM[100] = 4", "write RAM[100] = 5", "2: D=A",
+        ]);
+        assert_eq!(writes.borrow().0, 1);
+
+        em.clear_observers();
+        em.set_pc(0);
+        em.resume(10);
+        assert_eq!(log.borrow().events.len(), 5);
+
+        let writes = Rc::new(RefCell::new(Writes(0, 2)));
+        em.add_observer(Box::new(writes.clone()));
+        em.load_code("@100\nM=1\nM=1\nM=1\n").unwrap();
+        em.set_pc(0);
+        assert_eq!(em.resume(10), Stop::Breakpoint);
+        assert_eq!((writes.borrow().0, em.pc()), (2, 3));
+    }
+}
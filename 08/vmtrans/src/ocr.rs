@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use crate::bus::SCREEN;
+use crate::emul::Emul;
+use crate::loader::{LoadError,load_error};
+
+// The Jack OS text grid: 23 rows of 64 characters, each 8x11 pixels
+pub const TEXT_ROWS: usize = 23;
+pub const TEXT_COLS: usize = 64;
+pub const GLYPH_HEIGHT: usize = 11;
+// Shown for a cell that matches no glyph
+pub const UNKNOWN: char = '\u{fffd}';
+
+type Glyph = [u8; GLYPH_HEIGHT];
+
+// The bitmap font of the Jack OS Output class.  Each glyph row is a byte
+// whose bit 0 is the leftmost pixel, as in Output.create.
+pub struct Font {
+    glyphs: HashMap<Glyph,char>,
+}
+
+// Character for an Output.create index; 0 is the black square Output
+// prints for characters it has no glyph for
+fn glyph_char(index: i32) -> Option<char> {
+    match index {
+        0 => Some('\u{25a0}'),
+        _ => char::from_u32(index as u32),
+    }
+}
+
+impl Font {
+    fn from_calls(calls: Vec<(usize, Vec<i32>)>) -> Result<Font, LoadError> {
+        let mut glyphs = HashMap::new();
+        for (line, args) in calls {
+            if args.len() != GLYPH_HEIGHT + 1 || args[1..].iter().any(|b| !(0..256).contains(b)) {
+                return Err(load_error(line, "Expected a character and 11 bytes"));
+            }
+            let c = glyph_char(args[0]).ok_or_else(|| load_error(line, "Invalid character"))?;
+            let mut g = [0; GLYPH_HEIGHT];
+            for (b, a) in g.iter_mut().zip(&args[1..]) {
+                *b = *a as u8;
+            }
+            // The first character wins where two share a glyph
+            glyphs.entry(g).or_insert(c);
+        }
+        if glyphs.is_empty() {
+            return Err(load_error(0, "No Output.create calls"));
+        }
+        Ok(Font{glyphs})
+    }
+
+    // From the Output.create(...) calls in Output.jack
+    pub fn from_jack(src: &str) -> Result<Font, LoadError> {
+        let mut calls = vec![];
+        for (i, line) in src.lines().enumerate() {
+            let code = line.split("//").next().unwrap();
+            if let Some(pos) = code.find("Output.create(") {
+                let rest = &code[pos + "Output.create(".len()..];
+                let args = rest.split(')').next().unwrap().split(',')
+                    .map(|a| a.trim().parse().map_err(|_| load_error(i + 1, "Invalid number")))
+                    .collect::<Result<Vec<i32>,_>>()?;
+                calls.push((i + 1, args));
+            }
+        }
+        Font::from_calls(calls)
+    }
+
+    // From compiled Output.vm, where each call is 12 constant pushes
+    // followed by call Output.create 12
+    pub fn from_vm(src: &str) -> Result<Font, LoadError> {
+        let mut calls = vec![];
+        let mut pushed = vec![];
+        for (i, line) in src.lines().enumerate() {
+            let words = line.split("//").next().unwrap().split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["push", "constant", n] => pushed.push(n.parse().map_err(|_| load_error(i + 1, "Invalid number"))?),
+                ["call", "Output.create", _] => {
+                    let args = pushed.split_off(pushed.len().saturating_sub(GLYPH_HEIGHT + 1));
+                    calls.push((i + 1, args));
+                },
+                [] => (),
+                _ => pushed.clear(),
+            }
+        }
+        Font::from_calls(calls)
+    }
+
+    // Output.jack or Output.vm
+    pub fn load(path: &str) -> Result<Font, String> {
+        let src = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let font = if path.ends_with(".vm") { Font::from_vm(&src) } else { Font::from_jack(&src) };
+        font.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.glyphs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.glyphs.is_empty()
+    }
+
+    // The character drawn in a cell: a space if it is blank
+    fn decode(&self, g: &Glyph) -> char {
+        match self.glyphs.get(g) {
+            Some(c) => *c,
+            None if g.iter().all(|b| *b == 0) => ' ',
+            None => UNKNOWN,
+        }
+    }
+}
+
+// The pixels of the text cell at (row, col)
+fn cell(em: &Emul, row: usize, col: usize) -> Glyph {
+    let mut g = [0; GLYPH_HEIGHT];
+    for (i, b) in g.iter_mut().enumerate() {
+        let addr = SCREEN + (row * GLYPH_HEIGHT + i) * 32 + col / 2;
+        let word = em.bus.read(addr).unwrap_or(0) as u16;
+        *b = (word >> (8 * (col % 2))) as u8;
+    }
+    g
+}
+
+// The screen as text, one line per text row with trailing blanks removed
+pub fn read_text(em: &Emul, font: &Font) -> Vec<String> {
+    (0..TEXT_ROWS).map(|row| {
+        let line = (0..TEXT_COLS).map(|col| font.decode(&cell(em, row, col))).collect::<String>();
+        line.trim_end().to_string()
+    }).collect()
+}
+
+// (row, column) of the first place text appears on the screen
+pub fn find_text(em: &Emul, font: &Font, text: &str) -> Option<(usize, usize)> {
+    read_text(em, font).iter().enumerate().find_map(|(row, line)| {
+        line.find(text).map(|i| (row, line[..i].chars().count()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    // A few glyphs in the style of Output.jack
+    const FONT_JACK: &str = "\
+    function void initMap() {
+        let charMaps = Array.new(127);
+        // Black square, used for non printable characters
+        do Output.create(0,63,63,63,63,63,63,63,63,63,0,0);
+        do Output.create(32,0,0,0,0,0,0,0,0,0,0,0);          //
+        do Output.create(48,12,30,51,51,51,51,51,30,12,0,0); // 0
+        do Output.create(49,12,14,15,12,12,12,12,12,63,0,0); // 1
+        do Output.create(58,0,0,12,12,0,0,12,12,0,0,0);      // :
+        do Output.create(83,30,51,51,6,28,48,51,51,30,0,0);  // S
+        do Output.create(99,0,0,0,30,51,3,3,51,30,0,0);      // c
+        do Output.create(101,0,0,0,30,51,63,3,51,30,0,0);    // e
+        do Output.create(111,0,0,0,30,51,51,51,51,30,0,0);   // o
+        do Output.create(114,0,0,0,29,55,51,3,3,7,0,0);      // r
+        return;
+    }
+";
+
+    fn draw(em: &mut Emul, font: &Font, row: usize, col: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            let g = font.glyphs.iter().find(|(_, gc)| **gc == c).unwrap().0;
+            let col = col + i;
+            for (j, b) in g.iter().enumerate() {
+                let addr = SCREEN + (row * GLYPH_HEIGHT + j) * 32 + col / 2;
+                let word = em.bus.read(addr).unwrap() as u16 | (*b as u16) << (8 * (col % 2));
+                em.poke(addr, word as i16);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_text() {
+        let font = Font::from_jack(FONT_JACK).unwrap();
+        assert_eq!(font.len(), 10);
+        let mut em = Emul::new();
+        draw(&mut em, &font, 0, 0, "Score: 10");
+        draw(&mut em, &font, 22, 61, "oo");
+        em.poke(SCREEN + 11 * 32 * 5 + 3, 0x0100);
+        let text = read_text(&em, &font);
+        assert_eq!(text.len(), TEXT_ROWS);
+        assert_eq!(text[0], "Score: 10");
+        assert_eq!(text[5], format!("       {}", UNKNOWN));
+        assert_eq!(text[22], format!("{:>63}", "oo"));
+        assert_eq!(find_text(&em, &font, "10"), Some((0, 7)));
+        assert_eq!(find_text(&em, &font, "oo"), Some((22, 61)));
+        assert_eq!(find_text(&em, &font, "11"), None);
+    }
+
+    #[test]
+    fn test_font_sources() {
+        // The same calls compiled to VM code
+        let mut vm = String::new();
+        for line in FONT_JACK.lines().filter(|l| l.contains("Output.create(")) {
+            let args = line.split('(').nth(1).unwrap().split(')').next().unwrap();
+            for a in args.split(',') {
+                vm += &format!("push constant {}\n", a);
+            }
+            vm += "call Output.create 12\npop temp 0\n";
+        }
+        let mut parser = Parser::new("Output");
+        assert!(vm.lines().all(|l| parser.parse_str(l).is_ok()));
+        let font = Font::from_vm(&vm).unwrap();
+        assert_eq!(font.glyphs, Font::from_jack(FONT_JACK).unwrap().glyphs);
+
+        let err = Font::from_jack("do Output.create(65,1,2);\n").err().unwrap();
+        assert_eq!(err.to_string(), "LoadError: Line: 1, Error: Expected a character and 11 bytes");
+        assert!(Font::from_vm("push constant 1\n").is_err());
+    }
+}
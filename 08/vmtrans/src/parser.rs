@@ -16,14 +16,14 @@ pub struct ParserError {
 
 impl fmt::Display for ParserError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ParserError: File: {}, Line: {},  Error: {}, Code: {}\n",
+        writeln!(f, "ParserError: File: {}, Line: {},  Error: {}, Code: {}",
                self.file_name, self.line_num, self.description, self.code)
     }
 }
 
 impl fmt::Debug for ParserError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ParserError: File: {}, Line: {},  Error: {}, Code: {}\n",
+        writeln!(f, "ParserError: File: {}, Line: {},  Error: {}, Code: {}",
                self.file_name, self.line_num, self.description, self.code)
     }
 }
@@ -44,7 +44,7 @@ impl Parser {
     pub fn parse_str(&mut self, cmd_str: &str) -> Result<Option<VMCommand>, ParserError> {
         self.line_num += 1;
         let ws: Vec<&str> = cmd_str.split_whitespace().collect();
-        if ws.len() < 1 || ws[0] == "//" {
+        if ws.is_empty() || ws[0] == "//" {
             Ok(None)
         } else if let Some(vmc) = VMOp::from_str(ws[0]) {
             Ok(Some(VMCommand::Arithmetic(vmc)))
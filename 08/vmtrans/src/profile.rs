@@ -0,0 +1,233 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::emul::{Stop,Symbols};
+use crate::observer::{Observer,Step};
+
+struct Node {
+    func: Option<usize>,
+    parent: usize,
+    children: Vec<usize>,
+    calls: u64,
+    self_ticks: u64,
+}
+
+// Counts executed instructions per ROM address and keeps a call tree.
+// A call is a jump to the start of a function; the matching return is the
+// jump back to the instruction after it.
+pub struct Profiler {
+    counts: Vec<u64>,
+    funcs: Vec<(usize,String)>,
+    func_addrs: HashSet<usize>,
+    nodes: Vec<Node>,
+    cur: usize,
+    stack: Vec<usize>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Observer for Profiler {
+    fn set_symbols(&mut self, syms: &Symbols) {
+        self.funcs = syms.functions.clone();
+        self.func_addrs = self.funcs.iter().map(|(addr, _)| *addr).collect();
+    }
+
+    fn on_exec(&mut self, s: &Step) -> Option<Stop> {
+        // 0;JMP to the next address is still a jump, as when a function
+        // starts right after the call to it
+        let jumped = s.next_pc != s.pc + 1 || s.instr & 0xe007 == 0xe007;
+        self.record(s.pc, s.next_pc, jumped);
+        None
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler{counts: vec![], funcs: vec![], func_addrs: HashSet::new(), nodes: vec![], cur: 0, stack: vec![]}
+    }
+
+    fn func_at(&self, pc: usize) -> Option<usize> {
+        let i = self.funcs.partition_point(|(addr, _)| *addr <= pc);
+        if i == 0 {
+            None
+        } else {
+            Some(i-1)
+        }
+    }
+
+    fn func_name(&self, func: Option<usize>) -> &str {
+        match func {
+            Some(i) => &self.funcs[i].1,
+            None => "(top)",
+        }
+    }
+
+    // Called once per executed instruction, with the pc it ran at, the pc
+    // of the next instruction and whether it jumped there.
+    pub fn record(&mut self, pc: usize, next_pc: usize, jumped: bool) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+        }
+        self.counts[pc] += 1;
+        if self.nodes.is_empty() {
+            let func = self.func_at(pc);
+            self.nodes.push(Node{func, parent: 0, children: vec![], calls: 1, self_ticks: 0});
+        }
+        self.nodes[self.cur].self_ticks += 1;
+
+        if !jumped {
+            return;
+        }
+        if self.func_addrs.contains(&next_pc) {
+            let func = self.func_at(next_pc);
+            let child = match self.nodes[self.cur].children.iter().find(|c| self.nodes[**c].func == func) {
+                Some(c) => *c,
+                None => {
+                    self.nodes.push(Node{func, parent: self.cur, children: vec![], calls: 0, self_ticks: 0});
+                    let c = self.nodes.len() - 1;
+                    self.nodes[self.cur].children.push(c);
+                    c
+                }
+            };
+            self.nodes[child].calls += 1;
+            self.stack.push(pc + 1);
+            self.cur = child;
+        } else if self.stack.last() == Some(&next_pc) {
+            self.stack.pop();
+            self.cur = self.nodes[self.cur].parent;
+        }
+    }
+
+    pub fn count_at(&self, pc: usize) -> u64 {
+        self.counts.get(pc).cloned().unwrap_or(0)
+    }
+
+    // (function, instructions, calls), busiest first
+    pub fn function_counts(&self) -> Vec<(String,u64,u64)> {
+        let mut ticks = vec![0u64; self.funcs.len() + 1];
+        let mut calls = vec![0u64; self.funcs.len() + 1];
+        let slot = |f: Option<usize>| f.map_or(0, |i| i + 1);
+        for (pc, n) in self.counts.iter().enumerate() {
+            ticks[slot(self.func_at(pc))] += n;
+        }
+        for node in &self.nodes {
+            calls[slot(node.func)] += node.calls;
+        }
+        let mut r = vec![];
+        for i in 0..ticks.len() {
+            if ticks[i] > 0 || calls[i] > 0 {
+                let func = if i == 0 { None } else { Some(i - 1) };
+                r.push((self.func_name(func).to_string(), ticks[i], calls[i]));
+            }
+        }
+        r.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        r
+    }
+
+    pub fn flat_report(&self) -> String {
+        let total: u64 = self.counts.iter().sum();
+        let mut r = String::new();
+        writeln!(&mut r, "{:<40} {:>12} {:>7} {:>10}", "Function", "Instructions", "%", "Calls").unwrap();
+        for (name, ticks, calls) in self.function_counts() {
+            let pct = if total == 0 { 0.0 } else { 100.0 * ticks as f64 / total as f64 };
+            writeln!(&mut r, "{:<40} {:>12} {:>6.2}% {:>10}", name, ticks, pct, calls).unwrap();
+        }
+        r
+    }
+
+    fn total_ticks(&self, node: usize) -> u64 {
+        self.nodes[node].self_ticks +
+            self.nodes[node].children.iter().map(|c| self.total_ticks(*c)).sum::<u64>()
+    }
+
+    fn write_tree(&self, r: &mut String, node: usize, depth: usize) {
+        let n = &self.nodes[node];
+        writeln!(r, "{:width$}{} total={} self={} calls={}", "", self.func_name(n.func),
+                 self.total_ticks(node), n.self_ticks, n.calls, width = depth * 2).unwrap();
+        let mut children = n.children.iter().map(|c| (self.total_ticks(*c), *c)).collect::<Vec<_>>();
+        children.sort_by_key(|c| std::cmp::Reverse(c.0));
+        for (_, c) in children {
+            self.write_tree(r, c, depth + 1);
+        }
+    }
+
+    pub fn call_tree_report(&self) -> String {
+        let mut r = String::new();
+        if !self.nodes.is_empty() {
+            self.write_tree(&mut r, 0, 0);
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::Emul;
+    use crate::testutil::load_vm;
+    use crate::types::*;
+
+    #[test]
+    fn test_profile_calls() {
+        // START is a plain label outside any function, not a function
+        let table = vec![
+            VMCommand::Label("START".to_string()),
+            VMCommand::Push(VMSeg::CONSTANT, 3),
+            VMCommand::Call("Main.twice".to_string(), 1),
+            VMCommand::Push(VMSeg::CONSTANT, 4),
+            VMCommand::Call("Main.twice".to_string(), 1),
+            VMCommand::Label("END".to_string()),
+            VMCommand::Goto("END".to_string()),
+            VMCommand::Function("Main.twice".to_string(), 0),
+            VMCommand::Push(VMSeg::ARGUMENT, 0),
+            VMCommand::Call("Main.id".to_string(), 1),
+            VMCommand::Push(VMSeg::ARGUMENT, 0),
+            VMCommand::Arithmetic(VMOp::ADD),
+            VMCommand::Return,
+            VMCommand::Function("Main.id".to_string(), 0),
+            VMCommand::Push(VMSeg::ARGUMENT, 0),
+            VMCommand::Return,
+        ];
+        let mut em = Emul::new();
+        em.enable_profiler();
+        load_vm(&mut em, &table, &[(0, 256)]);
+        assert_eq!(em.run_rom(2000), Stop::Halted);
+        assert_eq!(em.peek(257), 8);
+
+        let p = em.profiler().unwrap();
+        let counts = p.function_counts();
+        let names = counts.iter().map(|(n, _, c)| (n.as_str(), *c)).collect::<Vec<_>>();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&("Main.twice", 2)));
+        assert!(names.contains(&("Main.id", 2)));
+        assert!(names.contains(&("(top)", 1)));
+        assert_eq!(counts.iter().map(|c| c.1).sum::<u64>(), (0..2000).map(|pc| p.count_at(pc)).sum());
+
+        let tree = p.call_tree_report();
+        let lines = tree.lines().map(|l| l.split(" total").next().unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["(top)", "  Main.twice", "    Main.id"]);
+        assert!(tree.contains("Main.id total=") && tree.contains("calls=2"));
+
+        drop(p);
+
+        // A call to a function that starts right after it
+        let mut p = Profiler::new();
+        p.set_symbols(&Symbols{labels: vec![], functions: vec![(2, "Main.f".to_string())]});
+        p.record(0, 1, false);
+        p.record(1, 2, true);
+        p.record(2, 3, false);
+        assert_eq!(p.call_tree_report().lines().map(|l| l.split(" total").next().unwrap()).collect::<Vec<_>>(),
+                   vec!["(top)", "  Main.f"]);
+
+        // With only a symbol table the functions are found by their prologue
+        let functions = em.symbols().functions.clone();
+        assert_eq!(functions.iter().map(|f| f.1.as_str()).collect::<Vec<_>>(), vec!["Main.twice", "Main.id"]);
+        em.set_functions(&[]);
+        em.find_functions();
+        assert_eq!(em.symbols().functions, functions);
+    }
+}
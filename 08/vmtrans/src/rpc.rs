@@ -0,0 +1,286 @@
+use std::fs::{read,read_to_string};
+use std::io::{BufRead,Write};
+
+use crate::bus::{SCREEN,SCREEN_SIZE};
+use crate::emul::{Emul,Stop};
+use crate::json::{Json,obj};
+use crate::loader::parse_image;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn invalid(message: &str) -> RpcError {
+    RpcError{code: INVALID_PARAMS, message: message.to_string()}
+}
+
+fn failed(message: impl ToString) -> RpcError {
+    RpcError{code: SERVER_ERROR, message: message.to_string()}
+}
+
+fn param_u64(params: &Json, key: &str) -> Result<u64, RpcError> {
+    params.get(key).as_i64().filter(|n| *n >= 0).map(|n| n as u64)
+        .ok_or_else(|| invalid(&format!("{} must be a non-negative integer", key)))
+}
+
+fn stop_name(stop: Stop) -> &'static str {
+    match stop {
+        Stop::End => "end",
+        Stop::Halted => "halted",
+        Stop::Breakpoint => "breakpoint",
+        Stop::Trapped => "trapped",
+        Stop::Fault => "fault",
+        Stop::TickLimit => "tickLimit",
+    }
+}
+
+// A JSON-RPC 2.0 server around Emul, one request or response per line.
+// Methods:
+//   load {path} or {asm} or {hack}     replace the program, clearing RAM
+//   step {count?}                      execute instructions
+//   run {ticks}                        run to a breakpoint, halt or the end
+//   registers                          {a, d, pc, ticks}
+//   readMemory {addr, count?}          {values}
+//   writeMemory {addr, values}
+//   setBreakpoints {locations}         ROM addresses or labels; replaces all
+//   setKey {key}
+//   screen                             256 rows of 128 hex digits, 4 per
+//                                      word, bit 0 of a word leftmost
+//   shutdown
+pub struct RpcServer {
+    pub em: Emul,
+    pub done: bool,
+}
+
+impl Default for RpcServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RpcServer {
+    pub fn new() -> RpcServer {
+        RpcServer{em: Emul::new(), done: false}
+    }
+
+    fn load(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let mut em = Emul::new();
+        if let Some(src) = params.get("asm").as_str() {
+            em.load_code(src).map_err(failed)?;
+        } else if let Some(src) = params.get("hack").as_str() {
+            em.load_hack(src).map_err(failed)?;
+        } else if let Some(path) = params.get("path").as_str() {
+            if path.ends_with(".bin") {
+                em.load_rom(parse_image(&read(path).map_err(failed)?).map_err(failed)?);
+            } else if path.ends_with(".hack") {
+                em.load_hack(&read_to_string(path).map_err(failed)?).map_err(failed)?;
+            } else {
+                em.load_code(&read_to_string(path).map_err(failed)?).map_err(failed)?;
+            }
+        } else {
+            return Err(invalid("load needs path, asm or hack"));
+        }
+        self.em = em;
+        Ok(obj(&[("romSize", (self.em.rom().len() as i64).into())]))
+    }
+
+    fn step(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let count = if params.get("count").is_null() { 1 } else { param_u64(params, "count")? };
+        let mut n = 0;
+        while n < count && self.em.pc() < self.em.rom().len() {
+            self.em.step();
+            if self.em.fault().is_some() {
+                break;
+            }
+            n += 1;
+        }
+        Ok(obj(&[("steps", (n as i64).into()), ("pc", (self.em.pc() as i64).into()),
+                 ("ticks", (self.em.ticks() as i64).into())]))
+    }
+
+    fn run(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let stop = self.em.resume(param_u64(params, "ticks")?);
+        let mut r = obj(&[("stop", stop_name(stop).into()), ("pc", (self.em.pc() as i64).into()),
+                          ("ticks", (self.em.ticks() as i64).into())]);
+        if let (Json::Obj(kvs), Some(t)) = (&mut r, self.em.trap()) {
+            kvs.push(("trap".to_string(), t.to_string().into()));
+        }
+        if let (Json::Obj(kvs), Some(f)) = (&mut r, self.em.fault()) {
+            kvs.push(("fault".to_string(), f.into()));
+        }
+        Ok(r)
+    }
+
+    fn read_memory(&self, params: &Json) -> Result<Json, RpcError> {
+        let addr = param_u64(params, "addr")? as usize;
+        let count = if params.get("count").is_null() { 1 } else { param_u64(params, "count")? as usize };
+        let values = (addr..addr + count)
+            .map(|a| self.em.bus.read(a).map(|v| (v as i64).into()))
+            .collect::<Result<Vec<Json>, _>>().map_err(failed)?;
+        Ok(obj(&[("values", values.into())]))
+    }
+
+    fn write_memory(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let addr = param_u64(params, "addr")? as usize;
+        let values = params.get("values").as_array().iter()
+            .map(|v| v.as_i64().filter(|n| (-32768..=65535).contains(n)).map(|n| n as i16))
+            .collect::<Option<Vec<_>>>().ok_or_else(|| invalid("values must be 16-bit integers"))?;
+        for (i, v) in values.iter().enumerate() {
+            self.em.bus.write(addr + i, *v).map_err(failed)?;
+        }
+        Ok(obj(&[("count", (values.len() as i64).into())]))
+    }
+
+    fn set_breakpoints(&mut self, params: &Json) -> Result<Json, RpcError> {
+        let mut addrs = vec![];
+        for loc in params.get("locations").as_array() {
+            let addr = match loc {
+                Json::Str(label) => self.em.label_addr(label),
+                _ => loc.as_i64().filter(|n| *n >= 0).map(|n| n as usize),
+            };
+            addrs.push(addr.ok_or_else(|| invalid(&format!("Bad location {}", loc)))?);
+        }
+        for pc in self.em.breakpoints() {
+            self.em.remove_breakpoint(pc);
+        }
+        for pc in &addrs {
+            self.em.add_breakpoint(*pc);
+        }
+        Ok(obj(&[("addresses", addrs.iter().map(|a| (*a as i64).into()).collect::<Vec<Json>>().into())]))
+    }
+
+    fn screen(&self) -> Json {
+        let rows = (0..SCREEN_SIZE / 32).map(|row| {
+            (0..32).map(|w| format!("{:04x}", self.em.bus.read(SCREEN + row * 32 + w).unwrap_or(0) as u16))
+                .collect::<String>().into()
+        }).collect::<Vec<Json>>();
+        obj(&[("width", 512.into()), ("height", 256.into()), ("rows", rows.into())])
+    }
+
+    fn call(&mut self, method: &str, params: &Json) -> Result<Json, RpcError> {
+        match method {
+            "load" => self.load(params),
+            "step" => self.step(params),
+            "run" => self.run(params),
+            "registers" => Ok(obj(&[("a", (self.em.a as i64).into()), ("d", (self.em.d as i64).into()),
+                                    ("pc", (self.em.pc() as i64).into()), ("ticks", (self.em.ticks() as i64).into())])),
+            "readMemory" => self.read_memory(params),
+            "writeMemory" => self.write_memory(params),
+            "setBreakpoints" => self.set_breakpoints(params),
+            "setKey" => {
+                let key = params.get("key").as_i64().ok_or_else(|| invalid("key must be an integer"))?;
+                self.em.set_key(key as i16);
+                Ok(Json::Null)
+            },
+            "screen" => Ok(self.screen()),
+            "shutdown" => {
+                self.done = true;
+                Ok(Json::Null)
+            },
+            _ => Err(RpcError{code: METHOD_NOT_FOUND, message: format!("Unknown method {}", method)}),
+        }
+    }
+
+    // Reply to one request line; None for notifications
+    pub fn handle(&mut self, line: &str) -> Option<Json> {
+        let req = match Json::parse(line) {
+            Ok(req) => req,
+            Err(e) => return Some(reply(&Json::Null, Err(RpcError{code: PARSE_ERROR, message: e}))),
+        };
+        let id = req.get("id").clone();
+        let result = match req.get("method").as_str() {
+            Some(method) if req.get("jsonrpc").as_str() == Some("2.0") => self.call(method, req.get("params")),
+            _ => Err(RpcError{code: INVALID_REQUEST, message: "Not a JSON-RPC 2.0 request".to_string()}),
+        };
+        if id.is_null() && req.get("method").as_str().is_some() {
+            return None;
+        }
+        Some(reply(&id, result))
+    }
+}
+
+fn reply(id: &Json, result: Result<Json, RpcError>) -> Json {
+    let body = match result {
+        Ok(v) => ("result", v),
+        Err(e) => ("error", obj(&[("code", e.code.into()), ("message", e.message.into())])),
+    };
+    obj(&[("jsonrpc", "2.0".into()), ("id", id.clone()), body])
+}
+
+// Serve requests until shutdown or the end of input
+pub fn serve(mut server: RpcServer, input: impl BufRead, out: &mut dyn Write) -> std::io::Result<RpcServer> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(r) = server.handle(&line) {
+            writeln!(out, "{}", r)?;
+            out.flush()?;
+        }
+        if server.done {
+            break;
+        }
+    }
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(server: &mut RpcServer, id: i64, method: &str, params: &str) -> Json {
+        let line = format!(r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{}}}"#, id, method, params);
+        server.handle(&line).unwrap()
+    }
+
+    #[test]
+    fn test_rpc() {
+        let mut server = RpcServer::new();
+        let asm = r#"{"asm":"@7\nD=A\n@100\nM=D\n(LOOP)\n@SCREEN\nM=-1\n"}"#;
+        assert_eq!(call(&mut server, 1, "load", asm).to_string(), r#"{"jsonrpc":"2.0","id":1,"result":{"romSize":6}}"#);
+        assert_eq!(call(&mut server, 2, "step", r#"{"count":2}"#).get("result").get("pc").as_i64(), Some(2));
+        assert_eq!(call(&mut server, 3, "registers", "{}").get("result").get("d").as_i64(), Some(7));
+        let r = call(&mut server, 4, "setBreakpoints", r#"{"locations":["LOOP"]}"#);
+        assert_eq!(r.get("result").get("addresses"), &Json::parse("[4]").unwrap());
+        let r = call(&mut server, 5, "run", r#"{"ticks":100}"#);
+        assert_eq!(r.get("result").to_string(), r#"{"stop":"breakpoint","pc":4,"ticks":4}"#);
+        assert_eq!(call(&mut server, 6, "run", r#"{"ticks":100}"#).get("result").get("stop").as_str(), Some("end"));
+        call(&mut server, 7, "writeMemory", r#"{"addr":101,"values":[-1,65535]}"#);
+        let r = call(&mut server, 8, "readMemory", r#"{"addr":100,"count":3}"#);
+        assert_eq!(r.get("result").get("values"), &Json::parse("[7,-1,-1]").unwrap());
+        let rows = call(&mut server, 9, "screen", "{}").get("result").get("rows").as_array().to_vec();
+        assert_eq!(rows.len(), 256);
+        assert_eq!(&rows[0].as_str().unwrap()[..8], "ffff0000");
+
+        let code = |r: Json| r.get("error").get("code").as_i64();
+        assert_eq!(code(call(&mut server, 10, "fly", "{}")), Some(METHOD_NOT_FOUND));
+        assert_eq!(code(call(&mut server, 11, "readMemory", r#"{"addr":-3}"#)), Some(INVALID_PARAMS));
+        assert_eq!(code(call(&mut server, 12, "readMemory", r#"{"addr":30000}"#)), Some(SERVER_ERROR));
+        assert_eq!(code(server.handle("{oops").unwrap()), Some(PARSE_ERROR));
+        assert_eq!(code(call(&mut server, 13, "load", r#"{"asm":"@-1\nD=A"}"#)), Some(SERVER_ERROR));
+        assert_eq!(server.em.rom().len(), 6);
+        assert_eq!(server.handle(r#"{"jsonrpc":"2.0","method":"setKey","params":{"key":65}}"#), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        let input = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"registers\"}\n\n\
+                     {\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"shutdown\"}\n\
+                     {\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"registers\"}\n";
+        let mut out = vec![];
+        let server = serve(RpcServer::new(), input.as_bytes(), &mut out).unwrap();
+        assert!(server.done);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.starts_with(r#"{"jsonrpc":"2.0","id":1,"result":{"a":0,"d":0,"pc":0,"ticks":0}}"#));
+    }
+}
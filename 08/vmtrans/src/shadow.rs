@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::bus::RAM_SIZE;
+use crate::emul::Stop;
+use crate::observer::Observer;
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct UninitRead {
+    pub pc: usize,
+    pub addr: usize,
+    // Filled in by Emul::uninit_reads
+    pub label: Option<String>,
+}
+
+impl fmt::Display for UninitRead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Read of uninitialized RAM[{}] at pc {}", self.addr, self.pc)?;
+        if let Some(ref l) = self.label {
+            write!(f, " ({})", l)?;
+        }
+        Ok(())
+    }
+}
+
+// An "initialized" bit per RAM word.  Each (pc, addr) pair is reported
+// once, however often it runs.
+pub struct Shadow {
+    init: Vec<bool>,
+    seen: HashSet<(usize,usize)>,
+    pub reads: Vec<UninitRead>,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Observer for Shadow {
+    fn on_mem_read(&mut self, pc: usize, addr: usize, _val: i16) -> Option<Stop> {
+        if self.check(pc, addr) {
+            self.reads.push(UninitRead{pc, addr, label: None});
+        }
+        None
+    }
+
+    fn on_mem_write(&mut self, _pc: usize, addr: usize, _val: i16) -> Option<Stop> {
+        self.mark(addr);
+        None
+    }
+}
+
+impl Shadow {
+    pub fn new() -> Shadow {
+        Shadow{init: vec![false; RAM_SIZE], seen: HashSet::new(), reads: vec![]}
+    }
+
+    pub fn mark(&mut self, addr: usize) {
+        if addr < RAM_SIZE {
+            self.init[addr] = true;
+        }
+    }
+
+    pub fn is_init(&self, addr: usize) -> bool {
+        addr >= RAM_SIZE || self.init[addr]
+    }
+
+    // Put back a bit saved with is_init, when an instruction is undone
+    pub fn set_init(&mut self, addr: usize, init: bool) {
+        if addr < RAM_SIZE {
+            self.init[addr] = init;
+        }
+    }
+
+    pub fn mark_all(&mut self) {
+        self.init.iter_mut().for_each(|b| *b = true);
+    }
+
+    // True if this is the first uninitialized read of addr at pc
+    pub fn check(&mut self, pc: usize, addr: usize) -> bool {
+        addr < RAM_SIZE && !self.init[addr] && self.seen.insert((pc, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::Emul;
+    use crate::testutil::load_vm;
+    use crate::types::*;
+
+    fn run_function(n_locals: i32) -> Vec<UninitRead> {
+        let table = vec![
+            VMCommand::Call("Main.f".to_string(), 0),
+            VMCommand::Label("END".to_string()),
+            VMCommand::Goto("END".to_string()),
+            VMCommand::Function("Main.f".to_string(), n_locals),
+            VMCommand::Push(VMSeg::LOCAL, 0),
+            VMCommand::Return,
+        ];
+        let mut em = Emul::new();
+        em.enable_uninit_check();
+        load_vm(&mut em, &table, &[(0, 256), (1, 0), (2, 0), (3, 0), (4, 0)]);
+        assert_eq!(em.run_rom(1000), Stop::Halted);
+        em.uninit_reads().to_vec()
+    }
+
+    #[test]
+    fn test_uninit_local() {
+        assert_eq!(run_function(1), vec![]);
+        let reads = run_function(0);
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].addr, 261);
+        assert_eq!(reads[0].label.as_deref(), Some("Main.f"));
+    }
+
+    #[test]
+    fn test_uninit_presets_and_devices() {
+        let mut em = Emul::new();
+        em.enable_uninit_check();
+        em.set_ram(&[(100, 1)]);
+        assert_eq!(em.run_code("@100\nD=M\n@101\nD=M\nD=M\n@SCREEN\nD=M\n@102\nM=1\nD=M\n", 100), Ok(Stop::End));
+        assert_eq!(em.uninit_reads().to_vec(), vec![UninitRead{pc: 3, addr: 101, label: None},
+                                                     UninitRead{pc: 4, addr: 101, label: None}]);
+        assert_eq!(em.uninit_reads()[0].to_string(), "Read of uninitialized RAM[101] at pc 3");
+    }
+}
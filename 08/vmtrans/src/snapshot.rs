@@ -0,0 +1,168 @@
+use std::fmt;
+
+use crate::bus::{RAM_SIZE,SCREEN_SIZE};
+use crate::loader::{LoadError,load_error};
+
+const HEADER: &str = "hack-snapshot 1";
+// The pc can be one past the last instruction of a full ROM
+const MAX_PC: i64 = 32768;
+
+// Complete machine state, apart from the ROM.  The text form is
+//
+//   hack-snapshot 1
+//   a 12
+//   d -3
+//   pc 40
+//   ticks 12345
+//   key 0
+//   mem 256 7 0 5      (nonzero runs of RAM and screen, starting at 256)
+//   device 0 1 2       (state of the first user device)
+#[derive(Debug,PartialEq,Clone)]
+pub struct Snapshot {
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+    pub ticks: u64,
+    pub key: i16,
+    pub mem: Vec<i16>,
+    pub devices: Vec<Vec<i16>>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "a {}\nd {}\npc {}\nticks {}\nkey {}", self.a, self.d, self.pc, self.ticks, self.key)?;
+        let mut addr = 0;
+        while addr < self.mem.len() {
+            if self.mem[addr] == 0 {
+                addr += 1;
+                continue;
+            }
+            write!(f, "mem {}", addr)?;
+            let mut n = 0;
+            while addr < self.mem.len() && self.mem[addr] != 0 && n < 16 {
+                write!(f, " {}", self.mem[addr])?;
+                addr += 1;
+                n += 1;
+            }
+            writeln!(f)?;
+        }
+        for (i, state) in self.devices.iter().enumerate() {
+            write!(f, "device {}", i)?;
+            for v in state {
+                write!(f, " {}", v)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    pub fn parse(src: &str) -> Result<Snapshot, LoadError> {
+        let mut snap = Snapshot{a: 0, d: 0, pc: 0, ticks: 0, key: 0, mem: vec![0; RAM_SIZE + SCREEN_SIZE], devices: vec![]};
+        let mut lines = src.lines().enumerate();
+        match lines.next() {
+            Some((_, l)) if l.trim() == HEADER => {},
+            _ => return Err(load_error(1, "Not a snapshot file")),
+        }
+        for (i, line) in lines {
+            let ws: Vec<&str> = line.split_whitespace().collect();
+            if ws.is_empty() {
+                continue;
+            }
+            let bad = || load_error(i + 1, "Invalid snapshot line");
+            let nums = ws[1..].iter().map(|w| w.parse::<i64>()).collect::<Result<Vec<_>,_>>().map_err(|_| bad())?;
+            // Words may be written signed or unsigned
+            let word = |n: i64| match n {
+                -32768..=65535 => Ok(n as i16),
+                _ => Err(load_error(i + 1, "Value out of range")),
+            };
+            let words = |ns: &[i64]| ns.iter().map(|n| word(*n)).collect::<Result<Vec<_>,_>>();
+            match (ws[0], nums.len()) {
+                ("a", 1) => snap.a = word(nums[0])?,
+                ("d", 1) => snap.d = word(nums[0])?,
+                ("pc", 1) if (0..=MAX_PC).contains(&nums[0]) => snap.pc = nums[0] as usize,
+                ("ticks", 1) if nums[0] >= 0 => snap.ticks = nums[0] as u64,
+                ("key", 1) => snap.key = word(nums[0])?,
+                ("mem", n) if n >= 1 => {
+                    let base = nums[0];
+                    if base < 0 || base as usize + n - 1 > snap.mem.len() {
+                        return Err(load_error(i + 1, "Address out of range"));
+                    }
+                    for (k, v) in words(&nums[1..])?.into_iter().enumerate() {
+                        snap.mem[base as usize + k] = v;
+                    }
+                },
+                // Devices come in the order they were mapped
+                ("device", n) if n >= 1 => {
+                    if nums[0] != snap.devices.len() as i64 {
+                        return Err(load_error(i + 1, "Device out of order"));
+                    }
+                    snap.devices.push(words(&nums[1..])?);
+                },
+                _ => return Err(bad()),
+            }
+        }
+        Ok(snap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::{Emul,Stop};
+
+    #[test]
+    fn test_round_trip() {
+        let mut em = Emul::new();
+        em.set_ram(&[(0, 256), (256, 3), (257, -4), (16384, -1)]);
+        assert_eq!(em.run_code("@256\nD=M\n@257\nD=D+M\n@258\nM=D\n@SP\nM=M+1\n", 100), Ok(Stop::End));
+        let snap = em.snapshot();
+        let text = snap.to_string();
+        assert!(text.contains("mem 256 3 -4 -1\n"));
+        assert!(text.contains("mem 16384 -1\n"));
+        assert_eq!(Snapshot::parse(&text), Ok(snap.clone()));
+
+        let mut em2 = Emul::new();
+        em2.restore(&Snapshot::parse(&text).unwrap()).unwrap();
+        assert_eq!((em2.a, em2.d, em2.pc(), em2.ticks()), (0, -1, 8, 8));
+        assert_eq!(em2.peek(0), 257);
+        assert_eq!(em2.peek(258), -1);
+        assert_eq!(em2.peek(16384), -1);
+        assert_eq!(em2.snapshot(), snap);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Snapshot::parse("a 1\n").is_err());
+        assert!(Snapshot::parse("hack-snapshot 1\nfoo 1\n").is_err());
+        assert!(Snapshot::parse("hack-snapshot 1\nmem 24575 1 2\n").is_err());
+        assert!(Snapshot::parse("hack-snapshot 1\nmem 24575 1\n").is_ok());
+        let err = |s: &str| Snapshot::parse(&format!("hack-snapshot 1\n{}\n", s)).err().map(|e| e.to_string());
+        assert_eq!(err("mem -1 5"), Some("LoadError: Line: 2, Error: Address out of range".to_string()));
+        assert_eq!(err("mem 0 65536"), Some("LoadError: Line: 2, Error: Value out of range".to_string()));
+        assert_eq!(err("a -32769"), Some("LoadError: Line: 2, Error: Value out of range".to_string()));
+        assert_eq!(err("device 99999999999"), Some("LoadError: Line: 2, Error: Device out of order".to_string()));
+        assert!(err("pc -1").is_some() && err("pc 32769").is_some() && err("ticks -5").is_some());
+        assert_eq!(err("a 65535\nmem 0 65535\ndevice 0 1\ndevice 1"), None);
+    }
+
+    #[test]
+    fn test_restore_devices() {
+        let mut em = Emul::new();
+        em.enable_debug_port(30000, Box::new(std::io::sink())).unwrap();
+        let mut snap = em.snapshot();
+        snap.a = 5;
+        assert_eq!(snap.devices.len(), 1);
+        snap.devices.clear();
+        assert_eq!(em.restore(&snap), Err("Snapshot has 0 device states, but 1 device is mapped".to_string()));
+        snap.devices.push(vec![1, 2, 3]);
+        assert!(em.restore(&snap).unwrap_err().starts_with("Device 0 state has 3 words"));
+        // Nothing was restored
+        assert_eq!(em.a, 0);
+        snap.devices = em.snapshot().devices;
+        assert_eq!(em.restore(&snap), Ok(()));
+        assert_eq!(em.a, 5);
+    }
+}
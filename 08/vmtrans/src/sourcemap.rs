@@ -0,0 +1,226 @@
+use std::fs::{read_dir,read_to_string};
+use std::path::Path;
+
+use crate::asm::{Asm,Command};
+use crate::parser::{Parser,ParserError};
+use crate::translator::Translator;
+use crate::types::VMCommand;
+
+// The first ROM address of the code for one source line
+#[derive(Debug,PartialEq,Clone)]
+pub struct Line {
+    pub addr: usize,
+    pub file: usize,
+    pub line: usize,
+    pub call: bool,
+    pub ret: bool,
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub struct Function {
+    pub name: String,
+    pub file: usize,
+    pub n_locals: usize,
+    pub addr: usize,
+}
+
+// Maps ROM addresses back to the .vm (or .asm) lines they came from
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct SourceMap {
+    pub files: Vec<String>,
+    lines: Vec<Line>,
+    funcs: Vec<Function>,
+}
+
+// Number of instructions in a piece of assembly
+fn count(asm: &Asm, code: &str) -> usize {
+    code.lines()
+        .filter(|l| matches!(asm.parse_cmd(l), Ok(Some(c)) if !matches!(c, Command::Label(_))))
+        .count()
+}
+
+pub fn file_stem(path: &str) -> String {
+    Path::new(path).file_stem().map_or(path.to_string(), |s| s.to_string_lossy().into_owned())
+}
+
+// Read a program for source-level tools: a directory of .vm files
+// (translated with the bootstrap), a single .vm file or a .asm file.
+// Returns the assembly, its map and whether the program sets up SP itself.
+pub fn load_program(program: &str) -> Result<(String, SourceMap, bool), String> {
+    let path = Path::new(program);
+    let read = |p: &Path| read_to_string(p).map_err(|e| format!("{}: {}", p.display(), e));
+    if path.is_dir() {
+        let mut names = read_dir(path).map_err(|e| e.to_string())?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|x| x == "vm"))
+            .collect::<Vec<_>>();
+        names.sort();
+        let mut files = vec![];
+        for p in names {
+            files.push((p.to_string_lossy().into_owned(), read(&p)?));
+        }
+        let (code, map) = SourceMap::translate(&files, true).map_err(|e| e.to_string())?;
+        Ok((code, map, true))
+    } else if program.ends_with(".vm") {
+        let files = [(program.to_string(), read(path)?)];
+        let (code, map) = SourceMap::translate(&files, false).map_err(|e| e.to_string())?;
+        Ok((code, map, false))
+    } else {
+        let src = read(path)?;
+        let map = SourceMap::for_asm(program, &src);
+        Ok((src, map, true))
+    }
+}
+
+impl SourceMap {
+    // Translate .vm sources, given as (path, text), recording where each
+    // command's code starts
+    pub fn translate(files: &[(String,String)], bootstrap: bool) -> Result<(String, SourceMap), ParserError> {
+        let counter = Asm::new();
+        let mut map = SourceMap::default();
+        let mut code = String::new();
+        let mut tr = Translator::new("bootstrap");
+        if bootstrap {
+            code += &tr.gen_bootstrap();
+        }
+        let mut addr = count(&counter, &code);
+        for (fi, (path, src)) in files.iter().enumerate() {
+            map.files.push(path.clone());
+            let base = file_stem(path);
+            let mut parser = Parser::new(&base);
+            tr.set_file(&base);
+            for (i, line) in src.lines().enumerate() {
+                let cmd = match parser.parse_str(line)? {
+                    Some(cmd) => cmd,
+                    None => continue,
+                };
+                let out = tr.trans_cmd(&cmd);
+                map.lines.push(Line{addr, file: fi, line: i + 1,
+                                    call: matches!(cmd, VMCommand::Call(..)), ret: cmd == VMCommand::Return});
+                if let VMCommand::Function(ref name, n) = cmd {
+                    map.funcs.push(Function{name: name.clone(), file: fi, n_locals: n as usize, addr});
+                }
+                addr += count(&counter, &out);
+                code += &out;
+            }
+        }
+        Ok((code, map))
+    }
+
+    // Map an assembly file onto itself
+    pub fn for_asm(path: &str, src: &str) -> SourceMap {
+        let counter = Asm::new();
+        let mut map = SourceMap{files: vec![path.to_string()], lines: vec![], funcs: vec![]};
+        let mut addr = 0;
+        for (i, line) in src.lines().enumerate() {
+            let n = count(&counter, line);
+            if n > 0 {
+                map.lines.push(Line{addr, file: 0, line: i + 1, call: false, ret: false});
+                addr += n;
+            }
+        }
+        map
+    }
+
+    // Map a ROM image with one instruction per line, as in a .hack file
+    pub fn for_rom(path: &str, len: usize) -> SourceMap {
+        let lines = (0..len).map(|addr| Line{addr, file: 0, line: addr + 1, call: false, ret: false}).collect();
+        SourceMap{files: vec![path.to_string()], lines, funcs: vec![]}
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    // The ROM addresses of the i'th line's code, given the ROM size
+    pub fn line_range(&self, i: usize, rom_len: usize) -> std::ops::Range<usize> {
+        let end = self.lines.get(i + 1).map_or(rom_len, |l| l.addr);
+        self.lines[i].addr..end.max(self.lines[i].addr)
+    }
+
+    // The line whose code contains pc
+    pub fn loc(&self, pc: usize) -> Option<&Line> {
+        let i = self.lines.partition_point(|l| l.addr <= pc);
+        if i == 0 {
+            None
+        } else {
+            Some(&self.lines[i - 1])
+        }
+    }
+
+    // True if pc is the first instruction of a source line
+    pub fn is_start(&self, pc: usize) -> bool {
+        let i = self.lines.partition_point(|l| l.addr < pc);
+        self.lines.get(i).is_some_and(|l| l.addr == pc)
+    }
+
+    // ROM address of a source line, or of the next line with code.  Also
+    // returns the line actually used.
+    pub fn addr_of(&self, file: usize, line: usize) -> Option<(usize,usize)> {
+        self.lines.iter()
+            .filter(|l| l.file == file && l.line >= line)
+            .min_by_key(|l| l.line)
+            .map(|l| (l.addr, l.line))
+    }
+
+    pub fn func_at(&self, pc: usize) -> Option<&Function> {
+        let i = self.funcs.partition_point(|f| f.addr <= pc);
+        if i == 0 {
+            None
+        } else {
+            Some(&self.funcs[i - 1])
+        }
+    }
+
+    pub fn funcs(&self) -> &[Function] {
+        &self.funcs
+    }
+
+    // (address, name) of each function, for Emul::set_functions
+    pub fn functions(&self) -> Vec<(usize,String)> {
+        self.funcs.iter().map(|f| (f.addr, f.name.clone())).collect()
+    }
+
+    // Index of a file, matched by full path or else by file name
+    pub fn file_index(&self, path: &str) -> Option<usize> {
+        let name = Path::new(path).file_name();
+        self.files.iter().position(|f| f == path)
+            .or_else(|| self.files.iter().position(|f| Path::new(f).file_name() == name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emul::Emul;
+
+    #[test]
+    fn test_translate_map() {
+        let main = "// Main\nfunction Main.main 1\npush constant 3\n\ncall Main.dbl 1\nreturn\n".to_string();
+        let dbl = "function Main.dbl 0\npush argument 0\npush argument 0\nadd\nreturn\n".to_string();
+        let (code, map) = SourceMap::translate(&[("dir/Main.vm".to_string(), main + &dbl)], false).unwrap();
+        let mut em = Emul::new();
+        em.load_code(&code).unwrap();
+        assert_eq!(map.lines.len(), 9);
+        assert_eq!(map.lines[0].addr, 0);
+        assert!(map.is_start(map.lines[3].addr) && !map.is_start(map.lines[3].addr + 1));
+        assert_eq!(map.loc(map.lines[2].addr + 5).map(|l| (l.line, l.call)), Some((5, true)));
+        assert_eq!(map.addr_of(0, 4), Some((map.lines[2].addr, 5)));
+        assert_eq!(em.label_addr("Main.dbl"), Some(map.funcs[1].addr));
+        assert_eq!(map.func_at(map.lines[5].addr + 1).map(|f| f.name.as_str()), Some("Main.dbl"));
+        assert_eq!(map.func_at(map.lines[1].addr).map(|f| f.n_locals), Some(1));
+        assert_eq!(map.file_index("/elsewhere/Main.vm"), Some(0));
+        assert_eq!(map.file_index("Other.vm"), None);
+
+        // The function table comes from the map, not from comments
+        assert!(em.symbols().functions.is_empty());
+        em.set_functions(&map.functions());
+        assert_eq!(em.function_at(map.lines[5].addr).map(|f| f.1), Some("Main.dbl"));
+        em.load_code("// function Fake.f 0\n(Fake.f)\n@1\n").unwrap();
+        assert!(em.symbols().functions.is_empty());
+
+        let asm = SourceMap::for_asm("x.asm", "// x\n@1\n(L)\nD=A\n\n@L\n0;JMP\n");
+        assert_eq!(asm.lines.iter().map(|l| (l.addr, l.line)).collect::<Vec<_>>(),
+                   vec![(0, 2), (1, 4), (2, 6), (3, 7)]);
+    }
+}
@@ -0,0 +1,51 @@
+// Fixtures shared by the unit tests
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::emul::Emul;
+use crate::translator::Translator;
+use crate::types::VMCommand;
+
+// Translate commands as the file Main
+pub fn translate(cmds: &[VMCommand]) -> String {
+    let mut tr = Translator::new("Main");
+    let mut code = String::new();
+    for cmd in cmds {
+        code += &tr.trans_cmd(cmd);
+    }
+    code
+}
+
+// Load translated commands into em, with their functions, and set RAM,
+// usually the pointers.  Tools enabled on em beforehand see the RAM being
+// set.
+pub fn load_vm(em: &mut Emul, cmds: &[VMCommand], ram: &[(usize,i16)]) {
+    em.set_ram(ram);
+    em.load_code(&translate(cmds)).unwrap();
+    let functions = cmds.iter().filter_map(|c| match c {
+        VMCommand::Function(name, _) => em.label_addr(name).map(|addr| (addr, name.clone())),
+        _ => None,
+    }).collect::<Vec<_>>();
+    em.set_functions(&functions);
+}
+
+// An output that can still be read after it is boxed
+#[derive(Clone,Default)]
+pub struct SharedBuf(pub Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuf {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::{Duration,Instant};
+
+use crate::emul::{Emul,Stop};
+
+// Instructions a second and frames a second for interactive programs
+pub const DEFAULT_RATE: u64 = 6_000_000;
+pub const DEFAULT_FPS: u32 = 30;
+// How far behind the clock a run may fall before the backlog is dropped,
+// so a slow host runs slowly rather than in bursts
+const MAX_LAG: u32 = 4;
+
+// Paces a run to a fixed instruction rate, stopping once a frame so that
+// devices can be refreshed.  Ticks are spread evenly over real time, so
+// delay loops such as Sys.wait take the same time on any host.
+pub struct Throttle {
+    rate: u64,
+    frame: Duration,
+    start: Instant,
+    // Ticks at start
+    base: u64,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle{rate: DEFAULT_RATE, frame: Duration::from_secs(1) / DEFAULT_FPS, start: Instant::now(), base: 0}
+    }
+
+    pub fn with_rate(mut self, rate: u64) -> Throttle {
+        self.rate = rate.max(1);
+        self
+    }
+
+    pub fn with_fps(mut self, fps: u32) -> Throttle {
+        self.frame = Duration::from_secs(1) / fps.max(1);
+        self
+    }
+
+    pub fn frame(&self) -> Duration {
+        self.frame
+    }
+
+    // Start counting from now, as after a pause
+    pub fn reset(&mut self, ticks: u64) {
+        self.start = Instant::now();
+        self.base = ticks;
+    }
+
+    // Ticks the program should have reached after elapsed time
+    fn target(&self, elapsed: Duration) -> u64 {
+        self.base + (elapsed.as_nanos() * self.rate as u128 / 1_000_000_000) as u64
+    }
+
+    // Ticks to run now to keep up with the clock.  Beyond MAX_LAG frames
+    // behind, the clock restarts one frame behind the program.
+    pub fn budget(&mut self, ticks: u64) -> u64 {
+        self.budget_at(self.start.elapsed(), ticks)
+    }
+
+    // budget, with elapsed the time since start
+    fn budget_at(&mut self, elapsed: Duration, ticks: u64) -> u64 {
+        let due = self.target(elapsed).saturating_sub(ticks);
+        if due > self.target(self.frame * MAX_LAG) - self.base {
+            self.start += elapsed.saturating_sub(self.frame);
+            self.base = ticks;
+            return self.target(self.frame) - ticks;
+        }
+        due
+    }
+
+    // Time from elapsed to the next frame boundary
+    fn until_frame(&self, elapsed: Duration) -> Duration {
+        let (frame, now) = (self.frame.as_nanos(), elapsed.as_nanos());
+        Duration::from_nanos(((now / frame + 1) * frame - now) as u64)
+    }
+
+    // Sleep until the next frame boundary
+    pub fn wait(&self) {
+        std::thread::sleep(self.until_frame(self.start.elapsed()));
+    }
+
+    // Run at the throttled rate until the program ends, halts or has run
+    // max_ticks instructions, or on_frame returns false.  on_frame is
+    // called once a frame with the emulator.
+    pub fn run(&mut self, em: &mut Emul, max_ticks: u64, mut on_frame: impl FnMut(&mut Emul) -> bool) -> Stop {
+        self.reset(em.ticks());
+        loop {
+            let ticks = self.budget(em.ticks()).min(max_ticks.saturating_sub(em.ticks()));
+            let stop = em.resume(ticks);
+            let cont = on_frame(em);
+            if stop != Stop::TickLimit || em.ticks() >= max_ticks || !cont {
+                return stop;
+            }
+            self.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let ms = Duration::from_millis;
+        let mut t = Throttle::new().with_rate(100_000).with_fps(50);
+        assert_eq!(t.frame(), ms(20));
+        t.reset(1000);
+        assert_eq!(t.target(ms(0)), 1000);
+        assert_eq!(t.target(ms(20)), 3000);
+        assert_eq!(t.budget_at(ms(20), 1000), 2000);
+        assert_eq!(t.budget_at(ms(30), 3000), 1000);
+        // Ahead of the clock there is nothing to run
+        assert_eq!(t.budget_at(ms(30), 5000), 0);
+        assert_eq!(t.until_frame(ms(30)), ms(10));
+        assert_eq!(t.until_frame(ms(40)), ms(20));
+
+        // A host that falls behind loses the backlog rather than catching
+        // up: one frame's worth is due, counted from the program's ticks
+        let start = t.start;
+        assert_eq!(t.budget_at(ms(1000), 4000), 2000);
+        assert_eq!((t.start - start, t.base), (ms(980), 4000));
+        // Time is now counted from the new start
+        assert_eq!(t.budget_at(ms(20), 6000), 0);
+        // Up to MAX_LAG frames behind is caught up
+        assert_eq!(t.budget_at(ms(80), 4000), 8000);
+    }
+
+    #[test]
+    fn test_run() {
+        let mut em = Emul::new();
+        em.load_code("(L)\n@L\nD;JEQ\n").unwrap();
+        let mut t = Throttle::new().with_rate(1_000_000).with_fps(100);
+        let stop = t.run(&mut em, 1000, |_| true);
+        assert_eq!((stop, em.ticks()), (Stop::TickLimit, 1000));
+        let mut frames = 0;
+        let stop = t.run(&mut em, 1_000_000, |_| {
+            frames += 1;
+            false
+        });
+        assert_eq!((stop, frames), (Stop::TickLimit, 1));
+        assert!(em.ticks() < 1_000_000);
+    }
+}
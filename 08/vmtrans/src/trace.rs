@@ -0,0 +1,108 @@
+use std::io::Write;
+use std::ops::Range;
+
+use crate::asm::disasm;
+use crate::emul::{Stop,Symbols};
+use crate::json::{Json,obj};
+use crate::observer::{Observer,Step};
+
+#[derive(Debug,PartialEq,Copy,Clone)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+impl TraceFormat {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<TraceFormat> {
+        match s {
+            "text" => Some(TraceFormat::Text),
+            "jsonl" => Some(TraceFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    pc_range: Option<Range<usize>>,
+    label: Option<String>,
+    syms: Symbols,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        Tracer{out, format, pc_range: None, label: None, syms: Symbols::default()}
+    }
+
+    // Only trace instructions whose ROM address is in range
+    pub fn with_pc_range(mut self, range: Range<usize>) -> Tracer {
+        self.pc_range = Some(range);
+        self
+    }
+
+    // Only trace instructions in the function `label`, or, for a label that
+    // is not a function, in the code block it starts
+    pub fn with_label(mut self, label: &str) -> Tracer {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    fn record(&mut self, s: &Step) {
+        if let Some(ref r) = self.pc_range {
+            if !r.contains(&s.pc) {
+                return;
+            }
+        }
+        let label = self.syms.label_at(s.pc);
+        if let Some(ref l) = self.label {
+            let func = self.syms.function_at(s.pc).map(|(_, f)| f);
+            if func != Some(l.as_str()) && label != Some(l.as_str()) {
+                return;
+            }
+        }
+        let instr = disasm(s.instr);
+        let res = match self.format {
+            TraceFormat::Text => {
+                write!(self.out, "{:>8} {:>5} {:<12} A={:<6} D={}", s.tick, s.pc, instr, s.a, s.d)
+                    .and_then(|_| match s.write {
+                        Some((addr, val)) => writeln!(self.out, " RAM[{}]={}", addr, val),
+                        None => writeln!(self.out),
+                    })
+            },
+            TraceFormat::JsonLines => {
+                let write = match s.write {
+                    Some((addr, val)) => obj(&[("addr", (addr as i64).into()), ("value", (val as i64).into())]),
+                    None => Json::Null,
+                };
+                let entry = obj(&[
+                    ("tick", (s.tick as i64).into()),
+                    ("pc", (s.pc as i64).into()),
+                    ("instr", instr.as_str().into()),
+                    ("a", (s.a as i64).into()),
+                    ("d", (s.d as i64).into()),
+                    ("write", write),
+                    ("label", label.map_or(Json::Null, |l| l.into())),
+                ]);
+                writeln!(self.out, "{}", entry)
+            },
+        };
+        res.expect("Write to trace file failed");
+    }
+}
+
+impl Observer for Tracer {
+    fn set_symbols(&mut self, syms: &Symbols) {
+        self.syms = syms.clone();
+    }
+
+    fn on_exec(&mut self, s: &Step) -> Option<Stop> {
+        self.record(s);
+        None
+    }
+
+    fn flush(&mut self) {
+        self.out.flush().expect("Write to trace file failed");
+    }
+}
@@ -6,17 +6,27 @@ pub struct Translator {
     file_name: String,
     label_num: i32,
     return_num: i32,
+    // The function being translated, which scopes its labels
+    function: Option<String>,
 }
 
 impl Translator {
     pub fn new(fname: &str) -> Translator {
-        Translator{file_name: fname.to_string(), label_num: 0, return_num: 0}
+        Translator{file_name: fname.to_string(), label_num: 0, return_num: 0, function: None}
     }
 
-    pub fn gen_bootstrap() -> String {
-        let mut tr = Translator::new("bootstrap");
+    // Set SP and call Sys.init
+    pub fn gen_bootstrap(&mut self) -> String {
         "@256\nD=A\n@SP\nM=D\n".to_string() +
-            &tr.trans_cmd(&VMCommand::Call("Sys.init".to_string(), 0))
+            &self.trans_cmd(&VMCommand::Call("Sys.init".to_string(), 0))
+    }
+
+    // Go on to the next file of the same program.  Statics are named after
+    // the file, but the label counters carry on, so that comparison and
+    // return labels stay unique across the whole program.
+    pub fn set_file(&mut self, fname: &str) {
+        self.file_name = fname.to_string();
+        self.function = None;
     }
 
     fn get_return_address(&mut self) -> String {
@@ -24,6 +34,15 @@ impl Translator {
         format!("RETURN.{}.{}", self.file_name, self.return_num-1)
     }
 
+    // Labels are Function$label, so that functions can share label names;
+    // code outside any function keeps them bare
+    fn scoped(&self, label: &str) -> String {
+        match self.function {
+            Some(ref f) => format!("{}${}", f, label),
+            None => label.to_string(),
+        }
+    }
+
     pub fn trans_cmd(&mut self, cmd: &VMCommand) -> String {
         let mut r = String::new();
         match cmd {
@@ -44,7 +63,7 @@ impl Translator {
                         r.push_str("@SP\nA=M-1\nM=!M\n"),
                     VMOp::EQ | VMOp::LT | VMOp::GT => {
                         r.push_str("@SP\nAM=M-1\nD=M\n@SP\nAM=M-1\nD=M-D\nM=-1\n");
-                        write!(&mut r, "@TST.{}\n", self.label_num).unwrap();
+                        writeln!(&mut r, "@TST.{}", self.label_num).unwrap();
                         if *op == VMOp::EQ {
                             r.push_str("D;JEQ\n");
                         } else if *op == VMOp::LT {
@@ -125,15 +144,15 @@ impl Translator {
             },
             VMCommand::Label(label_str) => {
                 writeln!(&mut r, "// label {}", label_str).unwrap();
-                writeln!(&mut r, "({})", label_str).unwrap();
+                writeln!(&mut r, "({})", self.scoped(label_str)).unwrap();
             },
             VMCommand::Goto(label_str) => {
                 writeln!(&mut r, "// goto {}", label_str).unwrap();
-                writeln!(&mut r, "@{}\n0;JMP", label_str).unwrap();
+                writeln!(&mut r, "@{}\n0;JMP", self.scoped(label_str)).unwrap();
             },
             VMCommand::IfGoto(label_str) => {
                 writeln!(&mut r, "// if-goto {}", label_str).unwrap();
-                writeln!(&mut r, "@SP\nAM=M-1\nD=M\n@{}\nD;JNE", label_str).unwrap();
+                writeln!(&mut r, "@SP\nAM=M-1\nD=M\n@{}\nD;JNE", self.scoped(label_str)).unwrap();
             },
             VMCommand::Call(label_str, n_args) => {
                 writeln!(&mut r, "// call {} {}", label_str, n_args).unwrap();
@@ -162,6 +181,7 @@ impl Translator {
             },
             VMCommand::Function(label_str, n_locals) => {
                 writeln!(&mut r, "// function {} {}", label_str, n_locals).unwrap();
+                self.function = Some(label_str.clone());
                 // Put the label
                 writeln!(&mut r, "({})", label_str).unwrap();
                 // Zero out the locals
@@ -202,7 +222,7 @@ impl Translator {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::emul::Emul;
+    use crate::emul::{Emul,Stop};
     use crate::asm::Asm;
 
     #[test]
@@ -224,9 +244,9 @@ mod tests {
             let code = tr.trans_cmd(&VMCommand::Arithmetic(op));
             let mut em = Emul::new();
             em.set_ram(&[(0,258), (256, a), (257, b)]);
-            em.run_code(&code, 50).unwrap();
-            assert_eq!(em.ram[0], 257, "SP wrong");
-            assert_eq!(em.ram[256], expected, "Wrong result from operation");
+            assert_eq!(em.run_code(&code, 50), Ok(Stop::End));
+            assert_eq!(em.peek(0), 257, "SP wrong");
+            assert_eq!(em.peek(256), expected, "Wrong result from operation");
         }
     }
 
@@ -242,9 +262,9 @@ mod tests {
             let code = tr.trans_cmd(&VMCommand::Arithmetic(op));
             let mut em = Emul::new();
             em.set_ram(&[(0,257), (256, a)]);
-            em.run_code(&code, 50).unwrap();
-            assert_eq!(em.ram[0], 257, "SP wrong");
-            assert_eq!(em.ram[256], expected, "Wrong result from operation");
+            assert_eq!(em.run_code(&code, 50), Ok(Stop::End));
+            assert_eq!(em.peek(0), 257, "SP wrong");
+            assert_eq!(em.peek(256), expected, "Wrong result from operation");
         }
     }
 
@@ -291,18 +311,18 @@ mod tests {
                    (264, 98),
         ]);
 
-        em.run_code(&code, 100).unwrap();
-        assert_eq!(em.ram[0], 265+table.len() as i16, "SP wrong");
-        assert_eq!(em.ram[265], 33, "Wrong result from push constant 33");
-        assert_eq!(em.ram[266], 77, "Wrong result from push constant 77");
-        assert_eq!(em.ram[267], 97, "Wrong result from push local 0");
-        assert_eq!(em.ram[268], 98, "Wrong result from push local 1");
-        assert_eq!(em.ram[269], 17, "Wrong result from push argument 0");
-        assert_eq!(em.ram[270], 18, "Wrong result from push argument 1");
-        assert_eq!(em.ram[271], -3, "Wrong result from push pointer 0");
-        assert_eq!(em.ram[272], -4, "Wrong result from push pointer 1");
-        assert_eq!(em.ram[273], -5, "Wrong result from push temp 0");
-        //assert_eq!(em.ram[270], -6, "Wrong result from static 9");
+        assert_eq!(em.run_code(&code, 100), Ok(Stop::End));
+        assert_eq!(em.peek(0), 265+table.len() as i16, "SP wrong");
+        assert_eq!(em.peek(265), 33, "Wrong result from push constant 33");
+        assert_eq!(em.peek(266), 77, "Wrong result from push constant 77");
+        assert_eq!(em.peek(267), 97, "Wrong result from push local 0");
+        assert_eq!(em.peek(268), 98, "Wrong result from push local 1");
+        assert_eq!(em.peek(269), 17, "Wrong result from push argument 0");
+        assert_eq!(em.peek(270), 18, "Wrong result from push argument 1");
+        assert_eq!(em.peek(271), -3, "Wrong result from push pointer 0");
+        assert_eq!(em.peek(272), -4, "Wrong result from push pointer 1");
+        assert_eq!(em.peek(273), -5, "Wrong result from push temp 0");
+        //assert_eq!(em.peek(270), -6, "Wrong result from static 9");
     }
 
     #[test]
@@ -355,16 +375,16 @@ mod tests {
 
         ]);
 
-        em.run_code(&code, 100).unwrap();
-        assert_eq!(em.ram[0], 265, "SP wrong");
-        assert_eq!(em.ram[256], -1, "Wrong result from pop argument 0");
-        assert_eq!(em.ram[257], -2, "Wrong result from pop argument 1");
-        assert_eq!(em.ram[262], -3, "Wrong result from pop argument 0");
-        assert_eq!(em.ram[263], -4, "Wrong result from pop argument 1");
-        assert_eq!(em.ram[3], -5, "Wrong result from pop pointer 0");
-        assert_eq!(em.ram[4], -6, "Wrong result from pop pointer 1");
-        assert_eq!(em.ram[6], -7, "Wrong result from pop temp 1");
-        //assert_eq!(em.ram[270], -6, "Wrong result from static 9");
+        assert_eq!(em.run_code(&code, 100), Ok(Stop::End));
+        assert_eq!(em.peek(0), 265, "SP wrong");
+        assert_eq!(em.peek(256), -1, "Wrong result from pop argument 0");
+        assert_eq!(em.peek(257), -2, "Wrong result from pop argument 1");
+        assert_eq!(em.peek(262), -3, "Wrong result from pop argument 0");
+        assert_eq!(em.peek(263), -4, "Wrong result from pop argument 1");
+        assert_eq!(em.peek(3), -5, "Wrong result from pop pointer 0");
+        assert_eq!(em.peek(4), -6, "Wrong result from pop pointer 1");
+        assert_eq!(em.peek(6), -7, "Wrong result from pop temp 1");
+        //assert_eq!(em.peek(270), -6, "Wrong result from static 9");
     }
 
     #[test]
@@ -382,6 +402,100 @@ mod tests {
         assert_eq!(tr.trans_cmd(&VMCommand::Label("foo".to_string())), "// label foo\n(foo)\n");
         assert_eq!(tr.trans_cmd(&VMCommand::Goto("foo".to_string())), "// goto foo\n@foo\n0;JMP\n");
         assert_eq!(tr.trans_cmd(&VMCommand::IfGoto("foo".to_string())), "// if-goto foo\n@SP\nAM=M-1\nD=M\n@foo\nD;JNE\n");
+
+        tr.trans_cmd(&VMCommand::Function("Splat.f".to_string(), 0));
+        assert_eq!(tr.trans_cmd(&VMCommand::Label("foo".to_string())), "// label foo\n(Splat.f$foo)\n");
+        assert_eq!(tr.trans_cmd(&VMCommand::Goto("foo".to_string())), "// goto foo\n@Splat.f$foo\n0;JMP\n");
+        assert!(tr.trans_cmd(&VMCommand::IfGoto("foo".to_string())).contains("@Splat.f$foo\nD;JNE\n"));
+    }
+
+    #[test]
+    fn trans_program_labels_test() {
+        /* Files translated as one program, two of them with the same stem,
+         * each comparing with eq and calling A.one.  Their TST and RETURN
+         * labels must not collide.
+         * */
+        let mut tr = Translator::new("bootstrap");
+        let mut code = String::new();
+        for cmd in &[VMCommand::Call("A.f".to_string(), 0), VMCommand::Call("B.f".to_string(), 0),
+                     VMCommand::Call("C.f".to_string(), 0),
+                     VMCommand::Label("END".to_string()), VMCommand::Goto("END".to_string())] {
+            code += &tr.trans_cmd(cmd);
+        }
+        for (file, name, x) in &[("A", "A.f", 3), ("Dup", "B.f", 4), ("Dup", "C.f", 5)] {
+            tr.set_file(file);
+            for cmd in &[VMCommand::Function(name.to_string(), 0),
+                         VMCommand::Push(VMSeg::CONSTANT, *x),
+                         VMCommand::Push(VMSeg::CONSTANT, 4),
+                         VMCommand::Arithmetic(VMOp::EQ),
+                         VMCommand::Call("A.one".to_string(), 0),
+                         VMCommand::Arithmetic(VMOp::ADD),
+                         VMCommand::Return] {
+                code += &tr.trans_cmd(cmd);
+            }
+        }
+        tr.set_file("A");
+        for cmd in &[VMCommand::Function("A.one".to_string(), 0), VMCommand::Push(VMSeg::CONSTANT, 1),
+                     VMCommand::Return] {
+            code += &tr.trans_cmd(cmd);
+        }
+        let labels = code.lines().filter(|l| l.starts_with('(')).collect::<Vec<_>>();
+        let mut unique = labels.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(labels.len(), unique.len(), "Duplicate labels");
+        let dup_returns = labels.iter().filter(|l| l.starts_with("(RETURN.Dup.")).collect::<Vec<_>>();
+        assert_eq!(dup_returns.len(), 2);
+        assert_ne!(dup_returns[0], dup_returns[1]);
+
+        let mut em = Emul::new();
+        em.set_ram(&[(0, 256)]);
+        assert_eq!(em.run_code(&code, 10000), Ok(Stop::Halted));
+        assert_eq!(em.peek(0), 259, "SP wrong");
+        assert_eq!((em.peek(256), em.peek(257), em.peek(258)), (1, 0, 1), "Results wrong");
+    }
+
+    #[test]
+    fn trans_scoped_labels_test() {
+        /* Two functions using the same label name, as the Jack compiler
+         * does, each loop within their own body.
+         * */
+        let mut table = vec![
+            VMCommand::Call("COUNT".to_string(), 0),
+            VMCommand::Call("TWICE".to_string(), 0),
+            VMCommand::Label("END".to_string()),
+            VMCommand::Goto("END".to_string()),
+        ];
+        for (name, step) in &[("COUNT", 1), ("TWICE", 2)] {
+            table.extend(vec![
+                VMCommand::Function(name.to_string(), 1),
+                VMCommand::Label("LOOP".to_string()),
+                VMCommand::Push(VMSeg::LOCAL, 0),
+                VMCommand::Push(VMSeg::CONSTANT, *step),
+                VMCommand::Arithmetic(VMOp::ADD),
+                VMCommand::Pop(VMSeg::LOCAL, 0),
+                VMCommand::Push(VMSeg::LOCAL, 0),
+                VMCommand::Push(VMSeg::CONSTANT, 6),
+                VMCommand::Arithmetic(VMOp::LT),
+                VMCommand::IfGoto("LOOP".to_string()),
+                VMCommand::Push(VMSeg::LOCAL, 0),
+                VMCommand::Return,
+            ]);
+        }
+
+        let mut tr = Translator::new("Foo");
+        let mut code = String::new();
+        for cmd in &table {
+            code += &tr.trans_cmd(cmd);
+        }
+        assert!(code.contains("(COUNT$LOOP)") && code.contains("(TWICE$LOOP)"));
+
+        let mut em = Emul::new();
+        em.set_ram(&[(0, 256)]);
+        assert_eq!(em.run_code(&code, 10000), Ok(Stop::Halted));
+        assert_eq!(em.peek(0), 258, "SP wrong");
+        assert_eq!(em.peek(256), 6, "Result 0 wrong");
+        assert_eq!(em.peek(257), 6, "Result 1 wrong");
     }
 
     #[test]
@@ -403,18 +517,18 @@ mod tests {
                    (257, 22),
         ]);
 
-        em.run_code(&code, 100).unwrap();
-        assert_eq!(em.ram[0], 263, "SP wrong");
-        assert_eq!(em.ram[1], 263, "LCL wrong");
-        assert_eq!(em.ram[2], 256, "ARG wrong");
-        assert_eq!(em.ram[256], 11, "Argument 0 wrong");
-        assert_eq!(em.ram[257], 22, "Argument 1 wrong");
-        assert_eq!(em.ram[258], 47, "RA incorrect");
-        assert_eq!(em.ram[259], -1, "SavedLCL incorrect");
-        assert_eq!(em.ram[260], -2, "SavedARG incorrect");
-        assert_eq!(em.ram[261], -3, "SavedThis incorrect");
-        assert_eq!(em.ram[262], -4, "SavedThat incorrect");
-        //assert_eq!(em.ram[270], -6, "Wrong result from static 9");
+        assert_eq!(em.run_code(&code, 100), Ok(Stop::End));
+        assert_eq!(em.peek(0), 263, "SP wrong");
+        assert_eq!(em.peek(1), 263, "LCL wrong");
+        assert_eq!(em.peek(2), 256, "ARG wrong");
+        assert_eq!(em.peek(256), 11, "Argument 0 wrong");
+        assert_eq!(em.peek(257), 22, "Argument 1 wrong");
+        assert_eq!(em.peek(258), 47, "RA incorrect");
+        assert_eq!(em.peek(259), -1, "SavedLCL incorrect");
+        assert_eq!(em.peek(260), -2, "SavedARG incorrect");
+        assert_eq!(em.peek(261), -3, "SavedThis incorrect");
+        assert_eq!(em.peek(262), -4, "SavedThat incorrect");
+        //assert_eq!(em.peek(270), -6, "Wrong result from static 9");
     }
     
     #[test]
@@ -438,19 +552,19 @@ mod tests {
                    (262, -4),
         ]);
 
-        em.run_code(&code, 100).unwrap();
-        assert_eq!(em.ram[0], 265, "SP wrong");
-        assert_eq!(em.ram[1], 263, "LCL wrong");
-        assert_eq!(em.ram[2], 256, "ARG wrong");
-        assert_eq!(em.ram[256], 11, "Argument 0 wrong");
-        assert_eq!(em.ram[257], 22, "Argument 1 wrong");
-        assert_eq!(em.ram[258], 47, "RA incorrect");
-        assert_eq!(em.ram[259], -1, "SavedLCL incorrect");
-        assert_eq!(em.ram[260], -2, "SavedARG incorrect");
-        assert_eq!(em.ram[261], -3, "SavedThis incorrect");
-        assert_eq!(em.ram[262], -4, "SavedThat incorrect");
-        assert_eq!(em.ram[263], 0, "Arg0 incorrect");
-        assert_eq!(em.ram[264], 0, "Arg1 incorrect");
+        assert_eq!(em.run_code(&code, 100), Ok(Stop::End));
+        assert_eq!(em.peek(0), 265, "SP wrong");
+        assert_eq!(em.peek(1), 263, "LCL wrong");
+        assert_eq!(em.peek(2), 256, "ARG wrong");
+        assert_eq!(em.peek(256), 11, "Argument 0 wrong");
+        assert_eq!(em.peek(257), 22, "Argument 1 wrong");
+        assert_eq!(em.peek(258), 47, "RA incorrect");
+        assert_eq!(em.peek(259), -1, "SavedLCL incorrect");
+        assert_eq!(em.peek(260), -2, "SavedARG incorrect");
+        assert_eq!(em.peek(261), -3, "SavedThis incorrect");
+        assert_eq!(em.peek(262), -4, "SavedThat incorrect");
+        assert_eq!(em.peek(263), 0, "Arg0 incorrect");
+        assert_eq!(em.peek(264), 0, "Arg1 incorrect");
     }
 
     #[test]
@@ -483,13 +597,13 @@ mod tests {
         ]);
 
 
-        em.run(cmds, 100);
-        assert_eq!(em.ram[0], 257, "SP wrong");
-        assert_eq!(em.ram[1], -1, "LCL wrong");
-        assert_eq!(em.ram[2], -2, "ARG wrong");
-        assert_eq!(em.ram[3], -3, "THIS wrong");
-        assert_eq!(em.ram[4], -4, "THAT wrong");
-        assert_eq!(em.ram[256], 99, "Return val wrong");
+        assert_eq!(em.run(cmds, 100), Ok(Stop::End));
+        assert_eq!(em.peek(0), 257, "SP wrong");
+        assert_eq!(em.peek(1), -1, "LCL wrong");
+        assert_eq!(em.peek(2), -2, "ARG wrong");
+        assert_eq!(em.peek(3), -3, "THIS wrong");
+        assert_eq!(em.peek(4), -4, "THAT wrong");
+        assert_eq!(em.peek(256), 99, "Return val wrong");
     }
 
     #[test]
@@ -502,6 +616,7 @@ mod tests {
             VMCommand::Push(VMSeg::CONSTANT, 3),
             VMCommand::Push(VMSeg::CONSTANT, 7),
             VMCommand::Call("ADD".to_string(), 2),
+            VMCommand::Label("END".to_string()),
             VMCommand::Goto("END".to_string()),
             VMCommand::Function("ADD".to_string(), 1),
             VMCommand::Push(VMSeg::ARGUMENT, 0),
@@ -510,7 +625,6 @@ mod tests {
             VMCommand::Pop(VMSeg::LOCAL, 0),
             VMCommand::Push(VMSeg::LOCAL, 0),
             VMCommand::Return,
-            VMCommand::Label("END".to_string()),
         ];
 
         let mut tr = Translator::new("Foo");
@@ -529,13 +643,13 @@ mod tests {
                    (4, -4),
         ]);
 
-        em.run_code(&code, 1000).unwrap();
-        assert_eq!(em.ram[0], 257, "SP wrong");
-        assert_eq!(em.ram[1], -1, "LCL wrong");
-        assert_eq!(em.ram[2], -2, "LCL wrong");
-        assert_eq!(em.ram[3], -3, "LCL wrong");
-        assert_eq!(em.ram[4], -4, "LCL wrong");
-        assert_eq!(em.ram[256], 10, "Result wrong");
+        assert_eq!(em.run_code(&code, 1000), Ok(Stop::Halted));
+        assert_eq!(em.peek(0), 257, "SP wrong");
+        assert_eq!(em.peek(1), -1, "LCL wrong");
+        assert_eq!(em.peek(2), -2, "LCL wrong");
+        assert_eq!(em.peek(3), -3, "LCL wrong");
+        assert_eq!(em.peek(4), -4, "LCL wrong");
+        assert_eq!(em.peek(256), 10, "Result wrong");
     }
     
     #[test]
@@ -548,6 +662,7 @@ mod tests {
             VMCommand::Push(VMSeg::CONSTANT, 3),
             VMCommand::Push(VMSeg::CONSTANT, 7),
             VMCommand::Call("ADD".to_string(), 2),
+            VMCommand::Label("END".to_string()),
             VMCommand::Goto("END".to_string()),
             VMCommand::Function("ADD".to_string(), 1),
             VMCommand::Push(VMSeg::ARGUMENT, 0),
@@ -565,7 +680,6 @@ mod tests {
             VMCommand::Pop(VMSeg::LOCAL, 0),
             VMCommand::Push(VMSeg::LOCAL, 0),
             VMCommand::Return,
-            VMCommand::Label("END".to_string()),
         ];
 
         let mut tr = Translator::new("Foo");
@@ -583,13 +697,13 @@ mod tests {
                    (4, -4),
         ]);
 
-        em.run_code(&code, 1000).unwrap();
-        assert_eq!(em.ram[0], 257, "SP wrong");
-        assert_eq!(em.ram[1], -1, "LCL wrong");
-        assert_eq!(em.ram[2], -2, "LCL wrong");
-        assert_eq!(em.ram[3], -3, "LCL wrong");
-        assert_eq!(em.ram[4], -4, "LCL wrong");
-        assert_eq!(em.ram[256], 1, "Result wrong");
+        assert_eq!(em.run_code(&code, 1000), Ok(Stop::Halted));
+        assert_eq!(em.peek(0), 257, "SP wrong");
+        assert_eq!(em.peek(1), -1, "LCL wrong");
+        assert_eq!(em.peek(2), -2, "LCL wrong");
+        assert_eq!(em.peek(3), -3, "LCL wrong");
+        assert_eq!(em.peek(4), -4, "LCL wrong");
+        assert_eq!(em.peek(256), 1, "Result wrong");
     }
     
     #[test]
@@ -600,11 +714,11 @@ mod tests {
          * */
         let table = vec![
             VMCommand::Call("NOTHING".to_string(), 0),
+            VMCommand::Label("END".to_string()),
             VMCommand::Goto("END".to_string()),
             VMCommand::Function("NOTHING".to_string(), 1),
             VMCommand::Push(VMSeg::CONSTANT, 77),
             VMCommand::Return,
-            VMCommand::Label("END".to_string()),
         ];
 
         let mut tr = Translator::new("Foo");
@@ -619,9 +733,9 @@ mod tests {
                    (0, 256),
         ]);
 
-        em.run_code(&code, 1000).unwrap();
-        assert_eq!(em.ram[0], 257, "SP wrong");
-        assert_eq!(em.ram[256], 77, "Result wrong");
+        assert_eq!(em.run_code(&code, 1000), Ok(Stop::Halted));
+        assert_eq!(em.peek(0), 257, "SP wrong");
+        assert_eq!(em.peek(256), 77, "Result wrong");
     }
 }
 
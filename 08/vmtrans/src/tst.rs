@@ -0,0 +1,868 @@
+use std::fmt;
+use std::fs::{read_to_string,write};
+use std::path::{Path,PathBuf};
+use std::time::Instant;
+
+use crate::cpu::{Cpu,Pins};
+use crate::emul::Emul;
+use crate::loader::{LoadError,load_error};
+
+// Something that output-list can show
+#[derive(Debug,PartialEq,Clone)]
+pub enum Item {
+    Ram(usize),
+    A,
+    D,
+    PC,
+    Time,
+    // CPU chip pins
+    InM,
+    Instruction,
+    Reset,
+    OutM,
+    WriteM,
+    AddressM,
+    Pc,
+    DRegister,
+}
+
+impl Item {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<Item> {
+        match s {
+            "A" => Some(Item::A),
+            "D" => Some(Item::D),
+            "PC" => Some(Item::PC),
+            "time" => Some(Item::Time),
+            "inM" => Some(Item::InM),
+            "instruction" => Some(Item::Instruction),
+            "reset" => Some(Item::Reset),
+            "outM" => Some(Item::OutM),
+            "writeM" => Some(Item::WriteM),
+            "addressM" => Some(Item::AddressM),
+            "pc" => Some(Item::Pc),
+            "DRegister[]" => Some(Item::DRegister),
+            _ => s.strip_prefix("RAM[")
+                .and_then(|r| r.strip_suffix(']'))
+                .and_then(|n| n.parse().ok())
+                .map(Item::Ram),
+        }
+    }
+}
+
+// One output-list entry, e.g. RAM[256]%D1.6.1: a format letter, then the
+// padding to the left, the width of the value and the padding to the right.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Column {
+    pub name: String,
+    pub fmt: char,
+    pub left: usize,
+    pub width: usize,
+    pub right: usize,
+}
+
+impl Column {
+    pub fn parse(s: &str) -> Option<Column> {
+        let (name, spec) = match s.find('%') {
+            Some(i) => (&s[..i], &s[i+1..]),
+            None => (s, "D1.6.1"),
+        };
+        let fmt = spec.chars().next()?;
+        if !"DXBS".contains(fmt) {
+            return None;
+        }
+        let ns = spec[1..].split('.').map(|n| n.parse().ok()).collect::<Option<Vec<usize>>>()?;
+        if ns.len() != 3 || name.is_empty() {
+            return None;
+        }
+        Some(Column{name: name.to_string(), fmt, left: ns[0], width: ns[1], right: ns[2]})
+    }
+
+    fn total(&self) -> usize {
+        self.left + self.width + self.right
+    }
+
+    // The name, cut to the column width and centred
+    pub fn header(&self) -> String {
+        let name = self.name.chars().take(self.total()).collect::<String>();
+        let lp = (self.total() - name.len()) / 2;
+        format!("{:lp$}{:<rest$}", "", name, lp = lp, rest = self.total() - lp)
+    }
+
+    pub fn num(&self, v: i16) -> String {
+        let s = match self.fmt {
+            'B' => format!("{:016b}", v as u16),
+            'X' => format!("{:04X}", v as u16),
+            _ => v.to_string(),
+        };
+        let s = if self.fmt == 'B' || self.fmt == 'X' {
+            s[s.len().saturating_sub(self.width)..].to_string()
+        } else {
+            s
+        };
+        self.cell(&s)
+    }
+
+    // An unspecified value, like outM when writeM is 0
+    pub fn stars(&self) -> String {
+        "*".repeat(self.total())
+    }
+
+    pub fn cell(&self, s: &str) -> String {
+        if self.fmt == 'S' {
+            format!("{:l$}{:<w$}{:r$}", "", s, "", l = self.left, w = self.width, r = self.right)
+        } else {
+            format!("{:l$}{:>w$}{:r$}", "", s, "", l = self.left, w = self.width, r = self.right)
+        }
+    }
+}
+
+// "|a|b|c|"
+pub fn output_line(cells: &[String]) -> String {
+    format!("|{}|", cells.join("|"))
+}
+
+#[derive(Debug,PartialEq,Clone)]
+enum Cmd {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<(Column,Item)>),
+    Set(Item, i16),
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    Echo(String),
+    ClearEcho,
+    Repeat(usize, Vec<(usize,Cmd)>),
+}
+
+enum Tok {
+    Cmd(usize, String),
+    Open(usize, String),
+    Close(usize),
+}
+
+// Split a script into commands, dropping comments.  Commands end with ','
+// or ';' and blocks are "repeat N { ... }".
+fn tokenize(src: &str) -> Vec<Tok> {
+    let mut toks = vec![];
+    let mut cur = String::new();
+    let mut cur_line = 1;
+    let mut line = 1;
+    let mut quoted = false;
+    let cs = src.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    let flush = |toks: &mut Vec<Tok>, cur: &mut String, cur_line: usize| {
+        if !cur.trim().is_empty() {
+            toks.push(Tok::Cmd(cur_line, cur.trim().to_string()));
+        }
+        cur.clear();
+    };
+    while i < cs.len() {
+        let c = cs[i];
+        let next = cs.get(i + 1).cloned();
+        if c == '\n' {
+            line += 1;
+        }
+        if quoted {
+            quoted = c != '"';
+            cur.push(c);
+        } else if c == '/' && next == Some('/') {
+            while i < cs.len() && cs[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < cs.len() && !(cs[i] == '*' && cs.get(i + 1) == Some(&'/')) {
+                if cs[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        } else if c == ',' || c == ';' {
+            flush(&mut toks, &mut cur, cur_line);
+        } else if c == '{' {
+            toks.push(Tok::Open(cur_line, cur.trim().to_string()));
+            cur.clear();
+        } else if c == '}' {
+            flush(&mut toks, &mut cur, cur_line);
+            toks.push(Tok::Close(line));
+        } else {
+            if cur.trim().is_empty() {
+                cur_line = line;
+            }
+            quoted = c == '"';
+            cur.push(c);
+        }
+        i += 1;
+    }
+    flush(&mut toks, &mut cur, cur_line);
+    toks
+}
+
+fn parse_value(s: &str) -> Option<i16> {
+    if let Some(b) = s.strip_prefix("%B") {
+        u16::from_str_radix(b, 2).ok().map(|v| v as i16)
+    } else if let Some(x) = s.strip_prefix("%X") {
+        u16::from_str_radix(x, 16).ok().map(|v| v as i16)
+    } else {
+        s.strip_prefix("%D").unwrap_or(s).parse().ok()
+    }
+}
+
+fn parse_cmd(line: usize, s: &str) -> Result<Cmd, LoadError> {
+    let ws: Vec<&str> = s.split_whitespace().collect();
+    let bad = || load_error(line, &format!("Invalid command: {}", s));
+    let cmd = match (ws[0], ws.len()) {
+        ("load", 2) => Cmd::Load(ws[1].to_string()),
+        ("output-file", 2) => Cmd::OutputFile(ws[1].to_string()),
+        ("compare-to", 2) => Cmd::CompareTo(ws[1].to_string()),
+        ("output-list", n) if n >= 2 => Cmd::OutputList(ws[1..].iter()
+            .map(|w| {
+                let col = Column::parse(w)?;
+                let item = Item::from_str(&col.name)?;
+                Some((col, item))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(bad)?),
+        ("set", 3) => match (Item::from_str(ws[1]), parse_value(ws[2])) {
+            (Some(item), Some(v)) if !matches!(item, Item::Time | Item::OutM | Item::WriteM | Item::AddressM) => Cmd::Set(item, v),
+            _ => return Err(bad()),
+        },
+        ("tick", 1) => Cmd::Tick,
+        ("tock", 1) => Cmd::Tock,
+        ("ticktock", 1) => Cmd::TickTock,
+        ("output", 1) => Cmd::Output,
+        ("clear-echo", 1) => Cmd::ClearEcho,
+        ("echo", _) => Cmd::Echo(s[4..].trim().trim_matches('"').to_string()),
+        _ => return Err(bad()),
+    };
+    Ok(cmd)
+}
+
+fn parse_block(toks: &[Tok], i: &mut usize, nested: bool) -> Result<Vec<(usize,Cmd)>, LoadError> {
+    let mut r = vec![];
+    while *i < toks.len() {
+        *i += 1;
+        match &toks[*i - 1] {
+            Tok::Cmd(line, s) => r.push((*line, parse_cmd(*line, s)?)),
+            Tok::Open(line, s) => {
+                let ws: Vec<&str> = s.split_whitespace().collect();
+                let n = match ws.as_slice() {
+                    ["repeat", n] => n.parse().map_err(|_| load_error(*line, "Invalid repeat count"))?,
+                    _ => return Err(load_error(*line, &format!("Unsupported block: {}", s))),
+                };
+                r.push((*line, Cmd::Repeat(n, parse_block(toks, i, true)?)));
+            },
+            Tok::Close(line) => {
+                if nested {
+                    return Ok(r);
+                }
+                return Err(load_error(*line, "Unexpected }"));
+            },
+        }
+    }
+    if nested {
+        return Err(load_error(0, "Missing }"));
+    }
+    Ok(r)
+}
+
+#[derive(Debug,PartialEq)]
+pub struct Mismatch {
+    pub line: usize,
+    pub expected: String,
+    pub got: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Comparison failure at line {}\nexpected: {}\n     got: {}", self.line, self.expected, self.got)
+    }
+}
+
+// Compare output with a .cmp file line by line, skipping blank lines on
+// both sides and ignoring whitespace at either end of a line.
+pub fn compare(out: &str, cmp: &str) -> Option<Mismatch> {
+    let outs = out.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+    let cmps = cmp.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+    for i in 0..outs.len().max(cmps.len()) {
+        let got = outs.get(i).cloned().unwrap_or("");
+        let expected = cmps.get(i).cloned().unwrap_or("");
+        if got.trim() != expected.trim() {
+            return Some(Mismatch{line: i + 1, expected: expected.to_string(), got: got.to_string()});
+        }
+    }
+    None
+}
+
+// A budget that stopped a script before its end
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Limit {
+    Ticks,
+    Time,
+}
+
+pub struct TestRun {
+    pub output: String,
+    pub echo: String,
+    pub compared: bool,
+    pub failure: Option<Mismatch>,
+    pub limit: Option<Limit>,
+    // Clock cycles run
+    pub ticks: u64,
+}
+
+// A CPUEmulator test script (.tst)
+pub struct TestScript {
+    cmds: Vec<(usize,Cmd)>,
+}
+
+// The CPU chip alone, for "load CPU.hdl" scripts
+struct CpuTest {
+    cpu: Cpu,
+    instruction: u16,
+    in_m: i16,
+    reset: bool,
+    plus: Option<Pins>,
+}
+
+struct Runner<'a> {
+    em: Emul,
+    cpu: Option<CpuTest>,
+    dir: &'a Path,
+    columns: Vec<(Column,Item)>,
+    output: String,
+    echo: String,
+    out_file: Option<PathBuf>,
+    cmp_file: Option<PathBuf>,
+    half: bool,
+    time: u64,
+    max_ticks: u64,
+    deadline: Option<Instant>,
+    limit: Option<Limit>,
+}
+
+impl<'a> Runner<'a> {
+    // Note a spent budget, checking the clock every 4096 ticks
+    fn over_limit(&mut self) -> bool {
+        if self.time > self.max_ticks {
+            self.limit = Some(Limit::Ticks);
+        } else if self.time.is_multiple_of(4096) && self.deadline.is_some_and(|d| Instant::now() > d) {
+            self.limit = Some(Limit::Time);
+        }
+        self.limit.is_some()
+    }
+
+    fn pins(&self) -> Pins {
+        match self.cpu {
+            Some(ref t) => t.plus.unwrap_or_else(|| t.cpu.pins(t.instruction, t.in_m, t.reset)),
+            None => {
+                let cpu = Cpu{a: self.em.a, d: self.em.d, pc: self.em.pc()};
+                let instr = self.em.rom().get(self.em.pc()).cloned().unwrap_or(0);
+                cpu.pins(instr, self.em.bus.read(self.em.a as u16 as usize).unwrap_or(0), false)
+            },
+        }
+    }
+
+    fn value(&self, item: &Item) -> i16 {
+        let p = self.pins();
+        match item {
+            Item::Ram(addr) => self.em.bus.read(*addr).unwrap_or(0),
+            Item::A => self.cpu.as_ref().map_or(self.em.a, |t| t.cpu.a),
+            Item::D | Item::DRegister => p.d,
+            Item::PC | Item::Pc => p.pc as i16,
+            Item::Time => self.time as i16,
+            Item::InM => p.in_m,
+            Item::Instruction => p.instruction as i16,
+            Item::Reset => p.reset as i16,
+            Item::OutM => p.out_m,
+            Item::WriteM => p.write_m as i16,
+            Item::AddressM => p.address_m,
+        }
+    }
+
+    fn tick(&mut self) {
+        if let Some(ref mut t) = self.cpu {
+            t.plus = Some(t.cpu.cycle(t.instruction, t.in_m, t.reset).0);
+        }
+        self.half = true;
+    }
+
+    // One clock cycle.  Past the end of the program ROM holds zeros, i.e. @0.
+    fn tock(&mut self) {
+        if let Some(ref mut t) = self.cpu {
+            if t.plus.take().is_none() {
+                t.cpu.cycle(t.instruction, t.in_m, t.reset);
+            }
+        } else if self.em.pc() < self.em.rom().len() {
+            self.em.step();
+        } else {
+            self.em.a = 0;
+            self.em.set_pc(self.em.pc() + 1);
+        }
+        self.half = false;
+        self.time += 1;
+    }
+
+    fn set(&mut self, line: usize, item: &Item, v: i16) -> Result<(), LoadError> {
+        if let Some(ref mut t) = self.cpu {
+            match item {
+                Item::InM => t.in_m = v,
+                Item::Instruction => t.instruction = v as u16,
+                Item::Reset => t.reset = v != 0,
+                Item::A => t.cpu.a = v,
+                Item::D | Item::DRegister => t.cpu.d = v,
+                Item::PC | Item::Pc => t.cpu.pc = v as u16 as usize,
+                _ => return Err(load_error(line, "Can't set that on the CPU chip")),
+            }
+            return Ok(());
+        }
+        match item {
+            Item::Ram(addr) => {
+                self.em.bus.write(*addr, v).map_err(|e| load_error(line, &e.to_string()))?;
+            },
+            Item::A => self.em.a = v,
+            Item::D => self.em.d = v,
+            Item::PC => self.em.set_pc(v as u16 as usize),
+            _ => return Err(load_error(line, "Pins can only be set after load CPU.hdl")),
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, line: usize, name: &str) -> Result<(), LoadError> {
+        if name.ends_with(".hdl") {
+            if name != "CPU.hdl" {
+                return Err(load_error(line, "Only CPU.hdl is built in"));
+            }
+            self.cpu = Some(CpuTest{cpu: Cpu::default(), instruction: 0, in_m: 0, reset: false, plus: None});
+            return Ok(());
+        }
+        let src = read_to_string(self.dir.join(name))
+            .map_err(|e| load_error(line, &format!("{}: {}", name, e)))?;
+        if name.ends_with(".hack") {
+            self.em.load_hack(&src)?;
+        } else {
+            self.em.load_code(&src).map_err(|e| load_error(line, &e))?;
+        }
+        self.em.set_pc(0);
+        Ok(())
+    }
+
+    fn exec(&mut self, cmds: &[(usize,Cmd)]) -> Result<(), LoadError> {
+        for (line, cmd) in cmds {
+            match cmd {
+                Cmd::Load(name) => self.load(*line, name)?,
+                Cmd::OutputFile(name) => self.out_file = Some(self.dir.join(name)),
+                Cmd::CompareTo(name) => self.cmp_file = Some(self.dir.join(name)),
+                Cmd::OutputList(cols) => {
+                    self.columns = cols.clone();
+                    let hs = cols.iter().map(|(c, _)| c.header()).collect::<Vec<_>>();
+                    self.output += &output_line(&hs);
+                    self.output.push('\n');
+                },
+                Cmd::Set(item, v) => self.set(*line, item, *v)?,
+                Cmd::Tick => self.tick(),
+                Cmd::Tock | Cmd::TickTock => {
+                    if *cmd == Cmd::TickTock {
+                        self.tick();
+                    }
+                    self.tock();
+                    if let Some(f) = self.em.fault() {
+                        return Err(load_error(*line, f));
+                    }
+                    if self.over_limit() {
+                        return Err(load_error(*line, "Out of ticks or time"));
+                    }
+                },
+                Cmd::Output => {
+                    let cells = self.columns.iter().map(|(c, item)| match item {
+                        Item::Time => c.cell(&format!("{}{}", self.time, if self.half { "+" } else { "" })),
+                        Item::OutM if !self.pins().write_m => c.stars(),
+                        _ => c.num(self.value(item)),
+                    }).collect::<Vec<_>>();
+                    self.output += &output_line(&cells);
+                    self.output.push('\n');
+                },
+                Cmd::Echo(s) => self.echo = s.clone(),
+                Cmd::ClearEcho => self.echo.clear(),
+                Cmd::Repeat(n, body) => {
+                    for _ in 0..*n {
+                        self.exec(body)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TestScript {
+    pub fn parse(src: &str) -> Result<TestScript, LoadError> {
+        let toks = tokenize(src);
+        let mut i = 0;
+        Ok(TestScript{cmds: parse_block(&toks, &mut i, false)?})
+    }
+
+    // Run against a fresh machine.  Files named in the script are relative
+    // to dir.  Writes the output-file, if any, and checks it against the
+    // compare-to file.
+    pub fn run(&self, dir: &Path) -> Result<TestRun, LoadError> {
+        self.run_limited(dir, u64::MAX, None)
+    }
+
+    // As run, but give up after max_ticks clock cycles or at the deadline,
+    // returning the output so far with limit set
+    pub fn run_limited(&self, dir: &Path, max_ticks: u64, deadline: Option<Instant>) -> Result<TestRun, LoadError> {
+        let mut r = Runner{em: Emul::new(), cpu: None, dir, columns: vec![], output: String::new(), echo: String::new(),
+                           out_file: None, cmp_file: None, half: false, time: 0, max_ticks, deadline, limit: None};
+        match r.exec(&self.cmds) {
+            Err(_) if r.limit.is_some() => {
+                return Ok(TestRun{output: r.output, echo: r.echo, compared: false, failure: None, limit: r.limit, ticks: r.time});
+            },
+            res => res?,
+        }
+        if let Some(ref path) = r.out_file {
+            write(path, &r.output).map_err(|e| load_error(0, &format!("{}: {}", path.display(), e)))?;
+        }
+        let mut failure = None;
+        if let Some(ref path) = r.cmp_file {
+            let cmp = read_to_string(path).map_err(|e| load_error(0, &format!("{}: {}", path.display(), e)))?;
+            failure = compare(&r.output, &cmp);
+        }
+        Ok(TestRun{output: r.output, echo: r.echo, compared: r.cmp_file.is_some(), failure, limit: None, ticks: r.time})
+    }
+}
+
+pub fn run_test_file(path: &Path) -> Result<TestRun, LoadError> {
+    let src = read_to_string(path).map_err(|e| load_error(0, &format!("{}: {}", path.display(), e)))?;
+    TestScript::parse(&src)?.run(path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sourcemap::SourceMap;
+
+    #[test]
+    fn test_columns() {
+        let c = Column::parse("RAM[256]%D1.6.1").unwrap();
+        assert_eq!((c.header(), c.num(472)), ("RAM[256]".to_string(), "    472 ".to_string()));
+        let c = Column::parse("RAM[3006]%D1.6.1").unwrap();
+        assert_eq!(c.header(), "RAM[3006");
+        assert_eq!(Column::parse("RAM[0]%D2.6.2").unwrap().header(), "  RAM[0]  ");
+        assert_eq!(Column::parse("RAM[11]%D1.6.1").unwrap().header(), "RAM[11] ");
+        assert_eq!(Column::parse("instruction%B0.16.0").unwrap().num(-1), "1111111111111111");
+        assert_eq!(Column::parse("reset%B2.1.2").unwrap().num(1), "  1  ");
+        assert_eq!(Column::parse("x%X1.4.1").unwrap().num(-2), " FFFE ");
+        assert_eq!(Column::parse("time%S1.4.1").unwrap().cell("3+"), " 3+   ");
+        assert_eq!(Column::parse("RAM[0]"), Column::parse("RAM[0]%D1.6.1"));
+        assert!(Column::parse("RAM[0]%Q1.6.1").is_none());
+        assert!(Column::parse("RAM[0]%D1.6").is_none());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(TestScript::parse("load Foo.asm;\nrepeat 3 { ticktock; }\n/* block\ncomment */ output;").is_ok());
+        assert!(TestScript::parse("frob;").is_err());
+        assert!(TestScript::parse("repeat 3 { ticktock;").is_err());
+        assert!(TestScript::parse("while RAM[0] > 0 { ticktock; }").is_err());
+        assert!(TestScript::parse("output-list RAM[0]%D1.6.1 foo%D1.6.1;").is_err());
+    }
+
+    #[test]
+    fn test_cpu_script() {
+        let script = format!("load CPU.hdl, output-list {};\n\
+            set instruction %B0011000000111001, tick, output, tock, output;\n\
+            set instruction %B1110110000010000, tick, output, tock, output;\n\
+            set reset 1, tick, tock, output;\n", crate::cpu::PIN_COLUMNS);
+        let run = TestScript::parse(&script).unwrap().run(Path::new(".")).unwrap();
+        assert_eq!(run.output, "\
+|time| inM  |  instruction   |reset| outM  |writeM |addre| pc  |DRegiste|
+|0+  |     0|0011000000111001|  0  |*******|   0   |    0|    0|      0 |
+|1   |     0|0011000000111001|  0  |*******|   0   |12345|    1|      0 |
+|1+  |     0|1110110000010000|  0  |*******|   0   |12345|    1|  12345 |
+|2   |     0|1110110000010000|  0  |*******|   0   |12345|    2|  12345 |
+|3   |     0|1110110000010000|  1  |*******|   0   |12345|    0|  12345 |
+");
+        assert!(!run.compared);
+        assert!(TestScript::parse("set inM 3;").unwrap().run(Path::new(".")).is_err());
+    }
+
+    const BASIC_TEST_VM: &str = "\
+push constant 10\npop local 0\npush constant 21\npush constant 22\npop argument 2\npop argument 1
+push constant 36\npop this 6\npush constant 42\npush constant 45\npop that 5\npop that 2
+push constant 510\npop temp 6\npush local 0\npush that 5\nadd\npush argument 1\nsub\npush this 6
+push this 6\nadd\nsub\npush temp 6\nadd\n";
+
+    const BASIC_TEST_TST: &str = "\
+// The stock project 7 BasicTest script
+load BasicTest.asm,
+output-file BasicTest.out,
+compare-to BasicTest.cmp,
+output-list RAM[256]%D1.6.1 RAM[300]%D1.6.1 RAM[401]%D1.6.1
+            RAM[402]%D1.6.1 RAM[3006]%D1.6.1 RAM[3012]%D1.6.1
+            RAM[3015]%D1.6.1 RAM[11]%D1.6.1;
+
+set RAM[0] 256,   // stack pointer
+set RAM[1] 300,   // base address of the local segment
+set RAM[2] 400,   // base address of the argument segment
+set RAM[3] 3000,  // base address of the this segment
+set RAM[4] 3010,  // base address of the that segment
+
+repeat 600 {      // enough cycles to complete the execution
+  ticktock;
+}
+
+output;
+";
+
+    const BASIC_TEST_CMP: &str = "\
+|RAM[256]|RAM[300]|RAM[401]|RAM[402]|RAM[3006|RAM[3012|RAM[3015|RAM[11] |
+|    472 |     10 |     21 |     22 |     36 |     42 |     45 |    510 |
+";
+
+    // Translate a project's .vm files into NAME.asm beside its script and
+    // .cmp file, in a directory of its own
+    fn project_dir(name: &str, vm: &[(&str, &str)], bootstrap: bool, tst: &str, cmp: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vmtrans-tst-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = vm.iter().map(|(f, src)| (f.to_string(), src.to_string())).collect::<Vec<_>>();
+        let (asm, _) = SourceMap::translate(&files, bootstrap).unwrap();
+        write(dir.join(format!("{}.asm", name)), asm).unwrap();
+        write(dir.join(format!("{}.tst", name)), tst).unwrap();
+        write(dir.join(format!("{}.cmp", name)), cmp).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_basic_test() {
+        let dir = project_dir("BasicTest", &[("BasicTest.vm", BASIC_TEST_VM)], false, BASIC_TEST_TST, BASIC_TEST_CMP);
+        let run = run_test_file(&dir.join("BasicTest.tst")).unwrap();
+        assert_eq!(run.output, BASIC_TEST_CMP);
+        assert!(run.compared);
+        assert_eq!(run.failure, None);
+        assert_eq!(read_to_string(dir.join("BasicTest.out")).unwrap(), BASIC_TEST_CMP);
+
+        write(dir.join("BasicTest.cmp"), BASIC_TEST_CMP.replace("472", "473")).unwrap();
+        let run = run_test_file(&dir.join("BasicTest.tst")).unwrap();
+        assert_eq!(run.failure.map(|m| m.line), Some(2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const STACK_TEST_VM: &str = "\
+// Executes a sequence of arithmetic and logical operations on the stack.
+push constant 17\npush constant 17\neq\npush constant 17\npush constant 16\neq
+push constant 16\npush constant 17\neq\npush constant 892\npush constant 891\nlt
+push constant 891\npush constant 892\nlt\npush constant 891\npush constant 891\nlt
+push constant 32767\npush constant 32766\ngt\npush constant 32766\npush constant 32767\ngt
+push constant 32766\npush constant 32766\ngt\npush constant 57\npush constant 31
+push constant 53\nadd\npush constant 112\nsub\nneg\nand\npush constant 82\nor\nnot
+";
+
+    const STACK_TEST_TST: &str = "\
+// The stock project 7 StackTest script
+load StackTest.asm,
+output-file StackTest.out,
+compare-to StackTest.cmp,
+output-list RAM[0]%D2.6.2
+        RAM[256]%D2.6.2 RAM[257]%D2.6.2 RAM[258]%D2.6.2 RAM[259]%D2.6.2 RAM[260]%D2.6.2;
+
+set RAM[0] 256,  // initializes the stack pointer
+
+repeat 1000 {    // enough cycles to complete the execution
+  ticktock;
+}
+
+// outputs the stack pointer (RAM[0]) and the stack contents: RAM[256]-RAM[265]
+output;
+output-list RAM[261]%D2.6.2 RAM[262]%D2.6.2 RAM[263]%D2.6.2 RAM[264]%D2.6.2 RAM[265]%D2.6.2;
+output;
+";
+
+    const STACK_TEST_CMP: &str = "\
+|  RAM[0]  | RAM[256] | RAM[257] | RAM[258] | RAM[259] | RAM[260] |
+|     266  |      -1  |       0  |       0  |       0  |      -1  |
+| RAM[261] | RAM[262] | RAM[263] | RAM[264] | RAM[265] |
+|       0  |      -1  |       0  |       0  |     -91  |
+";
+
+    #[test]
+    fn test_stack_test() {
+        let dir = project_dir("StackTest", &[("StackTest.vm", STACK_TEST_VM)], false, STACK_TEST_TST, STACK_TEST_CMP);
+        let run = run_test_file(&dir.join("StackTest.tst")).unwrap();
+        assert_eq!(run.output, STACK_TEST_CMP);
+        assert!(run.compared);
+        assert_eq!(run.failure, None);
+
+        // Whitespace at the ends of a line is ignored, but not within it
+        let cmp = STACK_TEST_CMP.lines().map(|l| format!("  {}\t\n", l)).collect::<String>();
+        write(dir.join("StackTest.cmp"), cmp).unwrap();
+        assert_eq!(run_test_file(&dir.join("StackTest.tst")).unwrap().failure, None);
+        assert_eq!(compare("a\n\nb\n", "a\nb\n"), None);
+        assert_eq!(compare("a\nb\n", "\na\n\nb\n\n"), None);
+        write(dir.join("StackTest.cmp"), STACK_TEST_CMP.replace("|     -91  |", "|    -9 1  |")).unwrap();
+        let run = run_test_file(&dir.join("StackTest.tst")).unwrap();
+        assert_eq!(run.failure.map(|m| m.line), Some(4));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const FIB_MAIN_VM: &str = "\
+// Computes the n'th element of the Fibonacci series, recursively.
+function Main.fibonacci 0
+push argument 0
+push constant 2
+lt                     // checks if n<2
+if-goto IF_TRUE
+goto IF_FALSE
+label IF_TRUE          // if n<2, return n
+push argument 0
+return
+label IF_FALSE         // if n>=2, returns fib(n-2)+fib(n-1)
+push argument 0
+push constant 2
+sub
+call Main.fibonacci 1  // computes fib(n-2)
+push argument 0
+push constant 1
+sub
+call Main.fibonacci 1  // computes fib(n-1)
+add                    // returns fib(n-1) + fib(n-2)
+return
+";
+
+    const FIB_SYS_VM: &str = "\
+// Calls Main.fibonacci for the 4th element; the bootstrap code calls Sys.init
+function Sys.init 0
+push constant 4
+call Main.fibonacci 1   // computes the 4'th fibonacci element
+label WHILE
+goto WHILE              // loops infinitely
+";
+
+    const FIB_TST: &str = "\
+// The stock project 8 FibonacciElement script; RAM is left for the bootstrap
+load FibonacciElement.asm,
+output-file FibonacciElement.out,
+compare-to FibonacciElement.cmp,
+output-list RAM[0]%D1.6.1 RAM[261]%D1.6.1;
+
+repeat 6000 {
+  ticktock;
+}
+
+output;
+";
+
+    const FIB_CMP: &str = "\
+| RAM[0] |RAM[261]|
+|    262 |      3 |
+";
+
+    #[test]
+    fn test_fibonacci_element() {
+        let vm = [("Main.vm", FIB_MAIN_VM), ("Sys.vm", FIB_SYS_VM)];
+        let dir = project_dir("FibonacciElement", &vm, true, FIB_TST, FIB_CMP);
+        let run = run_test_file(&dir.join("FibonacciElement.tst")).unwrap();
+        assert_eq!(run.output, FIB_CMP);
+        assert!(run.compared);
+        assert_eq!(run.failure, None);
+
+        // Without the bootstrap nothing sets SP, and the first return faults
+        let dir = project_dir("FibonacciElement", &vm, false, FIB_TST, FIB_CMP);
+        assert!(run_test_file(&dir.join("FibonacciElement.tst")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const STATICS_CLASS1_VM: &str = "\
+// Stores two supplied arguments in static[0] and static[1].
+function Class1.set 0
+push argument 0
+pop static 0
+push argument 1
+pop static 1
+push constant 0
+return
+
+// Returns static[0] - static[1].
+function Class1.get 0
+push static 0
+push static 1
+sub
+return
+";
+
+    const STATICS_CLASS2_VM: &str = "\
+// Stores two supplied arguments in static[0] and static[1].
+function Class2.set 0
+push argument 0
+pop static 0
+push argument 1
+pop static 1
+push constant 0
+return
+
+// Returns static[0] - static[1].
+function Class2.get 0
+push static 0
+push static 1
+sub
+return
+";
+
+    const STATICS_SYS_VM: &str = "\
+// Sets Class1's statics to 6 and 8 and Class2's to 23 and 15, then
+// leaves Class1.get() and Class2.get() on the stack
+function Sys.init 0
+push constant 6
+push constant 8
+call Class1.set 2
+pop temp 0 // Dumps the return value
+push constant 23
+push constant 15
+call Class2.set 2
+pop temp 0 // Dumps the return value
+call Class1.get 0
+call Class2.get 0
+label WHILE
+goto WHILE
+";
+
+    const STATICS_TST: &str = "\
+// The stock project 8 StaticsTest script
+load StaticsTest.asm,
+output-file StaticsTest.out,
+compare-to StaticsTest.cmp,
+output-list RAM[0]%D1.6.1 RAM[261]%D1.6.1 RAM[262]%D1.6.1;
+
+set RAM[0] 256,
+
+repeat 2500 {
+  ticktock;
+}
+
+output;
+";
+
+    const STATICS_CMP: &str = "\
+| RAM[0] |RAM[261]|RAM[262]|
+|    263 |     -2 |      8 |
+";
+
+    #[test]
+    fn test_statics_test() {
+        // Each file gets its own statics even though the names match
+        let vm = [("Class1.vm", STATICS_CLASS1_VM), ("Class2.vm", STATICS_CLASS2_VM), ("Sys.vm", STATICS_SYS_VM)];
+        let dir = project_dir("StaticsTest", &vm, true, STATICS_TST, STATICS_CMP);
+        let run = run_test_file(&dir.join("StaticsTest.tst")).unwrap();
+        assert_eq!(run.output, STATICS_CMP);
+        assert!(run.compared);
+        assert_eq!(run.failure, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{self,Read,Write};
+use std::process::{Command,Stdio};
+use std::sync::mpsc::{channel,Receiver};
+use std::time::{Duration,Instant};
+
+use crate::bus::{SCREEN,SCREEN_SIZE};
+use crate::emul::{Emul,Stop};
+use crate::throttle::Throttle;
+
+// Terminals send no key-up events, so a key is held this long after its
+// last byte; autorepeat keeps it down while the key is held
+const KEY_HOLD: Duration = Duration::from_millis(150);
+const PANEL_WIDTH: usize = 26;
+const STACK_LINES: usize = 8;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Input {
+    Key(i16),
+    Quit,
+    Pause,
+    Step,
+}
+
+// Turn the bytes a terminal sends into Hack key codes: ASCII as is, and
+// newline 128, backspace 129, arrows 130-133, home 134, end 135, page up
+// 136, page down 137, insert 138, delete 139, esc 140, F1-F4 141-144.
+pub fn decode_keys(bytes: &[u8]) -> Vec<Input> {
+    let mut r = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let (input, n) = match rest {
+            [0x1b, b'[', b'A', ..] => (Input::Key(131), 3),
+            [0x1b, b'[', b'B', ..] => (Input::Key(133), 3),
+            [0x1b, b'[', b'C', ..] => (Input::Key(132), 3),
+            [0x1b, b'[', b'D', ..] => (Input::Key(130), 3),
+            [0x1b, b'[', b'H', ..] => (Input::Key(134), 3),
+            [0x1b, b'[', b'F', ..] => (Input::Key(135), 3),
+            [0x1b, b'[', c, b'~', ..] if (b'2'..=b'6').contains(c) && *c != b'4' => {
+                let code = match c { b'2' => 138, b'3' => 139, b'5' => 136, _ => 137 };
+                (Input::Key(code), 4)
+            },
+            [0x1b, b'O', c, ..] if (b'P'..=b'S').contains(c) => (Input::Key(141 + (c - b'P') as i16), 3),
+            [0x1b, ..] => (Input::Key(140), 1),
+            [0x03, ..] | [0x11, ..] => (Input::Quit, 1),
+            [0x10, ..] => (Input::Pause, 1),
+            [0x0e, ..] => (Input::Step, 1),
+            [b'\r', ..] | [b'\n', ..] => (Input::Key(128), 1),
+            [0x7f, ..] | [0x08, ..] => (Input::Key(129), 1),
+            [c, ..] if (32..127).contains(c) => (Input::Key(*c as i16), 1),
+            _ => {
+                i += 1;
+                continue;
+            },
+        };
+        r.push(input);
+        i += n;
+    }
+    r
+}
+
+// The screen as braille, each character 2x4 dots.  At scale 2 a dot
+// stands for a 2x2 block of pixels and is set if any of them is black.
+pub fn braille(em: &Emul, scale: usize) -> Vec<String> {
+    let words = (0..SCREEN_SIZE).map(|i| em.bus.read(SCREEN + i).unwrap_or(0) as u16).collect::<Vec<_>>();
+    let pixel = |row: usize, col: usize| words[row * 32 + col / 16] >> (col % 16) & 1 == 1;
+    let dot = |y: usize, x: usize| {
+        (0..scale).any(|dy| (0..scale).any(|dx| pixel(y * scale + dy, x * scale + dx)))
+    };
+    // Braille dot bits for (x, y) within a character
+    const BITS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let (rows, cols) = (256 / (4 * scale), 512 / (2 * scale));
+    (0..rows).map(|cy| {
+        (0..cols).map(|cx| {
+            let mut bits = 0;
+            for (y, row_bits) in BITS.iter().enumerate() {
+                for (x, bit) in row_bits.iter().enumerate() {
+                    if dot(cy * 4 + y, cx * 2 + x) {
+                        bits |= bit;
+                    }
+                }
+            }
+            char::from_u32(0x2800 + bits).unwrap()
+        }).collect()
+    }).collect()
+}
+
+// Registers, the current VM function and the top of the stack
+pub fn panel(em: &Emul, paused: bool) -> Vec<String> {
+    let ram = |addr: usize| em.bus.read(addr).unwrap_or(0);
+    let function = em.call_stack().first().and_then(|f| f.function.clone()).unwrap_or_else(|| "-".to_string());
+    let sp = ram(0) as u16 as usize;
+    let mut r = vec![
+        format!("PC    {}", em.pc()),
+        format!("A     {}", em.a),
+        format!("D     {}", em.d),
+        format!("Ticks {}", em.ticks()),
+        format!("Fn    {}", function),
+        format!("Key   {}", ram(crate::bus::KBD)),
+        String::new(),
+        format!("SP    {}", sp),
+    ];
+    for addr in (sp.saturating_sub(STACK_LINES)..sp).rev() {
+        r.push(format!("{:5} {}", addr, ram(addr)));
+    }
+    r.push(String::new());
+    r.push(if paused { "PAUSED  ^P run  ^N step" } else { "^P pause" }.to_string());
+    r.push("^C quit".to_string());
+    r.iter().map(|l| l.chars().take(PANEL_WIDTH).collect()).collect()
+}
+
+// One full frame: the screen with the panel beside it, drawn from the
+// top left of the terminal
+pub fn frame(em: &Emul, scale: usize, paused: bool) -> String {
+    let screen = braille(em, scale);
+    let panel = panel(em, paused);
+    let mut r = String::from("\x1b[H");
+    for (i, line) in screen.iter().enumerate() {
+        let side = panel.get(i).map_or("", |s| s.as_str());
+        r += &format!("{} {:<width$}\r\n", line, side, width = PANEL_WIDTH);
+    }
+    r
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let out = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+// Terminal in raw mode on the alternate screen, restored on drop
+struct Terminal {
+    saved: String,
+}
+
+impl Terminal {
+    fn open() -> io::Result<Terminal> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        Ok(Terminal{saved})
+    }
+
+    // (rows, columns)
+    fn size() -> Option<(usize, usize)> {
+        let s = stty(&["size"]).ok()?;
+        let mut ws = s.split_whitespace().map(|w| w.parse().ok());
+        Some((ws.next()??, ws.next()??))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        io::stdout().flush().ok();
+        stty(&[&self.saved]).ok();
+    }
+}
+
+fn read_stdin() -> Receiver<Vec<u8>> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        while let Ok(n) = stdin.read(&mut buf) {
+            if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Run the program in the terminal until it ends, halts, the user quits or
+// max_ticks instructions have run, at the throttle's rate, redrawing once
+// a frame
+pub fn run(em: &mut Emul, max_ticks: u64, mut throttle: Throttle) -> io::Result<Stop> {
+    let scale = match Terminal::size() {
+        Some((rows, cols)) if rows >= 64 && cols >= 256 + 1 + PANEL_WIDTH => 1,
+        _ => 2,
+    };
+    let term = Terminal::open()?;
+    let input = read_stdin();
+    let mut out = io::stdout();
+    let mut paused = false;
+    let mut last_key = None;
+    let mut stop = Stop::TickLimit;
+    throttle.reset(em.ticks());
+    'outer: loop {
+        while let Ok(bytes) = input.try_recv() {
+            for i in decode_keys(&bytes) {
+                match i {
+                    Input::Quit => break 'outer,
+                    Input::Pause => {
+                        paused = !paused;
+                        throttle.reset(em.ticks());
+                    },
+                    Input::Step if paused && em.pc() < em.rom().len() => em.step(),
+                    Input::Step => (),
+                    Input::Key(k) => {
+                        em.set_key(k);
+                        last_key = Some(Instant::now());
+                    },
+                }
+            }
+        }
+        if last_key.is_some_and(|t| t.elapsed() > KEY_HOLD) {
+            em.set_key(0);
+            last_key = None;
+        }
+        if !paused {
+            let ticks = throttle.budget(em.ticks()).min(max_ticks.saturating_sub(em.ticks()));
+            stop = em.resume(ticks);
+            if stop != Stop::TickLimit || em.ticks() >= max_ticks {
+                paused = true;
+            }
+        }
+        write!(out, "{}", frame(em, scale, paused))?;
+        out.flush()?;
+        throttle.wait();
+    }
+    drop(term);
+    Ok(stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_keys() {
+        assert_eq!(decode_keys(b"a\x1b[D\x1b[C\r\x7f\x1b\x1b[5~\x1bOQ\x03\x00"), vec![
+            Input::Key(97), Input::Key(130), Input::Key(132), Input::Key(128), Input::Key(129),
+            Input::Key(140), Input::Key(136), Input::Key(142), Input::Quit,
+        ]);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut em = Emul::new();
+        // Top left pixel, and pixel (3, 17) in the second word
+        em.set_ram(&[(SCREEN, 1), (SCREEN + 1, 2), (SCREEN + 97, 2), (0, 258), (256, 11), (257, 22)]);
+        let full = braille(&em, 1);
+        assert_eq!((full.len(), full[0].chars().count()), (64, 256));
+        assert_eq!(full[0].chars().take(10).collect::<String>(), "⠁⠀⠀⠀⠀⠀⠀⠀⢈⠀");
+        let half = braille(&em, 2);
+        assert_eq!((half.len(), half[0].chars().count()), (32, 128));
+        assert_eq!(half[0].chars().take(5).collect::<String>(), "⠁⠀⠀⠀⠃");
+
+        let p = panel(&em, false);
+        assert_eq!(p[7..10], ["SP    258", "  257 22", "  256 11"]);
+        let f = frame(&em, 2, false);
+        assert!(f.starts_with("\x1b[H⠁"));
+        assert_eq!(f.matches("\r\n").count(), 32);
+    }
+}
@@ -14,6 +14,7 @@ pub enum VMOp {
 }
 
 impl VMOp {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<VMOp> {
         match s {
             "add" => Some(VMOp::ADD),
@@ -57,6 +58,7 @@ pub enum VMSeg {
 }
 
 impl VMSeg {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<VMSeg> {
         match s {
             "local" => Some(VMSeg::LOCAL),